@@ -0,0 +1,82 @@
+//! Blargg subcommand.
+//!
+//! Runs Blargg's test ROMs (cpu_instrs, instr_timing, mem_timing and friends) and reports which passed.
+//! They report results two ways: printing text over serial that ends in "Passed" or "Failed",
+//! and writing a status byte to 0xA000 once the signature DE B0 61 is at 0xA001-0xA003.
+//! Whichever one shows up first decides the result.
+
+use std::path::PathBuf;
+use anyhow::Result;
+use clap::Args;
+use gbcore::{CPU, DOTS_PER_FRAME, DOTS_PER_SECOND};
+
+use crate::testing::{self, Outcome};
+
+const STATUS_ADDR: u16 = 0xA000;
+const SIGNATURE_ADDR: u16 = 0xA001;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const TEXT_ADDR: u16 = 0xA004;
+// Status while the test is still going
+const RUNNING: u8 = 0x80;
+
+#[derive(Args)]
+pub struct BlarggArgs {
+    /// Test ROMs to run, or directories to search for them
+    #[arg(required = true)]
+    roms: Vec<PathBuf>,
+
+    /// Give up on a ROM after this many seconds of emulated time
+    #[arg(long, default_value_t = 120)]
+    timeout: u64,
+}
+
+/// Runs every ROM and prints a table of results. Returns true if they all passed.
+pub fn run(args: &BlarggArgs) -> Result<bool> {
+    testing::run_suite(&args.roms, |rom, dots| {
+        let mut cpu = testing::boot(rom)?;
+        let outcome = run_test(&mut cpu, args.timeout);
+        *dots = cpu.elapsed_dots();
+        outcome
+    })
+}
+
+fn run_test(cpu: &mut CPU, timeout: u64) -> Result<Outcome> {
+    let end = timeout * DOTS_PER_SECOND;
+    while cpu.elapsed_dots() < end {
+        // Results only need checking now and then, a frame apart is plenty
+        let frame_end = cpu.elapsed_dots() + DOTS_PER_FRAME;
+        while cpu.elapsed_dots() < frame_end {
+            cpu.cycle()?;
+        }
+
+        let serial = String::from_utf8_lossy(cpu.serial_output()).into_owned();
+        if serial.contains("Passed") {
+            return Ok(Outcome::Passed)
+        }
+        if serial.contains("Failed") {
+            return Ok(Outcome::Failed(summarize(&serial)))
+        }
+
+        let signature = [0, 1, 2].map(|offset| cpu.peek(SIGNATURE_ADDR + offset));
+        let status = cpu.peek(STATUS_ADDR);
+        if signature == SIGNATURE && status != RUNNING {
+            return match status {
+                0 => Ok(Outcome::Passed),
+                code => Ok(Outcome::Failed(format!("code {}: {}", code, summarize(&memory_text(cpu))))),
+            }
+        }
+    }
+    Ok(Outcome::TimedOut)
+}
+
+/// The text a test left at 0xA004, up to the terminating zero.
+fn memory_text(cpu: &CPU) -> String {
+    let bytes: Vec<u8> = (TEXT_ADDR..=0xBFFF).map(|address| cpu.peek(address)).take_while(|&byte| byte != 0).collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Squeezes a test's output onto one line for the table, dropping the test name it starts with.
+fn summarize(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+    lines.iter().skip(1.min(lines.len().saturating_sub(1))).copied().collect::<Vec<_>>().join(" / ")
+}
@@ -0,0 +1,637 @@
+//! Debugger.
+//!
+//! A command prompt for stepping through a ROM: single steps, stepping over calls and out of functions,
+//! running to an address, breakpoint, or watchpoint, and looking at and changing registers and memory.
+//! Breakpoints can have conditions, skip their first few hits, or log a message and carry on instead of stopping.
+//! Ctrl+C stops a run and drops back to the prompt. Type `help` for the commands.
+//!
+//! Addresses and values are always hex, with or without a `$` or `0x` in front. Counts are decimal.
+//! With symbols loaded, addresses can be labels too, and listings show them. Breakpoints on a label in a switchable
+//! bank, or given as `BANK:ADDR`, only stop while that bank is switched in.
+//! Conditions and messages are [expressions](crate::expression), which have their own rules.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use anyhow::{anyhow, bail, Context, Result};
+use gbcore::{Reg8, Reg16, WatchCondition, WatchHit, WatchKind, Watchpoint, CPU};
+
+use crate::expression::{Expression, Message};
+use crate::testing;
+
+const PROMPT: &str = "(gb) ";
+const DEFAULT_DUMP_LENGTH: u16 = 64;
+const DEFAULT_LIST_LENGTH: usize = 10;
+// Instructions shown before PC in a listing, when it can work out where they start
+const LIST_BEFORE: usize = 4;
+
+const HELP: &str = "\
+step, s [N]          run one instruction, or N
+next, n              step over calls
+out, o               run until the current function returns
+continue, c          run until a breakpoint, or Ctrl+C
+to ADDR              run until PC gets to an address
+break, b ADDR [if EXPR]
+                     set a breakpoint, which only stops when EXPR is true if given
+condition ADDR [EXPR]
+                     change or remove a breakpoint's condition
+ignore ADDR N        go past a breakpoint the next N times it's hit
+log ADDR [MESSAGE]   print a message at a breakpoint and carry on, or stop there again,
+                     with {EXPR} for values in hex, {EXPR:d} in decimal, {EXPR:b} in binary
+delete, d [ADDR]     remove a breakpoint, or all of them
+breaks               list breakpoints
+print, p EXPR        show what an expression comes to, like a == $3C && [hl] & $80
+watch, wa ADDR[-END] [r|w|rw] [=VALUE|changed]
+                     stop after memory is read or written (w without r or rw),
+                     only when the value is VALUE or a write changes it, if given
+unwatch [N]          remove a watchpoint, or all of them
+watches              list watchpoints
+regs, r              show registers and flags
+set REG VALUE        change a register (a, f, b, c, d, e, h, l, af, bc, de, hl, sp, pc)
+mem, x ADDR [LEN]    hex dump memory, LEN bytes of it in decimal
+write, w ADDR BYTE.. write bytes to memory
+list, l [ADDR] [N]   disassemble around PC, or from an address
+sym [NAME|ADDR]      look up a symbol, or the label for an address
+record FILE [stems]  record the audio to a WAV file, with a file per channel too if stems
+stop-record          finish the recording
+help, h              show this
+quit, q              leave the debugger
+An empty line repeats the last command. ADDR can be a label when there are symbols,
+and breakpoints can be BANK:ADDR to only stop in one ROM bank.";
+
+/// Why a run handed control back.
+enum Stop {
+    Done,
+    Breakpoint(u16),
+    Watchpoint(WatchHit),
+    Interrupted,
+}
+
+/// Where a breakpoint is, and for one in a switchable bank, maybe which bank has to be switched in for it to stop.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Location {
+    address: u16,
+    bank: Option<u16>,
+}
+
+impl Location {
+    fn matches(&self, cpu: &CPU) -> bool {
+        self.bank.is_none_or(|bank| bank == cpu.bank_at(self.address))
+    }
+
+    fn label(&self, cpu: &CPU) -> Option<String> {
+        let bank = self.bank.unwrap_or_else(|| cpu.bank_at(self.address));
+        cpu.symbols()?.label(bank, self.address)
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.address),
+            None => write!(f, "${:04X}", self.address),
+        }
+    }
+}
+
+/// A breakpoint, which can have a condition, skip its first few hits, or log instead of stopping.
+#[derive(Default)]
+struct Breakpoint {
+    condition: Option<Expression>,
+    // Hits left to go past without stopping
+    ignore: u32,
+    hits: u32,
+    log: Option<Message>,
+}
+
+impl Breakpoint {
+    /// Counts a hit if the condition holds, logging it if this is a log. Returns whether to stop.
+    fn hit(&mut self, cpu: &CPU) -> Result<bool> {
+        if let Some(condition) = &self.condition && !condition.is_true(cpu)? {
+            return Ok(false)
+        }
+        self.hits += 1;
+        if self.ignore > 0 {
+            self.ignore -= 1;
+            return Ok(false)
+        }
+        match &self.log {
+            Some(message) => {
+                println!("${:04X}: {}", cpu.program_counter(), message.format(cpu)?);
+                Ok(false)
+            },
+            None => Ok(true),
+        }
+    }
+
+    fn describe(&self) -> String {
+        let mut description = String::new();
+        if let Some(condition) = &self.condition {
+            description += &format!("  if {}", condition);
+        }
+        if let Some(message) = &self.log {
+            description += &format!("  log {}", message);
+        }
+        if self.hits > 0 {
+            description += &format!("  hit {} times", self.hits);
+        }
+        if self.ignore > 0 {
+            description += &format!("  ignoring {} more", self.ignore);
+        }
+        description
+    }
+}
+
+struct Debugger {
+    breakpoints: BTreeMap<Location, Breakpoint>,
+    // Set by Ctrl+C, to stop a run
+    interrupted: Arc<AtomicBool>,
+}
+
+/// Runs the prompt until the user quits or stdin runs out.
+pub fn run(cpu: &mut CPU) -> Result<()> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&interrupted))
+        .context("Failed to catch Ctrl+C")?;
+    let mut debugger = Debugger {
+        breakpoints: BTreeMap::new(),
+        interrupted,
+    };
+
+    println!("Type help for a list of commands");
+    debugger.show_position(cpu);
+    let mut last_command = String::new();
+    let stdin = io::stdin();
+    loop {
+        print!("{}", PROMPT);
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(())
+        }
+        let line = match line.trim() {
+            "" => last_command.clone(),
+            line => line.to_string(),
+        };
+        last_command = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            continue
+        };
+        if matches!(command, "quit" | "q") {
+            return Ok(())
+        }
+        if let Err(err) = debugger.command(cpu, command, args) {
+            println!("Error: {:#}", err);
+        }
+    }
+}
+
+impl Debugger {
+    fn command(&mut self, cpu: &mut CPU, command: &str, args: &[&str]) -> Result<()> {
+        match command {
+            "step" | "s" => {
+                let count = match args.first() {
+                    Some(count) => count.parse().map_err(|_| anyhow!("{} isn't a count", count))?,
+                    None => 1,
+                };
+                let mut steps = 0;
+                self.execute(cpu, |_| {
+                    steps += 1;
+                    steps >= count
+                })?;
+            },
+            "next" | "n" => {
+                let pc = cpu.program_counter();
+                let instruction = cpu.disassemble(pc);
+                if instruction.is_call() {
+                    // Done once it comes back to the next instruction, and not just from a deeper call to the same place
+                    let return_address = pc.wrapping_add(instruction.length);
+                    let sp = cpu.stack_pointer();
+                    self.execute(cpu, |cpu| cpu.program_counter() == return_address && cpu.stack_pointer() >= sp)?;
+                } else {
+                    self.execute(cpu, |_| true)?;
+                }
+            },
+            "out" | "o" => {
+                let sp = cpu.stack_pointer();
+                let is_return = |cpu: &CPU| cpu.disassemble(cpu.program_counter()).is_return();
+                let mut returning = is_return(cpu);
+                self.execute(cpu, |cpu| {
+                    // The stack is only above where it started once the current function's return address is popped
+                    let done = returning && cpu.stack_pointer() > sp;
+                    returning = is_return(cpu);
+                    done
+                })?;
+            },
+            "continue" | "c" => {
+                self.execute(cpu, |_| false)?;
+            },
+            "to" => {
+                let address = parse_address(cpu, args.first().context("Expected an address")?)?;
+                self.execute(cpu, |cpu| cpu.program_counter() == address)?;
+            },
+            "break" | "b" => {
+                let (location, rest) = args.split_first().context("Expected an address")?;
+                let location = parse_location(cpu, location)?;
+                let condition = match rest {
+                    [] => None,
+                    ["if", expression @ ..] => Some(Expression::parse(&expression.join(" "))?),
+                    _ => bail!("Expected if and a condition after the address"),
+                };
+                let breakpoint = Breakpoint { condition, ..Breakpoint::default() };
+                println!("Breakpoint at {}{}", location, breakpoint.describe());
+                self.breakpoints.insert(location, breakpoint);
+            },
+            "condition" => {
+                let (location, expression) = args.split_first().context("Expected an address")?;
+                let breakpoint = self.breakpoint(cpu, location)?;
+                breakpoint.condition = match expression {
+                    [] => None,
+                    expression => Some(Expression::parse(&expression.join(" "))?),
+                };
+            },
+            "ignore" => {
+                let [location, count] = args else {
+                    bail!("Expected an address and a count");
+                };
+                let count = count.parse().map_err(|_| anyhow!("{} isn't a count", count))?;
+                self.breakpoint(cpu, location)?.ignore = count;
+            },
+            "log" => {
+                let (location, message) = args.split_first().context("Expected an address")?;
+                let log = match message {
+                    [] => None,
+                    message => Some(Message::parse(&message.join(" "))?),
+                };
+                let location = parse_location(cpu, location)?;
+                self.breakpoints.entry(location).or_default().log = log;
+            },
+            "print" | "p" => {
+                let value = Expression::parse(&args.join(" "))?.evaluate(cpu)?;
+                if value < 0 {
+                    println!("{} (-${:X})", value, value.unsigned_abs());
+                } else {
+                    println!("{} (${:X})", value, value);
+                }
+            },
+            "delete" | "d" => match args.first() {
+                Some(location) => {
+                    let location = parse_location(cpu, location)?;
+                    if self.breakpoints.remove(&location).is_none() {
+                        bail!("No breakpoint at {}", location);
+                    }
+                },
+                None => self.breakpoints.clear(),
+            },
+            "breaks" => {
+                if self.breakpoints.is_empty() {
+                    println!("No breakpoints");
+                }
+                for (location, breakpoint) in &self.breakpoints {
+                    let label = location.label(cpu).unwrap_or_default();
+                    let instruction = cpu.disassemble(location.address).to_string_with_labels(|address| cpu.label(address));
+                    let line = format!("{:<8} {:<16} {:<16}{}", location, label, instruction, breakpoint.describe());
+                    println!("{}", line.trim_end());
+                }
+            },
+            "watch" | "wa" => {
+                let watchpoint = parse_watchpoint(cpu, args)?;
+                cpu.add_watchpoint(watchpoint);
+                println!("Watchpoint {}: {}", cpu.watchpoints().len(), describe_watchpoint(&watchpoint));
+            },
+            "unwatch" => match args.first() {
+                Some(number) => {
+                    let watchpoint = number.parse::<usize>().ok()
+                        .and_then(|number| cpu.watchpoints().get(number.wrapping_sub(1)).copied())
+                        .ok_or_else(|| anyhow!("No watchpoint {}", number))?;
+                    cpu.remove_watchpoint(&watchpoint);
+                },
+                None => {
+                    for watchpoint in cpu.watchpoints().to_vec() {
+                        cpu.remove_watchpoint(&watchpoint);
+                    }
+                },
+            },
+            "watches" => {
+                if cpu.watchpoints().is_empty() {
+                    println!("No watchpoints");
+                }
+                for (number, watchpoint) in cpu.watchpoints().iter().enumerate() {
+                    println!("{:>2}  {}", number + 1, describe_watchpoint(watchpoint));
+                }
+            },
+            "regs" | "r" => show_registers(cpu),
+            "set" => {
+                let [register, value] = args else {
+                    bail!("Expected a register and a value");
+                };
+                set_register(cpu, register, parse_hex(value)?)?;
+                show_registers(cpu);
+            },
+            "mem" | "x" => {
+                let address = parse_address(cpu, args.first().context("Expected an address")?)?;
+                let length = match args.get(1) {
+                    Some(length) => length.parse().map_err(|_| anyhow!("{} isn't a count", length))?,
+                    None => DEFAULT_DUMP_LENGTH,
+                };
+                dump_memory(cpu, address, length);
+            },
+            "write" | "w" => {
+                let (address, bytes) = args.split_first().context("Expected an address")?;
+                let address = parse_address(cpu, address)?;
+                if bytes.is_empty() {
+                    bail!("Expected bytes to write");
+                }
+                for (offset, byte) in bytes.iter().enumerate() {
+                    let byte = u8::try_from(parse_hex(byte)?).map_err(|_| anyhow!("{} doesn't fit in a byte", byte))?;
+                    cpu.poke(address.wrapping_add(offset as u16), byte)?;
+                }
+                dump_memory(cpu, address, bytes.len() as u16);
+            },
+            "list" | "l" => {
+                let start = args.first().map(|address| parse_address(cpu, address)).transpose()?;
+                let count = match args.get(1) {
+                    Some(count) => count.parse().map_err(|_| anyhow!("{} isn't a count", count))?,
+                    None => DEFAULT_LIST_LENGTH,
+                };
+                list(cpu, start, count);
+            },
+            "sym" => {
+                let symbols = cpu.symbols().context("No symbols loaded")?;
+                match args.first() {
+                    None => println!("{} symbols", symbols.len()),
+                    Some(name) if let Some((bank, address)) = symbols.find(name) => println!("{} is {:02X}:{:04X}", name, bank, address),
+                    Some(address) => {
+                        let address = parse_address(cpu, address)?;
+                        match cpu.label(address) {
+                            Some(label) => println!("${:04X} is {}", address, label),
+                            None => println!("No label for ${:04X}", address),
+                        }
+                    },
+                }
+            },
+            "record" => {
+                let (path, rest) = args.split_first().context("Expected a WAV file")?;
+                let stems = match rest {
+                    [] => false,
+                    ["stems"] => true,
+                    _ => bail!("Expected stems or nothing after the file"),
+                };
+                cpu.start_recording(path, stems)?;
+                println!("Recording to {}", path);
+            },
+            "stop-record" => {
+                if !cpu.is_recording() {
+                    bail!("Not recording");
+                }
+                cpu.stop_recording()?;
+                println!("Recording finished");
+            },
+            "help" | "h" => println!("{}", HELP),
+            _ => bail!("Unknown command {}, try help", command),
+        }
+        Ok(())
+    }
+
+    /// Runs instructions until `done` says so after one, a breakpoint is reached, or Ctrl+C is pressed.
+    /// Shows where it stopped either way, or what went wrong.
+    fn execute(&mut self, cpu: &mut CPU, mut done: impl FnMut(&CPU) -> bool) -> Result<()> {
+        self.interrupted.store(false, Ordering::Relaxed);
+        cpu.take_watch_hit();
+        let result = testing::catch_panics(|| {
+            loop {
+                cpu.cycle()?;
+                if let Some(hit) = cpu.take_watch_hit() {
+                    return Ok(Stop::Watchpoint(hit))
+                }
+                if cpu.halted() {
+                    // Nothing is running, so there are no breakpoints to stop at until something wakes it.
+                    // A cycle spent halted still counts as a step though, or stepping a HALT would never finish
+                    if done(cpu) {
+                        return Ok(Stop::Done)
+                    }
+                    if self.interrupted.load(Ordering::Relaxed) {
+                        return Ok(Stop::Interrupted)
+                    }
+                    continue
+                }
+                let pc = cpu.program_counter();
+                // Every breakpoint at PC, whichever bank it's for
+                let here = Location { address: pc, bank: None }..=Location { address: pc, bank: Some(u16::MAX) };
+                let mut stop = false;
+                for (location, breakpoint) in self.breakpoints.range_mut(here) {
+                    if location.matches(cpu) {
+                        stop |= breakpoint.hit(cpu).with_context(|| format!("Breakpoint at {}", location))?;
+                    }
+                }
+                if stop {
+                    return Ok(Stop::Breakpoint(pc))
+                }
+                if done(cpu) {
+                    return Ok(Stop::Done)
+                }
+                if self.interrupted.load(Ordering::Relaxed) {
+                    return Ok(Stop::Interrupted)
+                }
+            }
+        });
+        match result? {
+            Stop::Done => {},
+            Stop::Breakpoint(address) => println!("Breakpoint at ${:04X}", address),
+            Stop::Watchpoint(hit) if hit.write => {
+                println!("Watchpoint: ${:04X} wrote ${:04X}, ${:02X} -> ${:02X}", hit.pc, hit.address, hit.old, hit.new);
+            },
+            Stop::Watchpoint(hit) => println!("Watchpoint: ${:04X} read ${:04X}, ${:02X}", hit.pc, hit.address, hit.new),
+            Stop::Interrupted => println!("Interrupted"),
+        }
+        self.show_position(cpu);
+        Ok(())
+    }
+
+    fn breakpoint(&mut self, cpu: &CPU, location: &str) -> Result<&mut Breakpoint> {
+        let location = parse_location(cpu, location)?;
+        self.breakpoints.get_mut(&location).ok_or_else(|| anyhow!("No breakpoint at {}", location))
+    }
+
+    fn show_position(&self, cpu: &CPU) {
+        if cpu.halted() {
+            println!("Halted");
+        }
+        let pc = cpu.program_counter();
+        if let Some(label) = cpu.label(pc) && is_offset(&label) {
+            println!("In {}", label);
+        }
+        println!("{}", list_line(cpu, pc, true));
+    }
+}
+
+fn show_registers(cpu: &CPU) {
+    println!("{}", cpu);
+    let state = cpu.state();
+    let flags = [("Z", state.zero()), ("N", state.subtract()), ("H", state.half_carry()), ("C", state.carry())];
+    let flags: Vec<&str> = flags.iter().map(|&(name, set)| if set { name } else { "-" }).collect();
+    println!("Flags: {}", flags.join(" "));
+}
+
+fn set_register(cpu: &mut CPU, name: &str, value: u16) -> Result<()> {
+    let register8 = match name.to_ascii_lowercase().as_str() {
+        "a" => Some(Reg8::A),
+        "f" => Some(Reg8::F),
+        "b" => Some(Reg8::B),
+        "c" => Some(Reg8::C),
+        "d" => Some(Reg8::D),
+        "e" => Some(Reg8::E),
+        "h" => Some(Reg8::H),
+        "l" => Some(Reg8::L),
+        _ => None,
+    };
+    if let Some(register) = register8 {
+        let value = u8::try_from(value).map_err(|_| anyhow!("${:X} doesn't fit in {}", value, name))?;
+        cpu.set_reg8(register, value);
+        return Ok(())
+    }
+    let register = match name.to_ascii_lowercase().as_str() {
+        "af" => Reg16::AF,
+        "bc" => Reg16::BC,
+        "de" => Reg16::DE,
+        "hl" => Reg16::HL,
+        "sp" => Reg16::SP,
+        "pc" => Reg16::PC,
+        _ => bail!("No register called {}", name),
+    };
+    cpu.set_reg16(register, value);
+    Ok(())
+}
+
+/// Parses `ADDR[-END] [r|w|rw] [=VALUE|changed]`, with the last two in either order.
+fn parse_watchpoint(cpu: &CPU, args: &[&str]) -> Result<Watchpoint> {
+    let (range, options) = args.split_first().context("Expected an address")?;
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse_address(cpu, start)?, parse_address(cpu, end)?),
+        None => (parse_address(cpu, range)?, parse_address(cpu, range)?),
+    };
+    if end < start {
+        bail!("${:04X}-${:04X} ends before it starts", start, end);
+    }
+    let mut watchpoint = Watchpoint::new(start, end, WatchKind::Write);
+    for option in options {
+        watchpoint = match option.to_ascii_lowercase().as_str() {
+            "r" => Watchpoint { kind: WatchKind::Read, ..watchpoint },
+            "w" => Watchpoint { kind: WatchKind::Write, ..watchpoint },
+            "rw" => Watchpoint { kind: WatchKind::Access, ..watchpoint },
+            "changed" => watchpoint.when(WatchCondition::Changes),
+            option if let Some(value) = option.strip_prefix('=') => {
+                let value = u8::try_from(parse_hex(value)?).map_err(|_| anyhow!("{} doesn't fit in a byte", value))?;
+                watchpoint.when(WatchCondition::Equals(value))
+            },
+            _ => bail!("Unknown watchpoint option {}", option),
+        };
+    }
+    Ok(watchpoint)
+}
+
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    let range = if watchpoint.start == watchpoint.end {
+        format!("${:04X}", watchpoint.start)
+    } else {
+        format!("${:04X}-${:04X}", watchpoint.start, watchpoint.end)
+    };
+    let kind = match watchpoint.kind {
+        WatchKind::Read => "read",
+        WatchKind::Write => "write",
+        WatchKind::Access => "read/write",
+    };
+    match watchpoint.condition {
+        None => format!("{} {}", kind, range),
+        Some(WatchCondition::Equals(value)) => format!("{} {} = ${:02X}", kind, range, value),
+        Some(WatchCondition::Changes) => format!("{} {} changed", kind, range),
+    }
+}
+
+/// Prints memory 16 bytes to a line, with the printable ones as text at the end.
+fn dump_memory(cpu: &CPU, address: u16, length: u16) {
+    let bytes: Vec<(u16, u8)> = (0..length).map(|offset| address.wrapping_add(offset))
+        .map(|address| (address, cpu.peek(address)))
+        .collect();
+    for row in bytes.chunks(16) {
+        let hex: Vec<String> = row.iter().map(|(_, byte)| format!("{:02X}", byte)).collect();
+        let text: String = row.iter()
+            .map(|&(_, byte)| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+        println!("${:04X}  {:<47}  {}", row[0].0, hex.join(" "), text);
+    }
+}
+
+/// Disassembles `count` instructions from an address, or around PC without one.
+fn list(cpu: &CPU, start: Option<u16>, count: usize) {
+    let pc = cpu.program_counter();
+    let start = start.unwrap_or_else(|| start_before(cpu, pc));
+    let mut address = start;
+    for _ in 0..count {
+        println!("{}", list_line(cpu, address, address == pc));
+        address = address.wrapping_add(cpu.disassemble(address).length);
+    }
+}
+
+/// Where to start disassembling so a few instructions before `pc` show up too. Instructions can't be
+/// decoded backwards, so this tries starting further and further back until the decoding lines up with PC.
+fn start_before(cpu: &CPU, pc: u16) -> u16 {
+    for distance in (1..=LIST_BEFORE as u16 * 3).rev() {
+        let mut starts = Vec::new();
+        let mut address = pc.wrapping_sub(distance);
+        while starts.len() <= LIST_BEFORE * 3 && address != pc && pc.wrapping_sub(address) <= distance {
+            starts.push(address);
+            address = address.wrapping_add(cpu.disassemble(address).length);
+        }
+        if address == pc {
+            return starts[starts.len().saturating_sub(LIST_BEFORE)]
+        }
+    }
+    pc
+}
+
+fn list_line(cpu: &CPU, address: u16, current: bool) -> String {
+    let instruction = cpu.disassemble(address);
+    let bytes: Vec<String> = (0..instruction.length).map(|offset| format!("{:02X}", cpu.peek(address.wrapping_add(offset)))).collect();
+    let instruction = instruction.to_string_with_labels(|address| cpu.label(address));
+    let line = format!("{} ${:04X}  {:<8}  {}", if current { '>' } else { ' ' }, address, bytes.join(" "), instruction);
+    match cpu.label(address) {
+        Some(label) if !is_offset(&label) => format!("{}:\n{}", label, line),
+        _ => line,
+    }
+}
+
+/// Whether a label is `Label+offset` rather than right on the address. Label names can't have a + in them.
+fn is_offset(label: &str) -> bool {
+    label.contains('+')
+}
+
+/// A label or hex address, for anything but breakpoints.
+fn parse_address(cpu: &CPU, text: &str) -> Result<u16> {
+    Ok(parse_location(cpu, text)?.address)
+}
+
+/// A label, `BANK:ADDR`, or hex address. Labels in switchable banks keep their bank, so the breakpoint is only for it.
+fn parse_location(cpu: &CPU, text: &str) -> Result<Location> {
+    if let Some((bank, address)) = cpu.symbols().and_then(|symbols| symbols.find(text)) {
+        let banked = matches!(address, 0x4000..=0x7FFF | 0xA000..=0xBFFF);
+        return Ok(Location { address, bank: banked.then_some(bank) })
+    }
+    if let Some((bank, address)) = text.split_once(':') {
+        return Ok(Location { address: parse_hex(address)?, bank: Some(parse_hex(bank)?) })
+    }
+    match parse_hex(text) {
+        Ok(address) => Ok(Location { address, bank: None }),
+        Err(_) if cpu.symbols().is_some() => bail!("{} isn't a symbol or hex number", text),
+        Err(err) => Err(err),
+    }
+}
+
+fn parse_hex(text: &str) -> Result<u16> {
+    let digits = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| anyhow!("{} isn't a hex number", text))
+}
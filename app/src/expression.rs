@@ -0,0 +1,479 @@
+//! Debugger expressions.
+//!
+//! A small language for breakpoint conditions and log messages, like `a == $3C && [hl] & $80` or `ly == 144`.
+//! Names are registers (`a`, `hl`, `sp`, `pc`, ...), flags (`zf`, `nf`, `hf`, `cf`), `ime`, and IO registers
+//! (`ly`, `lcdc`, `stat`, ...). `[ADDR]` reads a byte of memory.
+//!
+//! Unlike the rest of the debugger, plain numbers are decimal, so hex needs a `$` or `0x` in front.
+//! The operators are the usual C ones, except that the bitwise ones bind tighter than comparisons,
+//! so `[hl] & $80 == $80` does what it looks like it does.
+
+use std::fmt;
+use anyhow::{anyhow, bail, Result};
+use gbcore::{Reg8, Reg16, CPU};
+
+// Longest first, so `<<` isn't read as two `<`
+const SYMBOLS: [&str; 24] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<<", ">>",
+    "<", ">", "+", "-", "*", "/", "%", "&", "|", "^", "!", "~", "(", ")", "[", "]",
+];
+
+const IO_REGISTERS: [(&str, u16); 28] = [
+    ("p1", 0xFF00), ("joyp", 0xFF00), ("sb", 0xFF01), ("sc", 0xFF02),
+    ("div", 0xFF04), ("tima", 0xFF05), ("tma", 0xFF06), ("tac", 0xFF07), ("if", 0xFF0F),
+    ("nr50", 0xFF24), ("nr51", 0xFF25), ("nr52", 0xFF26),
+    ("lcdc", 0xFF40), ("stat", 0xFF41), ("scy", 0xFF42), ("scx", 0xFF43), ("ly", 0xFF44), ("lyc", 0xFF45),
+    ("dma", 0xFF46), ("bgp", 0xFF47), ("obp0", 0xFF48), ("obp1", 0xFF49), ("wy", 0xFF4A), ("wx", 0xFF4B),
+    ("key1", 0xFF4D), ("vbk", 0xFF4F), ("svbk", 0xFF70), ("ie", 0xFFFF),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Flag {
+    Zero,
+    Subtract,
+    HalfCarry,
+    Carry,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Number(i64),
+    Reg8(Reg8),
+    Reg16(Reg16),
+    Flag(Flag),
+    Ime,
+    Io(u16),
+    Memory(Box<Node>),
+    Unary(&'static str, Box<Node>),
+    Binary(&'static str, Box<Node>, Box<Node>),
+}
+
+/// A parsed expression, ready to evaluate against the CPU as often as needed.
+#[derive(Clone, Debug)]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens: &tokens, position: 0 };
+        let root = parser.expression(0)?;
+        if let Some(token) = parser.peek() {
+            bail!("Unexpected {} in {}", describe(token), source);
+        }
+        Ok(Self { source: source.trim().to_string(), root })
+    }
+
+    pub fn evaluate(&self, cpu: &CPU) -> Result<i64> {
+        evaluate(&self.root, cpu)
+    }
+
+    /// Whether it comes out as anything but zero.
+    pub fn is_true(&self, cpu: &CPU) -> Result<bool> {
+        Ok(self.evaluate(cpu)? != 0)
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while let Some(first) = rest.chars().next() {
+        let length = if first == '$' || first.is_ascii_digit() {
+            let length = rest[1..].find(|c: char| !c.is_ascii_alphanumeric()).map_or(rest.len(), |length| length + 1);
+            tokens.push(Token::Number(parse_number(&rest[..length])?));
+            length
+        } else if first.is_ascii_alphabetic() || first == '_' {
+            let length = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..length].to_ascii_lowercase()));
+            length
+        } else {
+            let symbol = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol))
+                .ok_or_else(|| anyhow!("Unexpected {} in {}", first, source))?;
+            tokens.push(Token::Symbol(symbol));
+            symbol.len()
+        };
+        rest = rest[length..].trim_start();
+    }
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Result<i64> {
+    let (digits, radix) = if let Some(hex) = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")) {
+        (hex, 16)
+    } else if let Some(binary) = text.strip_prefix("0b") {
+        (binary, 2)
+    } else {
+        (text, 10)
+    };
+    i64::from_str_radix(digits, radix).map_err(|_| anyhow!("{} isn't a number", text))
+}
+
+/// How tightly a binary operator binds, higher first.
+fn precedence(symbol: &str) -> Option<u8> {
+    let precedence = match symbol {
+        "*" | "/" | "%" => 9,
+        "+" | "-" => 8,
+        "<<" | ">>" => 7,
+        "&" => 6,
+        "^" => 5,
+        "|" => 4,
+        "==" | "!=" | "<" | "<=" | ">" | ">=" => 3,
+        "&&" => 2,
+        "||" => 1,
+        _ => return None,
+    };
+    Some(precedence)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<&Token> {
+        let token = self.tokens.get(self.position).ok_or_else(|| anyhow!("Expression ends too soon"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<()> {
+        match self.next()? {
+            Token::Symbol(found) if *found == symbol => Ok(()),
+            token => bail!("Expected {} but found {}", symbol, describe(token)),
+        }
+    }
+
+    /// Parses operators that bind at least as tightly as `minimum`, climbing up through tighter ones.
+    fn expression(&mut self, minimum: u8) -> Result<Node> {
+        let mut left = self.operand()?;
+        while let Some(Token::Symbol(symbol)) = self.peek()
+            && let Some(precedence) = precedence(symbol)
+            && precedence >= minimum
+        {
+            let symbol = *symbol;
+            self.position += 1;
+            let right = self.expression(precedence + 1)?;
+            left = Node::Binary(symbol, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn operand(&mut self) -> Result<Node> {
+        let node = match self.next()?.clone() {
+            Token::Number(number) => Node::Number(number),
+            Token::Name(name) => name_node(&name)?,
+            Token::Symbol("(") => {
+                let node = self.expression(0)?;
+                self.expect(")")?;
+                node
+            },
+            Token::Symbol("[") => {
+                let address = self.expression(0)?;
+                self.expect("]")?;
+                Node::Memory(Box::new(address))
+            },
+            Token::Symbol(symbol @ ("-" | "!" | "~")) => Node::Unary(symbol, Box::new(self.operand()?)),
+            token => bail!("Unexpected {}", describe(&token)),
+        };
+        Ok(node)
+    }
+}
+
+fn name_node(name: &str) -> Result<Node> {
+    let node = match name {
+        "a" => Node::Reg8(Reg8::A),
+        "f" => Node::Reg8(Reg8::F),
+        "b" => Node::Reg8(Reg8::B),
+        "c" => Node::Reg8(Reg8::C),
+        "d" => Node::Reg8(Reg8::D),
+        "e" => Node::Reg8(Reg8::E),
+        "h" => Node::Reg8(Reg8::H),
+        "l" => Node::Reg8(Reg8::L),
+        "af" => Node::Reg16(Reg16::AF),
+        "bc" => Node::Reg16(Reg16::BC),
+        "de" => Node::Reg16(Reg16::DE),
+        "hl" => Node::Reg16(Reg16::HL),
+        "sp" => Node::Reg16(Reg16::SP),
+        "pc" => Node::Reg16(Reg16::PC),
+        "zf" => Node::Flag(Flag::Zero),
+        "nf" => Node::Flag(Flag::Subtract),
+        "hf" => Node::Flag(Flag::HalfCarry),
+        "cf" => Node::Flag(Flag::Carry),
+        "ime" => Node::Ime,
+        _ => match IO_REGISTERS.iter().find(|(io, _)| *io == name) {
+            Some(&(_, address)) => Node::Io(address),
+            None => bail!("Nothing called {}", name),
+        },
+    };
+    Ok(node)
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Number(number) => number.to_string(),
+        Token::Name(name) => name.clone(),
+        Token::Symbol(symbol) => symbol.to_string(),
+    }
+}
+
+fn evaluate(node: &Node, cpu: &CPU) -> Result<i64> {
+    let value = match node {
+        Node::Number(number) => *number,
+        Node::Reg8(register) => cpu.reg8(*register) as i64,
+        Node::Reg16(register) => cpu.reg16(*register) as i64,
+        Node::Flag(flag) => {
+            let state = cpu.state();
+            let set = match flag {
+                Flag::Zero => state.zero(),
+                Flag::Subtract => state.subtract(),
+                Flag::HalfCarry => state.half_carry(),
+                Flag::Carry => state.carry(),
+            };
+            set as i64
+        },
+        Node::Ime => cpu.ime() as i64,
+        Node::Io(address) => cpu.peek(*address) as i64,
+        Node::Memory(address) => {
+            let address = evaluate(address, cpu)?;
+            let address = u16::try_from(address).map_err(|_| anyhow!("${:X} isn't an address", address))?;
+            cpu.peek(address) as i64
+        },
+        Node::Unary(symbol, operand) => {
+            let operand = evaluate(operand, cpu)?;
+            match *symbol {
+                "-" => operand.wrapping_neg(),
+                "!" => (operand == 0) as i64,
+                _ => !operand,
+            }
+        },
+        // These two only look at the right side if they need to
+        Node::Binary("&&", left, right) => (evaluate(left, cpu)? != 0 && evaluate(right, cpu)? != 0) as i64,
+        Node::Binary("||", left, right) => (evaluate(left, cpu)? != 0 || evaluate(right, cpu)? != 0) as i64,
+        Node::Binary(symbol, left, right) => {
+            let left = evaluate(left, cpu)?;
+            let right = evaluate(right, cpu)?;
+            match *symbol {
+                "*" => left.wrapping_mul(right),
+                "/" => left.checked_div(right).ok_or_else(|| anyhow!("Division by zero"))?,
+                "%" => left.checked_rem(right).ok_or_else(|| anyhow!("Division by zero"))?,
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "<<" | ">>" => {
+                    let shift = u32::try_from(right).ok().filter(|&shift| shift < 64)
+                        .ok_or_else(|| anyhow!("Can't shift by {}", right))?;
+                    if *symbol == "<<" { left << shift } else { left >> shift }
+                },
+                "&" => left & right,
+                "^" => left ^ right,
+                "|" => left | right,
+                "==" => (left == right) as i64,
+                "!=" => (left != right) as i64,
+                "<" => (left < right) as i64,
+                "<=" => (left <= right) as i64,
+                ">" => (left > right) as i64,
+                _ => (left >= right) as i64,
+            }
+        },
+    };
+    Ok(value)
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Radix {
+    Hex,
+    Decimal,
+    Binary,
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Text(String),
+    Value(Expression, Radix),
+}
+
+/// A log message with expressions in braces, like `a={a} ly={ly:d}`. Values are hex unless they end in
+/// `:d` for decimal or `:b` for binary. `{{` and `}}` are literal braces.
+#[derive(Clone, Debug)]
+pub struct Message {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl Message {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = source.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                },
+                '{' => {
+                    let mut inside = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => inside.push(c),
+                            None => bail!("Unmatched {{ in message, use {{{{ for a brace"),
+                        }
+                    }
+                    let (expression, radix) = match inside.rsplit_once(':') {
+                        Some((expression, "x")) => (expression, Radix::Hex),
+                        Some((expression, "d")) => (expression, Radix::Decimal),
+                        Some((expression, "b")) => (expression, Radix::Binary),
+                        Some((_, format)) => bail!("Unknown format {}, expected x, d, or b", format),
+                        None => (inside.as_str(), Radix::Hex),
+                    };
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Value(Expression::parse(expression)?, radix));
+                },
+                '}' => bail!("Unmatched }} in message, use }}}} for a brace"),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Self { source: source.to_string(), parts })
+    }
+
+    pub fn format(&self, cpu: &CPU) -> Result<String> {
+        let mut message = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => message.push_str(text),
+                Part::Value(expression, radix) => {
+                    let value = expression.evaluate(cpu)?;
+                    let value = match radix {
+                        Radix::Hex if value < 0 => format!("-${:X}", value.unsigned_abs()),
+                        Radix::Hex => format!("${:X}", value),
+                        Radix::Decimal => value.to_string(),
+                        Radix::Binary => format!("%{:b}", value),
+                    };
+                    message.push_str(&value);
+                },
+            }
+        }
+        Ok(message)
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(source: &str, cpu: &CPU) -> i64 {
+        Expression::parse(source).unwrap().evaluate(cpu).unwrap()
+    }
+
+    #[test]
+    fn precedence() {
+        let cpu = CPU::new();
+        let cases = [
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("10 - 4 - 3", 3),
+            ("1 << 2 + 1", 8),
+            ("$81 & $80 == $80", 1),
+            ("1 | 2 ^ 3 & 4", 3),
+            ("0 || 1 && 0", 0),
+            ("-2 * -3", 6),
+            ("!0 + ~0", 0),
+            ("7 % 4 >= 3 && 8 / 3 != 3", 1),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(evaluate(source, &cpu), expected, "{}", source);
+        }
+    }
+
+    #[test]
+    fn literals() {
+        let cpu = CPU::new();
+        for source in ["$1F", "0x1F", "0x1f", "31", "0b11111"] {
+            assert_eq!(evaluate(source, &cpu), 31, "{}", source);
+        }
+    }
+
+    #[test]
+    fn registers_flags_and_io() {
+        let mut cpu = CPU::new();
+        cpu.set_reg16(Reg16::HL, 0xC123);
+        cpu.set_reg8(Reg8::A, 0x3C);
+        cpu.set_reg8(Reg8::F, 0x90);
+        cpu.poke(0xFF45, 0x90).unwrap();
+        assert_eq!(evaluate("hl", &cpu), 0xC123);
+        assert_eq!(evaluate("h == $C1 && l == $23", &cpu), 1);
+        assert_eq!(evaluate("A == $3C", &cpu), 1);
+        assert_eq!(evaluate("zf * 8 + nf * 4 + hf * 2 + cf", &cpu), 0b1001);
+        assert_eq!(evaluate("lyc", &cpu), 0x90);
+        assert_eq!(evaluate("LYC == 144", &cpu), 1);
+    }
+
+    #[test]
+    fn memory_reads() {
+        let mut cpu = CPU::new();
+        cpu.poke(0xC000, 0x12).unwrap();
+        cpu.poke(0xC001, 0x34).unwrap();
+        cpu.set_reg16(Reg16::HL, 0xC000);
+        assert_eq!(evaluate("[hl]", &cpu), 0x12);
+        assert_eq!(evaluate("[hl + 1]", &cpu), 0x34);
+        assert_eq!(evaluate("[$C000] << 8 | [$C001]", &cpu), 0x1234);
+        assert!(Expression::parse("[-1]").unwrap().evaluate(&cpu).is_err());
+    }
+
+    #[test]
+    fn bad_expressions() {
+        for source in ["", "1 +", "(1", "[hl", "1 2", "foo", "$xyz", "0b2", "a = 1", "a @ b"] {
+            assert!(Expression::parse(source).is_err(), "{}", source);
+        }
+        let cpu = CPU::new();
+        for source in ["1 / 0", "1 % 0", "1 << 64"] {
+            assert!(Expression::parse(source).unwrap().evaluate(&cpu).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn messages() {
+        let mut cpu = CPU::new();
+        cpu.set_reg8(Reg8::A, 0x2A);
+        let message = Message::parse("a={a} is {a:d} or {a:b}, {{literal}} {-a}").unwrap();
+        assert_eq!(message.format(&cpu).unwrap(), "a=$2A is 42 or %101010, {literal} -$2A");
+        for source in ["{a", "a}", "{a:q}", "{}", "{1 +}"] {
+            assert!(Message::parse(source).is_err(), "{}", source);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! GBS subcommand.
+//!
+//! Renders tracks from GBS music rips straight to WAV (and optionally MIDI), without needing an audio device.
+
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+use clap::Args;
+use gbcore::{GbsPlayer, DOTS_PER_SECOND};
+
+use crate::testing;
+
+#[derive(Args)]
+pub struct GbsArgs {
+    /// GBS file to play
+    file: PathBuf,
+
+    /// Track to play, counting from 1, otherwise the file's default track
+    #[arg(long)]
+    track: Option<u8>,
+
+    /// Length to render, in seconds
+    #[arg(long, default_value_t = 120.0)]
+    seconds: f64,
+
+    /// WAV file to render to, otherwise just print the file's details
+    #[arg(long, short, value_name = "WAV")]
+    out: Option<PathBuf>,
+
+    /// Also render each sound channel to its own WAV file, next to the main one
+    #[arg(long, requires = "out")]
+    stems: bool,
+
+    /// Also export the track's notes to a MIDI file
+    #[arg(long, value_name = "MID", requires = "out")]
+    midi: Option<PathBuf>,
+
+    /// Sample rate to render at, in Hz
+    #[arg(long, default_value_t = gbcore::DEFAULT_SAMPLE_RATE)]
+    sample_rate: u32,
+}
+
+pub fn run(args: &GbsArgs) -> Result<()> {
+    let data = std::fs::read(&args.file).with_context(|| format!("Failed to read {}", args.file.display()))?;
+    let mut player = GbsPlayer::new(&data)?;
+
+    let header = player.header();
+    println!("Title:     {}", header.title);
+    println!("Author:    {}", header.author);
+    println!("Copyright: {}", header.copyright);
+    println!("Tracks:    {} (default {})", header.song_count, header.first_song);
+    println!("Driver:    load {:#06X}, init {:#06X}, play {:#06X} on {}",
+        header.load_address, header.init_address, header.play_address,
+        if header.uses_timer() { "timer" } else { "VBlank" });
+
+    let Some(out) = &args.out else {
+        return Ok(())
+    };
+    if let Some(track) = args.track {
+        player.start_song(track)?;
+    }
+    let cpu = player.cpu();
+    cpu.set_sample_rate(args.sample_rate)?;
+    cpu.start_recording(out, args.stems)?;
+    if let Some(midi) = &args.midi {
+        cpu.start_midi_export(midi)?;
+    }
+
+    println!("Rendering track {} for {} seconds to {}", player.song(), args.seconds, out.display());
+    let result = testing::catch_panics(|| player.run((args.seconds * DOTS_PER_SECOND as f64) as u64));
+    // Finish both files whatever happened, so a failed render still leaves something playable
+    let recorded = player.cpu().stop_recording();
+    let exported = player.cpu().stop_midi_export();
+    result.and(recorded).and(exported)
+}
@@ -0,0 +1,526 @@
+//! GDB stub.
+//!
+//! Serves the GDB remote serial protocol on a localhost port, so GDB (or anything else that speaks it) can
+//! read and write registers and memory, set breakpoints and watchpoints, and step or continue.
+//! GDB doesn't know the SM83, so the registers come from a target description of our own: af, bc, de, hl, sp,
+//! and pc, 16 bits each. Connect with `target remote localhost:PORT`.
+//!
+//! One client at a time; the emulator exits once it detaches or kills the session.
+
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use anyhow::{anyhow, bail, Context, Result};
+use gbcore::{Reg16, WatchHit, WatchKind, Watchpoint, CPU};
+
+use crate::testing;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="gbcore.sm83">
+    <reg name="af" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="bc" bitsize="16" type="uint16"/>
+    <reg name="de" bitsize="16" type="uint16"/>
+    <reg name="hl" bitsize="16" type="uint16"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+// In the order of the target description, which is also the order `g` and `G` packets have them in
+const REGISTERS: [Reg16; 6] = [Reg16::AF, Reg16::BC, Reg16::DE, Reg16::HL, Reg16::SP, Reg16::PC];
+const PACKET_SIZE: usize = 0x4000;
+// GDB sends this byte on its own, outside of any packet, to stop a continue
+const INTERRUPT: u8 = 0x03;
+// How many instructions to run between checking for an interrupt
+const POLL_INTERVAL: u32 = 1024;
+
+// Signal numbers for stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// Why the CPU stopped, for the stop reply.
+enum Stop {
+    Signal(u8),
+    Watch(WatchHit),
+}
+
+/// What a `Z` or `z` packet sets or clears.
+#[derive(Debug, PartialEq)]
+enum Point {
+    Breakpoint(u16),
+    Watch(Watchpoint),
+}
+
+/// Something GDB sent, taken off the front of what's been read so far.
+#[derive(Debug, PartialEq)]
+enum Incoming {
+    Packet(Vec<u8>),
+    /// A packet that didn't match its checksum, which GDB has to send again.
+    Corrupt,
+    /// GDB didn't get the last packet sent to it, and wants it again.
+    Retransmit,
+}
+
+struct Connection {
+    stream: TcpStream,
+    // Read but not yet looked at
+    pending: VecDeque<u8>,
+    last_sent: Vec<u8>,
+}
+
+struct Stub {
+    breakpoints: BTreeSet<u16>,
+    last_stop: u8,
+}
+
+/// Waits for GDB to connect and then serves it until it goes away.
+pub fn serve(cpu: &mut CPU, port: u16) -> Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+        .with_context(|| format!("Failed to listen on port {}", port))?;
+    println!("Waiting for GDB on {}", listener.local_addr()?);
+    let (stream, address) = listener.accept().context("Failed to accept a connection")?;
+    println!("GDB connected from {}", address);
+    stream.set_nodelay(true)?;
+    let mut connection = Connection { stream, pending: VecDeque::new(), last_sent: Vec::new() };
+    let mut stub = Stub { breakpoints: BTreeSet::new(), last_stop: SIGTRAP };
+
+    while let Some(packet) = connection.receive()? {
+        match stub.handle(cpu, &mut connection, &packet)? {
+            Some(reply) => connection.send(reply.as_bytes())?,
+            None => {
+                println!("GDB disconnected");
+                return Ok(())
+            },
+        }
+    }
+    println!("GDB disconnected");
+    Ok(())
+}
+
+impl Stub {
+    /// Replies to a packet, or returns None if the session is over.
+    fn handle(&mut self, cpu: &mut CPU, connection: &mut Connection, packet: &[u8]) -> Result<Option<String>> {
+        let Some((&command, body)) = packet.split_first() else {
+            return Ok(Some(String::new()))
+        };
+        let reply = match command {
+            b'?' => format!("S{:02x}", self.last_stop),
+            b'g' => read_registers(cpu),
+            b'G' => reply_ok(write_registers(cpu, body)),
+            b'p' => match parse_hex(body).ok().and_then(|number| REGISTERS.get(number as usize)) {
+                Some(&register) => hex_u16(cpu.reg16(register)),
+                None => error(),
+            },
+            b'P' => reply_ok(write_register(cpu, body)),
+            b'm' => read_memory(cpu, body).unwrap_or_else(|_| error()),
+            b'M' => reply_ok(write_memory(cpu, body, false)),
+            b'X' => reply_ok(write_memory(cpu, body, true)),
+            b'Z' | b'z' => reply_ok(self.set_point(cpu, command == b'Z', body)),
+            b's' | b'c' => {
+                if !body.is_empty() {
+                    match parse_hex(body) {
+                        Ok(address) => cpu.set_program_counter(address),
+                        Err(_) => return Ok(Some(error())),
+                    }
+                }
+                let stop = self.execute(cpu, connection, command == b's')?;
+                self.stop_reply(stop)
+            },
+            b'H' | b'T' => "OK".to_string(),
+            b'D' => {
+                connection.send(b"OK")?;
+                return Ok(None)
+            },
+            b'k' => return Ok(None),
+            b'q' => query(body),
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn set_point(&mut self, cpu: &mut CPU, insert: bool, body: &[u8]) -> Result<()> {
+        match (parse_point(body)?, insert) {
+            (Point::Breakpoint(address), true) => { self.breakpoints.insert(address); },
+            (Point::Breakpoint(address), false) => { self.breakpoints.remove(&address); },
+            (Point::Watch(watchpoint), true) => cpu.add_watchpoint(watchpoint),
+            (Point::Watch(watchpoint), false) => { cpu.remove_watchpoint(&watchpoint); },
+        }
+        Ok(())
+    }
+
+    /// Runs one instruction, or until a breakpoint, watchpoint, or interrupt from GDB.
+    fn execute(&mut self, cpu: &mut CPU, connection: &mut Connection, step: bool) -> Result<Stop> {
+        // Anything set off by memory being read or written over the connection doesn't count
+        cpu.take_watch_hit();
+        let mut count = 0u32;
+        let result = testing::catch_panics(|| {
+            loop {
+                cpu.cycle()?;
+                if let Some(hit) = cpu.take_watch_hit() {
+                    return Ok(Stop::Watch(hit))
+                }
+                if step || (!cpu.halted() && self.breakpoints.contains(&cpu.program_counter())) {
+                    return Ok(Stop::Signal(SIGTRAP))
+                }
+                count += 1;
+                if count.is_multiple_of(POLL_INTERVAL) && connection.interrupted()? {
+                    return Ok(Stop::Signal(SIGINT))
+                }
+            }
+        });
+        match result {
+            Ok(stop) => Ok(stop),
+            Err(err) => {
+                // Shown in GDB's console too, since otherwise all it gets is the SIGILL
+                let message = format!("Error: {:#}", err);
+                eprintln!("{}", message);
+                connection.send(console_output(&message).as_bytes())?;
+                Ok(Stop::Signal(SIGILL))
+            },
+        }
+    }
+
+    fn stop_reply(&mut self, stop: Stop) -> String {
+        match stop {
+            Stop::Signal(signal) => {
+                self.last_stop = signal;
+                format!("S{:02x}", signal)
+            },
+            Stop::Watch(hit) => {
+                self.last_stop = SIGTRAP;
+                let name = match hit.watchpoint.kind {
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Write => "watch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, name, hit.address)
+            },
+        }
+    }
+}
+
+fn query(body: &[u8]) -> String {
+    let body = String::from_utf8_lossy(body);
+    if body.starts_with("Supported") {
+        format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE)
+    } else if body == "Attached" {
+        "1".to_string()
+    } else if body == "fThreadInfo" {
+        "m1".to_string()
+    } else if body == "sThreadInfo" {
+        "l".to_string()
+    } else if body == "C" {
+        "QC1".to_string()
+    } else if let Some(range) = body.strip_prefix("Xfer:features:read:target.xml:") {
+        read_target_xml(range).unwrap_or_else(|_| error())
+    } else {
+        String::new()
+    }
+}
+
+/// Parses the body of a `Z` or `z` packet: type, address, and length.
+fn parse_point(body: &[u8]) -> Result<Point> {
+    let body = std::str::from_utf8(body)?;
+    let mut fields = body.split(',');
+    let kind = fields.next().context("Missing type")?;
+    let address = parse_hex(fields.next().context("Missing address")?.as_bytes())?;
+    let length = parse_hex(fields.next().context("Missing length")?.as_bytes())?;
+    let watch = match kind {
+        // Software and hardware breakpoints are all the same here
+        "0" | "1" => return Ok(Point::Breakpoint(address)),
+        "2" => WatchKind::Write,
+        "3" => WatchKind::Read,
+        "4" => WatchKind::Access,
+        _ => bail!("Unknown breakpoint type {}", kind),
+    };
+    let end = address.checked_add(length.max(1) - 1).context("Watchpoint runs past the end of memory")?;
+    Ok(Point::Watch(Watchpoint::new(address, end, watch)))
+}
+
+/// An `O` packet, which GDB prints to its console.
+fn console_output(text: &str) -> String {
+    let hex: String = format!("{}\n", text).bytes().map(|byte| format!("{:02x}", byte)).collect();
+    format!("O{}", hex)
+}
+
+/// A chunk of the target description, `m` if there's more after it and `l` if it's the last.
+fn read_target_xml(range: &str) -> Result<String> {
+    let (offset, length) = range.split_once(',').context("Expected an offset and length")?;
+    let offset = usize::from_str_radix(offset, 16)?;
+    let length = usize::from_str_radix(length, 16)?;
+    let xml = TARGET_XML.as_bytes();
+    let start = offset.min(xml.len());
+    let end = offset.saturating_add(length).min(xml.len());
+    let more = if end < xml.len() { 'm' } else { 'l' };
+    Ok(format!("{}{}", more, String::from_utf8_lossy(&xml[start..end])))
+}
+
+fn read_registers(cpu: &CPU) -> String {
+    REGISTERS.iter().map(|&register| hex_u16(cpu.reg16(register))).collect()
+}
+
+fn write_registers(cpu: &mut CPU, body: &[u8]) -> Result<()> {
+    if body.len() < REGISTERS.len() * 4 {
+        bail!("Expected {} registers", REGISTERS.len());
+    }
+    for (register, hex) in REGISTERS.iter().zip(body.chunks(4)) {
+        cpu.set_reg16(*register, parse_u16(hex)?);
+    }
+    Ok(())
+}
+
+fn write_register(cpu: &mut CPU, body: &[u8]) -> Result<()> {
+    let (number, value) = split_at_byte(body, b'=')?;
+    let register = REGISTERS.get(parse_hex(number)? as usize).context("No such register")?;
+    cpu.set_reg16(*register, parse_u16(value)?);
+    Ok(())
+}
+
+fn read_memory(cpu: &CPU, body: &[u8]) -> Result<String> {
+    let (address, length) = split_at_byte(body, b',')?;
+    let address = parse_hex(address)?;
+    let length = parse_hex(length)?;
+    Ok((0..length).map(|offset| format!("{:02x}", cpu.peek(address.wrapping_add(offset)))).collect())
+}
+
+/// Handles `M`, with the data in hex, and `X`, with it in binary.
+fn write_memory(cpu: &mut CPU, body: &[u8], binary: bool) -> Result<()> {
+    let (range, data) = split_at_byte(body, b':')?;
+    let (address, length) = split_at_byte(range, b',')?;
+    let address = parse_hex(address)?;
+    let data = if binary {
+        unescape(data)
+    } else {
+        data.chunks(2).map(|hex| parse_hex(hex).map(|byte| byte as u8)).collect::<Result<Vec<u8>>>()?
+    };
+    if data.len() != parse_hex(length)? as usize {
+        bail!("Data doesn't match the length");
+    }
+    for (offset, byte) in data.into_iter().enumerate() {
+        cpu.poke(address.wrapping_add(offset as u16), byte)?;
+    }
+    Ok(())
+}
+
+impl Connection {
+    /// Waits for the next packet and acknowledges it, or returns None if GDB hung up.
+    fn receive(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            match decode(&mut self.pending) {
+                Some(Incoming::Packet(packet)) => {
+                    self.stream.write_all(b"+")?;
+                    return Ok(Some(packet))
+                },
+                Some(Incoming::Corrupt) => self.stream.write_all(b"-")?,
+                Some(Incoming::Retransmit) => self.stream.write_all(&self.last_sent)?,
+                None => {
+                    let mut buffer = [0; 1024];
+                    let count = self.stream.read(&mut buffer).context("Failed to read from GDB")?;
+                    if count == 0 {
+                        return Ok(None)
+                    }
+                    self.pending.extend(&buffer[..count]);
+                },
+            }
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<()> {
+        let packet = encode(data);
+        self.stream.write_all(&packet)?;
+        self.last_sent = packet;
+        Ok(())
+    }
+
+    /// Checks whether GDB has asked to stop, without waiting for it to say anything.
+    fn interrupted(&mut self) -> Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut buffer = [0; 1024];
+        let result = self.stream.read(&mut buffer);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => bail!("GDB disconnected"),
+            Ok(count) => self.pending.extend(&buffer[..count]),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {},
+            Err(err) => return Err(err).context("Failed to read from GDB"),
+        }
+        let before = self.pending.len();
+        self.pending.retain(|&byte| byte != INTERRUPT);
+        Ok(self.pending.len() != before)
+    }
+}
+
+/// Takes the next packet or retransmit request off the front of what's been read,
+/// or returns None if there isn't a whole one there yet.
+/// Acks, and interrupts when there's nothing running to interrupt, get skipped over.
+fn decode(pending: &mut VecDeque<u8>) -> Option<Incoming> {
+    while let Some(&byte) = pending.front() {
+        match byte {
+            b'$' => break,
+            b'-' => {
+                pending.pop_front();
+                return Some(Incoming::Retransmit)
+            },
+            _ => { pending.pop_front(); },
+        }
+    }
+    // The packet runs up to the #, then there's a two digit checksum
+    let end = pending.iter().position(|&byte| byte == b'#')?;
+    if pending.len() < end + 3 {
+        return None
+    }
+    let framed: Vec<u8> = pending.drain(..end + 3).collect();
+    let packet = framed[1..end].to_vec();
+    if parse_hex(&framed[end + 1..]).ok() == Some(checksum(&packet) as u16) {
+        Some(Incoming::Packet(packet))
+    } else {
+        Some(Incoming::Corrupt)
+    }
+}
+
+/// Frames a reply as a packet, escaping it and adding the checksum.
+fn encode(data: &[u8]) -> Vec<u8> {
+    let mut packet = vec![b'$'];
+    packet.extend(escape(data));
+    packet.extend(format!("#{:02x}", checksum(&packet[1..])).bytes());
+    packet
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+// These can't show up as themselves in a packet, so they're sent as } followed by the byte xor 0x20
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            escaped.extend([b'}', byte ^ 0x20]);
+        } else {
+            escaped.push(byte);
+        }
+    }
+    escaped
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut bytes = data.iter();
+    let mut unescaped = Vec::with_capacity(data.len());
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'}' => unescaped.extend(bytes.next().map(|byte| byte ^ 0x20)),
+            _ => unescaped.push(byte),
+        }
+    }
+    unescaped
+}
+
+fn split_at_byte(data: &[u8], separator: u8) -> Result<(&[u8], &[u8])> {
+    let index = data.iter().position(|&byte| byte == separator)
+        .ok_or_else(|| anyhow!("Expected a {}", separator as char))?;
+    Ok((&data[..index], &data[index + 1..]))
+}
+
+fn parse_hex(hex: &[u8]) -> Result<u16> {
+    let text = std::str::from_utf8(hex)?;
+    u16::from_str_radix(text, 16).map_err(|_| anyhow!("{} isn't a 16 bit hex number", text))
+}
+
+/// Register values go over the wire in target byte order, so little endian.
+fn parse_u16(hex: &[u8]) -> Result<u16> {
+    Ok(parse_hex(hex)?.swap_bytes())
+}
+
+fn hex_u16(value: u16) -> String {
+    format!("{:04x}", value.swap_bytes())
+}
+
+fn reply_ok(result: Result<()>) -> String {
+    match result {
+        Ok(()) => "OK".to_string(),
+        Err(_) => error(),
+    }
+}
+
+fn error() -> String {
+    "E01".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(bytes: &[u8]) -> VecDeque<u8> {
+        bytes.iter().copied().collect()
+    }
+
+    #[test]
+    fn decodes_framed_packets() {
+        let mut bytes = pending(b"+$g#67$m0,1");
+        assert_eq!(decode(&mut bytes), Some(Incoming::Packet(b"g".to_vec())));
+        // The second packet hasn't all arrived yet, so it's left for later
+        assert_eq!(decode(&mut bytes), None);
+        bytes.extend(b"#fa");
+        assert_eq!(decode(&mut bytes), Some(Incoming::Packet(b"m0,1".to_vec())));
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn decodes_bad_checksums_and_retransmit_requests() {
+        let mut bytes = pending(b"$g#00-\x03$?#3f");
+        assert_eq!(decode(&mut bytes), Some(Incoming::Corrupt));
+        assert_eq!(decode(&mut bytes), Some(Incoming::Retransmit));
+        assert_eq!(decode(&mut bytes), Some(Incoming::Packet(b"?".to_vec())));
+    }
+
+    #[test]
+    fn encodes_replies_with_escapes() {
+        assert_eq!(encode(b"OK"), b"$OK#9a");
+        // The checksum covers the escaped bytes
+        assert_eq!(encode(b"a}b"), b"$a}]b#9d");
+        assert_eq!(escape(b"$#}*"), b"}\x04}\x03}]}\x0a");
+    }
+
+    #[test]
+    fn binary_writes_are_unescaped() {
+        let mut cpu = CPU::new();
+        write_memory(&mut cpu, b"c000,3:}\x03a}]", true).unwrap();
+        assert_eq!([cpu.peek(0xC000), cpu.peek(0xC001), cpu.peek(0xC002)], [b'#', b'a', b'}']);
+        assert!(write_memory(&mut cpu, b"c000,2:a", true).is_err());
+    }
+
+    #[test]
+    fn parses_breakpoints_and_watchpoints() {
+        assert_eq!(parse_point(b"0,150,1").unwrap(), Point::Breakpoint(0x0150));
+        assert_eq!(parse_point(b"1,150,1").unwrap(), Point::Breakpoint(0x0150));
+        assert_eq!(parse_point(b"2,c000,2").unwrap(), Point::Watch(Watchpoint::new(0xC000, 0xC001, WatchKind::Write)));
+        assert_eq!(parse_point(b"3,c000,1").unwrap(), Point::Watch(Watchpoint::new(0xC000, 0xC000, WatchKind::Read)));
+        assert_eq!(parse_point(b"4,c000,0").unwrap(), Point::Watch(Watchpoint::new(0xC000, 0xC000, WatchKind::Access)));
+        assert!(parse_point(b"5,c000,1").is_err());
+        assert!(parse_point(b"2,ffff,2").is_err());
+        assert!(parse_point(b"2,c000").is_err());
+    }
+
+    #[test]
+    fn registers_go_in_target_description_order() {
+        let mut cpu = CPU::new();
+        for (register, value) in REGISTERS.into_iter().zip([0x12F0, 0x3456, 0x789A, 0xBCDE, 0xFFFE, 0x0150]) {
+            cpu.set_reg16(register, value);
+        }
+        // Each one little endian
+        assert_eq!(read_registers(&cpu), "f01256349a78debcfeff5001");
+
+        write_registers(&mut cpu, b"cdab000000000000feffff00").unwrap();
+        assert_eq!(cpu.reg16(Reg16::AF), 0xABC0);
+        assert_eq!(cpu.reg16(Reg16::SP), 0xFFFE);
+        assert_eq!(cpu.reg16(Reg16::PC), 0x00FF);
+    }
+
+    #[test]
+    fn console_output_is_hex() {
+        assert_eq!(console_output("Hi"), "O48690a");
+    }
+}
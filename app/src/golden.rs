@@ -0,0 +1,211 @@
+//! Golden subcommand.
+//!
+//! Regression checks across a pile of ROMs. Each one runs for a fixed number of frames, with scripted input
+//! if it has any, and the final framebuffer and all the audio it produced get hashed. The hashes are compared
+//! with a manifest kept alongside the ROMs, so any change in what comes out gets flagged, on purpose or not.
+//!
+//! The manifest has a line per ROM: its path, then the framebuffer and audio hashes in hex.
+//! An input script sits next to its ROM with the extension `.input`, and each line is a frame number
+//! followed by the buttons held from then on, e.g. `120 start` to press Start, then `125` to let go.
+//! Blank lines and anything after a `#` are ignored in both.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Args;
+use gbcore::{Button, DOTS_PER_FRAME};
+
+use crate::testing;
+
+#[derive(Args)]
+pub struct GoldenArgs {
+    /// ROMs to run, or directories to search for them
+    #[arg(required = true)]
+    roms: Vec<PathBuf>,
+
+    /// Manifest of expected hashes
+    #[arg(long, value_name = "FILE", default_value = "golden.txt")]
+    manifest: PathBuf,
+
+    /// Frames to run each ROM for before hashing
+    #[arg(long, default_value_t = 600)]
+    frames: u64,
+
+    /// Write the hashes seen to the manifest instead of checking them
+    #[arg(long)]
+    update: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Hashes {
+    video: u64,
+    audio: u64,
+}
+
+/// Runs every ROM and prints a table of results. Returns true if nothing changed.
+pub fn run(args: &GoldenArgs) -> Result<bool> {
+    let roms = testing::find_roms(&args.roms)?;
+    let mut expected = if args.manifest.exists() { read_manifest(&args.manifest)? } else { BTreeMap::new() };
+    let mut seen = BTreeMap::new();
+    let mut rows = vec![vec!["ROM".to_string(), "Result".to_string(), "Video".to_string(), "Audio".to_string(), "Details".to_string()]];
+    let mut unchanged = 0;
+    for rom in &roms {
+        let name = testing::display_name(rom, &args.roms);
+        let hashes = testing::catch_panics(|| run_rom(rom, args.frames));
+        let (result, details) = match (&hashes, expected.remove(&name)) {
+            (Err(err), _) => ("ERROR", format!("{:#}", err)),
+            (Ok(_), None) => ("NEW", "not in the manifest".to_string()),
+            (Ok(hashes), Some(expected)) if *hashes == expected => ("passed", String::new()),
+            (Ok(hashes), Some(expected)) => {
+                let mut changed = Vec::new();
+                if hashes.video != expected.video {
+                    changed.push("video");
+                }
+                if hashes.audio != expected.audio {
+                    changed.push("audio");
+                }
+                ("CHANGED", format!("{} changed", changed.join(" and ")))
+            },
+        };
+        if result == "passed" {
+            unchanged += 1;
+        }
+        let (video, audio) = match &hashes {
+            Ok(hashes) => (format!("{:016x}", hashes.video), format!("{:016x}", hashes.audio)),
+            Err(_) => (String::new(), String::new()),
+        };
+        rows.push(vec![name.clone(), result.to_string(), video, audio, details]);
+        if let Ok(hashes) = hashes {
+            seen.insert(name, hashes);
+        }
+    }
+    // Whatever is left in the manifest never got run
+    for name in expected.keys() {
+        rows.push(vec![name.clone(), "MISSING".to_string(), String::new(), String::new(), "in the manifest, but not found".to_string()]);
+    }
+    testing::print_table(&rows);
+
+    if args.update {
+        write_manifest(&args.manifest, &seen)?;
+        println!("\nWrote {} hashes to {}", seen.len(), args.manifest.display());
+        return Ok(true)
+    }
+    println!("\n{} of {} unchanged", unchanged, rows.len() - 1);
+    Ok(unchanged == rows.len() - 1)
+}
+
+/// Runs a ROM for the given number of frames, feeding it its input script, and hashes what came out.
+fn run_rom(rom: &Path, frames: u64) -> Result<Hashes> {
+    let script_path = rom.with_extension("input");
+    let script = if script_path.exists() { read_script(&script_path)? } else { Vec::new() };
+    let mut cpu = testing::boot(rom)?;
+    let mut audio = Fnv::new();
+    let mut samples = Vec::new();
+    // Frames are counted in time rather than VBlanks, so ROMs that keep the LCD off still finish
+    for frame in 0..frames {
+        if let Some((_, held)) = script.iter().rfind(|(start, _)| *start <= frame) {
+            for button in Button::ALL {
+                cpu.set_button(button, held.contains(&button));
+            }
+        }
+        let end = (frame + 1) * DOTS_PER_FRAME;
+        while cpu.elapsed_dots() < end {
+            cpu.cycle()?;
+        }
+        samples.clear();
+        cpu.drain_samples_i16(&mut samples);
+        audio.write(samples.iter().flat_map(|sample| sample.to_le_bytes()));
+    }
+    let mut video = Fnv::new();
+    video.write(cpu.framebuffer().iter().flat_map(|pixel| pixel.to_le_bytes()));
+    Ok(Hashes { video: video.finish(), audio: audio.finish() })
+}
+
+/// Reads an input script into the frames where the held buttons change, in order.
+fn read_script(path: &Path) -> Result<Vec<(u64, Vec<Button>)>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut script: Vec<(u64, Vec<Button>)> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let mut words = strip_comment(line).split_whitespace();
+        let Some(frame) = words.next() else {
+            continue
+        };
+        let parse = || -> Result<(u64, Vec<Button>)> {
+            let frame: u64 = frame.parse().map_err(|_| anyhow!("{} isn't a frame number", frame))?;
+            if script.last().is_some_and(|(last, _)| *last >= frame) {
+                bail!("frame {} is out of order", frame);
+            }
+            Ok((frame, words.map(parse_button).collect::<Result<_>>()?))
+        };
+        script.push(parse().with_context(|| format!("{} line {}", path.display(), number + 1))?);
+    }
+    Ok(script)
+}
+
+fn parse_button(name: &str) -> Result<Button> {
+    Ok(match name.to_ascii_lowercase().as_str() {
+        "right" => Button::Right,
+        "left" => Button::Left,
+        "up" => Button::Up,
+        "down" => Button::Down,
+        "a" => Button::A,
+        "b" => Button::B,
+        "select" => Button::Select,
+        "start" => Button::Start,
+        _ => bail!("{} isn't a button", name),
+    })
+}
+
+fn read_manifest(path: &Path) -> Result<BTreeMap<String, Hashes>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut manifest = BTreeMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let words: Vec<&str> = strip_comment(line).split_whitespace().collect();
+        if words.is_empty() {
+            continue
+        }
+        let hash = |word: &str| u64::from_str_radix(word, 16).ok();
+        // The hashes are always the last two words, so ROM paths can have spaces in them
+        let (name, video, audio) = match &words[..] {
+            [name @ .., video, audio] if !name.is_empty() => (name.join(" "), video, audio),
+            _ => bail!("{} line {}: expected a ROM and two hashes", path.display(), number + 1),
+        };
+        let (Some(video), Some(audio)) = (hash(video), hash(audio)) else {
+            bail!("{} line {}: hashes should be in hex", path.display(), number + 1);
+        };
+        manifest.insert(name, Hashes { video, audio });
+    }
+    Ok(manifest)
+}
+
+fn write_manifest(path: &Path, hashes: &BTreeMap<String, Hashes>) -> Result<()> {
+    let mut text = String::from("# ROM, framebuffer hash, audio hash\n");
+    for (name, hashes) in hashes {
+        text += &format!("{} {:016x} {:016x}\n", name, hashes.video, hashes.audio);
+    }
+    fs::write(path, text).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn strip_comment(line: &str) -> &str {
+    line.split('#').next().unwrap_or("")
+}
+
+/// 64-bit FNV-1a, which is plenty to notice a change, and stays the same across Rust versions unlike `DefaultHasher`.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Self(0xCBF29CE484222325)
+    }
+
+    fn write(&mut self, bytes: impl IntoIterator<Item = u8>) {
+        for byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001B3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
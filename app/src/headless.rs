@@ -0,0 +1,130 @@
+//! Headless runs.
+//!
+//! Conditions for stopping a ROM running unattended, and dumping what it left behind once it stops:
+//! the screen, everything sent over serial, and a summary of the registers and memory.
+
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use gbcore::{CPU, DOTS_PER_FRAME, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use crate::screenshot;
+
+/// Why a run stopped.
+pub enum Stop {
+    Frames(u64),
+    Cycles(u64),
+    Halt,
+    JumpToSelf(u16),
+    Serial,
+    Closed,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stop::Frames(frames) => write!(f, "reached the limit of {} frames", frames),
+            Stop::Cycles(cycles) => write!(f, "reached the limit of {} M-cycles", cycles),
+            Stop::Halt => write!(f, "executed HALT"),
+            Stop::JumpToSelf(address) => write!(f, "jumped to itself at {:#06X}", address),
+            Stop::Serial => write!(f, "sent the expected serial output"),
+            Stop::Closed => write!(f, "closed by the user"),
+        }
+    }
+}
+
+pub struct StopConditions {
+    max_cycles: Option<u64>,
+    on_halt: bool,
+    on_jump_to_self: bool,
+    on_serial: Option<String>,
+    cycles: u64,
+    // Serial output already searched, so it's only searched again once there's more
+    serial_checked: usize,
+}
+
+impl StopConditions {
+    pub fn new(max_cycles: Option<u64>, on_halt: bool, on_jump_to_self: bool, on_serial: Option<String>) -> Self {
+        Self {
+            max_cycles,
+            on_halt,
+            on_jump_to_self,
+            on_serial,
+            cycles: 0,
+            serial_checked: 0,
+        }
+    }
+
+    /// M-cycles run so far.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Runs one instruction, returning why to stop if any of the conditions has been met.
+    pub fn step(&mut self, cpu: &mut CPU) -> Result<Option<Stop>> {
+        let address = cpu.program_counter();
+        let was_idle = cpu.halted() || cpu.stopped();
+        self.cycles += cpu.cycle()? as u64;
+
+        if let Some(max_cycles) = self.max_cycles
+            && self.cycles >= max_cycles {
+            return Ok(Some(Stop::Cycles(max_cycles)))
+        }
+        if self.on_halt && cpu.halted() {
+            return Ok(Some(Stop::Halt))
+        }
+        // The classic way for a test ROM to finish is `JR -2`, spinning on the spot forever
+        if self.on_jump_to_self && !was_idle && !cpu.halted() && !cpu.stopped() && cpu.program_counter() == address {
+            return Ok(Some(Stop::JumpToSelf(address)))
+        }
+        if let Some(expected) = &self.on_serial {
+            let output = cpu.serial_output();
+            if output.len() != self.serial_checked {
+                self.serial_checked = output.len();
+                if String::from_utf8_lossy(output).contains(expected.as_str()) {
+                    return Ok(Some(Stop::Serial))
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Writes the screen (`screen.png`), serial output (`serial.txt`), all 64 KiB of memory as the CPU sees it
+/// (`memory.bin`), and a readable summary (`summary.txt`) to a directory.
+pub fn dump(cpu: &CPU, dir: &Path, outcome: &str, cycles: u64) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let write = |name: &str, data: &[u8]| {
+        let path = dir.join(name);
+        fs::write(&path, data).with_context(|| format!("Failed to write {}", path.display()))
+    };
+
+    screenshot::save_png(&dir.join("screen.png"), cpu.framebuffer(), SCREEN_WIDTH, SCREEN_HEIGHT)?;
+    write("serial.txt", cpu.serial_output())?;
+    let memory: Vec<u8> = (0..=0xFFFF).map(|address| cpu.peek(address)).collect();
+    write("memory.bin", &memory)?;
+
+    let mut summary = String::new();
+    writeln!(summary, "Outcome:   {}", outcome)?;
+    writeln!(summary, "Ran for:   {} frames, {} M-cycles", cpu.elapsed_dots() / DOTS_PER_FRAME, cycles)?;
+    writeln!(summary, "Registers: {}", cpu)?;
+    if let Some(cartridge) = cpu.cartridge() {
+        writeln!(summary, "Cartridge: \"{}\", {:?}, ROM bank {}", cartridge.header().title, cartridge.mbc(), cartridge.rom_bank())?;
+    }
+    writeln!(summary, "\nStack:")?;
+    let stack = cpu.stack_pointer();
+    hex_dump(&mut summary, &memory, stack, stack.saturating_add(15))?;
+    writeln!(summary, "\nIO registers, HRAM, and IE:")?;
+    hex_dump(&mut summary, &memory, 0xFF00, 0xFFFF)?;
+    write("summary.txt", summary.as_bytes())?;
+    Ok(())
+}
+
+fn hex_dump(out: &mut String, memory: &[u8], start: u16, end: u16) -> fmt::Result {
+    for row in (start as usize..=end as usize).step_by(16) {
+        let bytes: Vec<String> = memory[row..(row + 16).min(end as usize + 1)].iter().map(|byte| format!("{:02X}", byte)).collect();
+        writeln!(out, "{:04X}: {}", row, bytes.join(" "))?;
+    }
+    Ok(())
+}
@@ -1,3 +1,416 @@
-fn main() {
-    println!("Hello, world!");
+//! Game Boy Emulator App.
+//!
+//! Command line frontend for gbcore.
+
+mod blargg;
+mod debugger;
+mod expression;
+mod gdb;
+mod gbs;
+mod golden;
+mod headless;
+mod mooneye;
+mod pacer;
+mod screenshot;
+mod screentest;
+mod terminal;
+mod trace;
+mod testing;
+mod window;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use gbcore::{AudioChannel, Cartridge, CartridgeHeader, Model, Symbols, CPU, DOTS_PER_FRAME};
+use headless::{Stop, StopConditions};
+
+/// What LY reads as for Gameboy Doctor.
+const DOCTOR_LY: u8 = 0x90;
+
+/// Exit code when emulation fails partway through. Bad arguments get clap's own code, 2.
+const EXIT_EMULATION: u8 = 1;
+/// Exit code when the ROM, boot ROM, or save can't be loaded.
+const EXIT_LOAD: u8 = 3;
+/// Exit code when a test suite ran fine, but not every test passed, or when traces differ.
+const EXIT_TESTS_FAILED: u8 = 4;
+
+#[derive(Parser)]
+#[command(version, about = "A Game Boy emulator", args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: Args,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Play a GBS music rip, rendering a track to WAV
+    Gbs(gbs::GbsArgs),
+    /// Run Blargg's test ROMs, checking their serial and memory output for a pass or fail
+    Blargg(blargg::BlarggArgs),
+    /// Run Mooneye's test ROMs, checking the registers when they hit their LD B,B breakpoint
+    Mooneye(mooneye::MooneyeArgs),
+    /// Run ROMs to a frame or LD B,B breakpoint and compare the screen with reference PNGs, like dmg-acid2 and cgb-acid2
+    ScreenTest(screentest::ScreenTestArgs),
+    /// Run ROMs for a fixed number of frames and check the screen and audio hashes against a manifest
+    Golden(golden::GoldenArgs),
+    /// Compare a trace log with a reference one, showing where they first differ
+    TraceCompare(trace::TraceCompareArgs),
+}
+
+#[derive(clap::Args)]
+struct Args {
+    /// ROM file to run
+    #[arg(required = true)]
+    rom: Option<PathBuf>,
+
+    /// Game Boy model to emulate, otherwise whatever the cartridge header asks for
+    #[arg(long, value_enum, default_value_t = ModelArg::Auto)]
+    model: ModelArg,
+
+    /// Boot ROM to run before the cartridge, otherwise start right at the cartridge's entry point
+    #[arg(long, value_name = "FILE")]
+    boot_rom: Option<PathBuf>,
+
+    /// Directory to keep battery saves in, otherwise next to the ROM
+    #[arg(long, value_name = "DIR")]
+    save_dir: Option<PathBuf>,
+
+    /// Run without a window
+    #[arg(long)]
+    headless: bool,
+
+    /// Play in the terminal instead of a window, drawing with colored half blocks
+    #[arg(long, conflicts_with = "headless")]
+    terminal: bool,
+
+    /// Start in the debugger, a command prompt for stepping through the ROM, instead of a window
+    #[arg(long, conflicts_with_all = ["headless", "terminal"])]
+    debug: bool,
+
+    /// Wait for GDB to connect on this localhost port and let it drive, instead of opening a window
+    #[arg(long, value_name = "PORT", conflicts_with_all = ["headless", "terminal", "debug"])]
+    gdb: Option<u16>,
+
+    /// Draw in the terminal with sixel graphics instead of half blocks
+    #[arg(long, requires = "terminal")]
+    sixel: bool,
+
+    /// Window or sixel image size, as a multiple of the Game Boy's 160x144 screen
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u8).range(1..=10), conflicts_with = "headless")]
+    scale: u8,
+
+    /// Stop after this many frames, otherwise run until something goes wrong
+    #[arg(long)]
+    frames: Option<u64>,
+
+    /// Stop after this many M-cycles
+    #[arg(long)]
+    cycles: Option<u64>,
+
+    /// Stop once the CPU executes HALT
+    #[arg(long)]
+    until_halt: bool,
+
+    /// Stop once the CPU jumps to the instruction it's already on, the usual way test ROMs finish
+    #[arg(long)]
+    until_loop: bool,
+
+    /// Stop once the serial output contains this text
+    #[arg(long, value_name = "TEXT")]
+    until_serial: Option<String>,
+
+    /// Dump the screen, serial output, and a register and memory summary to this directory when done
+    #[arg(long, value_name = "DIR")]
+    dump: Option<PathBuf>,
+
+    /// Record the audio output to a WAV file
+    #[arg(long, value_name = "WAV")]
+    record: Option<PathBuf>,
+
+    /// Also record each sound channel to its own WAV file, next to the main recording
+    #[arg(long, requires = "record")]
+    stems: bool,
+
+    /// Sample rate for audio output and recordings, in Hz
+    #[arg(long, default_value_t = gbcore::DEFAULT_SAMPLE_RATE)]
+    sample_rate: u32,
+
+    /// Mute a sound channel (1-4), can be given more than once
+    #[arg(long, value_name = "CHANNEL", value_parser = clap::value_parser!(u8).range(1..=4))]
+    mute: Vec<u8>,
+
+    /// Solo a sound channel (1-4), can be given more than once
+    #[arg(long, value_name = "CHANNEL", value_parser = clap::value_parser!(u8).range(1..=4))]
+    solo: Vec<u8>,
+
+    /// Log every sound register write to a VGM file. The log is kept by frame, so not in the debugger or GDB
+    #[arg(long, value_name = "VGM", conflicts_with_all = ["debug", "gdb"])]
+    vgm: Option<PathBuf>,
+
+    /// Frame to start the VGM log on
+    #[arg(long, value_name = "FRAME", default_value_t = 0, requires = "vgm")]
+    vgm_start: u64,
+
+    /// Frame to set the VGM loop point on
+    #[arg(long, value_name = "FRAME", requires = "vgm")]
+    vgm_loop: Option<u64>,
+
+    /// Frame to stop the VGM log on, otherwise it runs until the emulator stops
+    #[arg(long, value_name = "FRAME", requires = "vgm")]
+    vgm_stop: Option<u64>,
+
+    /// Export the sound channels' notes to a MIDI file
+    #[arg(long, value_name = "MID")]
+    midi: Option<PathBuf>,
+
+    /// RGBDS .sym or .map file with labels for the debugger and traces, otherwise the ROM's .sym if there is one
+    #[arg(long, value_name = "FILE")]
+    symbols: Option<PathBuf>,
+
+    /// Log the registers before every instruction to a file, in Gameboy Doctor's format,
+    /// with the label for PC at the end of each line if there are symbols
+    #[arg(long, value_name = "FILE")]
+    trace: Option<PathBuf>,
+
+    /// Make LY always read 0x90 and leave labels out while tracing, like Gameboy Doctor's reference logs expect
+    #[arg(long, requires = "trace")]
+    trace_doctor: bool,
+
+    /// Dump the oscilloscope capture of each sound channel to a CSV file when done
+    #[arg(long, value_name = "CSV")]
+    scope_csv: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ModelArg {
+    Auto,
+    Dmg,
+    Cgb,
+}
+
+impl ModelArg {
+    /// The model asked for, or None to go by the cartridge header.
+    fn model(self) -> Option<Model> {
+        match self {
+            ModelArg::Auto => None,
+            ModelArg::Dmg => Some(Model::Dmg),
+            ModelArg::Cgb => Some(Model::Cgb),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match &cli.command {
+        Some(Command::Gbs(args)) => gbs::run(args).map_err(|err| (EXIT_EMULATION, err)),
+        Some(Command::Blargg(args)) => suite_result(blargg::run(args)),
+        Some(Command::Mooneye(args)) => suite_result(mooneye::run(args)),
+        Some(Command::ScreenTest(args)) => suite_result(screentest::run(args)),
+        Some(Command::Golden(args)) => suite_result(golden::run(args)),
+        Some(Command::TraceCompare(args)) => match trace::compare(args) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err((EXIT_TESTS_FAILED, anyhow!("The traces differ"))),
+            Err(err) => Err((EXIT_EMULATION, err)),
+        },
+        None => run_rom(&cli.run),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err((code, err)) => {
+            eprintln!("Error: {:#}", err);
+            ExitCode::from(code)
+        },
+    }
+}
+
+/// Turns whether a test suite passed into the right exit code.
+fn suite_result(result: Result<bool>) -> Result<(), (u8, anyhow::Error)> {
+    match result {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((EXIT_TESTS_FAILED, anyhow!("Not every test passed"))),
+        Err(err) => Err((EXIT_EMULATION, err)),
+    }
+}
+
+/// Loads the ROM, boot ROM, and save, and gets the system ready to run.
+fn load(args: &Args) -> Result<(CPU, Option<PathBuf>)> {
+    let path = args.rom.as_ref().context("No ROM given")?;
+    let rom = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let checksum_valid = CartridgeHeader::checksum_valid(&rom);
+    let cartridge = Cartridge::load(rom).with_context(|| format!("Failed to load {}", path.display()))?;
+    if !checksum_valid {
+        eprintln!("Warning: header checksum doesn't match, a real Game Boy would refuse to boot this");
+    }
+    let model = args.model.model().unwrap_or_else(|| Model::preferred_by(cartridge.header()));
+    let boot_rom = match &args.boot_rom {
+        Some(path) => Some(std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?),
+        None => None,
+    };
+    let save_path = cartridge.has_battery().then(|| {
+        let dir = args.save_dir.clone().unwrap_or_else(|| path.parent().unwrap_or(Path::new("")).to_path_buf());
+        dir.join(path.with_extension("sav").file_name().unwrap_or_default())
+    });
+
+    let mut cpu = CPU::with_model(model);
+    cpu.load_cartridge(cartridge, boot_rom.as_deref())?;
+    if let Some(save_path) = &save_path
+        && let Some(cartridge) = cpu.cartridge_mut()
+        && save_path.exists() {
+        let save = std::fs::read(save_path).with_context(|| format!("Failed to read {}", save_path.display()))?;
+        cartridge.load_ram(&save).with_context(|| format!("Failed to load {}", save_path.display()))?;
+    }
+    let symbols_path = args.symbols.clone().or_else(|| Some(path.with_extension("sym")).filter(|path| path.exists()));
+    if let Some(symbols_path) = symbols_path {
+        cpu.set_symbols(Some(Symbols::load(&symbols_path)?));
+    }
+    Ok((cpu, save_path))
+}
+
+fn run_rom(args: &Args) -> Result<(), (u8, anyhow::Error)> {
+    let (mut cpu, save_path) = load(args).map_err(|err| (EXIT_LOAD, err))?;
+    testing::catch_panics(|| run_loaded(&mut cpu, args, save_path.as_deref())).map_err(|err| (EXIT_EMULATION, err))
+}
+
+fn run_loaded(cpu: &mut CPU, args: &Args, save_path: Option<&Path>) -> Result<()> {
+    cpu.set_sample_rate(args.sample_rate)?;
+    for channel in args.mute.iter().filter_map(|&n| AudioChannel::from_number(n)) {
+        cpu.set_channel_muted(channel, true);
+    }
+    for channel in args.solo.iter().filter_map(|&n| AudioChannel::from_number(n)) {
+        cpu.set_channel_soloed(channel, true);
+    }
+
+    if let Some(path) = &args.record {
+        cpu.start_recording(path, args.stems)?;
+    }
+    if let Some(path) = &args.midi {
+        cpu.start_midi_export(path)?;
+    }
+    if let Some(path) = &args.trace {
+        cpu.start_trace(path, !args.trace_doctor)?;
+        if args.trace_doctor {
+            cpu.set_ly_stub(Some(DOCTOR_LY));
+        }
+    }
+    let mut conditions = StopConditions::new(args.cycles, args.until_halt, args.until_loop, args.until_serial.clone());
+    // A panic still gets the captures finished and the save written below
+    let result = testing::catch_panics(|| if args.debug {
+        debugger::run(cpu).map(|_| Stop::Closed)
+    } else if let Some(port) = args.gdb {
+        gdb::serve(cpu, port).map(|_| Stop::Closed)
+    } else if args.headless {
+        run(cpu, args, &mut conditions, |_| Ok(true))
+    } else if args.terminal {
+        let sixel = args.sixel.then_some(args.scale as usize);
+        terminal::Terminal::open(sixel)
+            .and_then(|mut terminal| run(cpu, args, &mut conditions, |cpu| terminal.present(cpu)))
+    } else {
+        let title = match cpu.cartridge().map(|cartridge| cartridge.header().title.as_str()) {
+            Some(title) if !title.is_empty() => title.to_string(),
+            _ => "Game Boy".to_string(),
+        };
+        window::Window::open(&title, args.scale as usize)
+            .and_then(|mut window| run(cpu, args, &mut conditions, |cpu| window.present(cpu)))
+    });
+    let outcome = match &result {
+        Ok(stop) => format!("Stopped: {}", stop),
+        Err(err) => format!("Failed: {:#}", err),
+    };
+    if args.headless && result.is_ok() {
+        println!("{} ({})", outcome, cpu);
+    }
+    // Finish the captures and save even if emulation failed, so whatever was captured is still usable.
+    // Every step gets its turn, so one failing doesn't lose the rest
+    let mut cleanup_error = None;
+    let mut cleanup = |step: Result<()>| if let Err(err) = step {
+        cleanup_error.get_or_insert(err);
+    };
+    cleanup(cpu.stop_recording());
+    cleanup(cpu.stop_vgm_log());
+    cleanup(cpu.stop_midi_export());
+    cleanup(cpu.stop_trace());
+    if let Some(path) = &args.scope_csv {
+        cleanup(write_scope_csv(cpu, path));
+    }
+    if let Some(path) = save_path
+        && let Some(cartridge) = cpu.cartridge() {
+        cleanup(std::fs::write(path, cartridge.ram()).with_context(|| format!("Failed to write {}", path.display())));
+    }
+    if let Some(dir) = &args.dump {
+        cleanup(headless::dump(cpu, dir, &outcome, conditions.cycles()));
+    }
+    match (result, cleanup_error) {
+        (Err(err), Some(cleanup_err)) => {
+            eprintln!("Error: {:#}", cleanup_err);
+            Err(err)
+        },
+        (Err(err), None) | (Ok(_), Some(err)) => Err(err),
+        (Ok(_), None) => Ok(()),
+    }
+}
+
+/// Runs frame after frame, handing each one to `present`, until it returns false or a stop condition is met.
+fn run(
+    cpu: &mut CPU,
+    args: &Args,
+    conditions: &mut StopConditions,
+    mut present: impl FnMut(&mut CPU) -> Result<bool>,
+) -> Result<Stop> {
+    let mut frame = 0;
+    loop {
+        if let Some(frames) = args.frames
+            && frame >= frames {
+            return Ok(Stop::Frames(frames))
+        }
+        if let Some(path) = &args.vgm {
+            if frame == args.vgm_start {
+                cpu.start_vgm_log(path)?;
+            }
+            if args.vgm_loop == Some(frame) {
+                cpu.mark_vgm_loop();
+            }
+            if args.vgm_stop == Some(frame) {
+                cpu.stop_vgm_log()?;
+            }
+        }
+        if let Some(stop) = run_frame(cpu, conditions)? {
+            return Ok(stop)
+        }
+        if !present(cpu)? {
+            return Ok(Stop::Closed)
+        }
+        frame += 1;
+    }
+}
+
+/// Runs until the end of the current frame, or until a stop condition is met.
+fn run_frame(cpu: &mut CPU, conditions: &mut StopConditions) -> Result<Option<Stop>> {
+    let end = (cpu.elapsed_dots() / DOTS_PER_FRAME + 1) * DOTS_PER_FRAME;
+    while cpu.elapsed_dots() < end {
+        if let Some(stop) = conditions.step(cpu)? {
+            return Ok(Some(stop))
+        }
+    }
+    Ok(None)
+}
+
+/// Writes one row per oscilloscope point, with the time in seconds and each channel's output.
+fn write_scope_csv(cpu: &CPU, path: &Path) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut file = BufWriter::new(file);
+    let channels = AudioChannel::ALL.map(|channel| cpu.oscilloscope(channel));
+    writeln!(file, "time,ch1,ch2,ch3,ch4")?;
+    for i in 0..gbcore::SCOPE_LENGTH {
+        let time = i as f64 / gbcore::SCOPE_RATE as f64;
+        let points: Vec<String> = channels.iter().map(|points| points[i].to_string()).collect();
+        writeln!(file, "{:.6},{}", time, points.join(","))?;
+    }
+    file.flush()?;
+    Ok(())
 }
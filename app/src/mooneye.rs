@@ -0,0 +1,63 @@
+//! Mooneye subcommand.
+//!
+//! Runs Mooneye's test ROMs and reports which passed. Each one finishes by executing `LD B,B`
+//! as a breakpoint, with the Fibonacci numbers 3, 5, 8, 13, 21, 34 in B, C, D, E, H, L if it passed,
+//! or 0x42 in all of them if it failed.
+
+use std::path::PathBuf;
+use anyhow::Result;
+use clap::Args;
+use gbcore::{Reg8, CPU, DOTS_PER_SECOND};
+
+use crate::testing::{self, Outcome};
+use crate::ModelArg;
+
+const REGISTERS: [Reg8; 6] = [Reg8::B, Reg8::C, Reg8::D, Reg8::E, Reg8::H, Reg8::L];
+const PASSED: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const FAILED: [u8; 6] = [0x42; 6];
+
+#[derive(Args)]
+pub struct MooneyeArgs {
+    /// Test ROMs to run, or directories to search for them
+    #[arg(required = true)]
+    roms: Vec<PathBuf>,
+
+    /// Give up on a ROM after this many seconds of emulated time
+    #[arg(long, default_value_t = 20)]
+    timeout: u64,
+
+    /// Game Boy model to run the tests on, otherwise whatever each cartridge header asks for
+    #[arg(long, value_enum, default_value_t = ModelArg::Auto)]
+    model: ModelArg,
+}
+
+/// Runs every ROM and prints a table of results. Returns true if they all passed.
+pub fn run(args: &MooneyeArgs) -> Result<bool> {
+    testing::run_suite(&args.roms, |rom, dots| {
+        let mut cpu = testing::boot_as(rom, args.model.model())?;
+        let outcome = run_test(&mut cpu, args.timeout);
+        *dots = cpu.elapsed_dots();
+        outcome
+    })
+}
+
+fn run_test(cpu: &mut CPU, timeout: u64) -> Result<Outcome> {
+    let end = timeout * DOTS_PER_SECOND;
+    while cpu.elapsed_dots() < end {
+        if testing::at_breakpoint(cpu) {
+            let registers = REGISTERS.map(|register| cpu.reg8(register));
+            return Ok(match registers {
+                PASSED => Outcome::Passed,
+                FAILED => Outcome::Failed(String::new()),
+                registers => {
+                    let registers: Vec<String> = REGISTERS.iter().zip(registers)
+                        .map(|(register, value)| format!("{:?}={:02X}", register, value))
+                        .collect();
+                    Outcome::Failed(format!("unexpected registers {}", registers.join(" ")))
+                },
+            })
+        }
+        cpu.cycle()?;
+    }
+    Ok(Outcome::TimedOut)
+}
@@ -0,0 +1,44 @@
+//! Frame pacing.
+//!
+//! Keeps the frontends running at the Game Boy's refresh rate of about 59.73 Hz,
+//! by sleeping until each frame is due rather than a fixed time per frame, so any lag doesn't add up.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gbcore::{DOTS_PER_FRAME, DOTS_PER_SECOND};
+
+// Falling further behind than this gives up on catching up, rather than running flat out for a while
+const MAX_LAG_FRAMES: u32 = 4;
+
+pub struct FramePacer {
+    frame_time: Duration,
+    next_frame: Instant,
+}
+
+impl Default for FramePacer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FramePacer {
+    pub fn new() -> Self {
+        let frame_time = Duration::from_secs_f64(DOTS_PER_FRAME as f64 / DOTS_PER_SECOND as f64);
+        Self {
+            frame_time,
+            next_frame: Instant::now() + frame_time,
+        }
+    }
+
+    /// Sleeps until the next frame is due.
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        if self.next_frame > now {
+            thread::sleep(self.next_frame - now);
+        } else if now - self.next_frame > self.frame_time * MAX_LAG_FRAMES {
+            self.next_frame = now;
+        }
+        self.next_frame += self.frame_time;
+    }
+}
@@ -0,0 +1,40 @@
+//! Screenshots.
+//!
+//! Saving framebuffers (0x00RRGGBB pixels, row by row) as PNG files, and reading them back.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use anyhow::{Context, Result};
+
+pub fn save_png(path: &Path, pixels: &[u32], width: usize, height: usize) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let data: Vec<u8> = pixels.iter().flat_map(|&pixel| [(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]).collect();
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Reads a PNG back as 0x00RRGGBB pixels, along with its width and height. Any alpha is dropped.
+pub fn load_png(path: &Path) -> Result<(Vec<u32>, usize, usize)> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    // Palettes and low bit depths come out as plain 8-bit channels
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().with_context(|| format!("Failed to decode {}", path.display()))?;
+    let mut data = vec![0; reader.output_buffer_size().context("PNG is too large")?];
+    let info = reader.next_frame(&mut data).with_context(|| format!("Failed to decode {}", path.display()))?;
+    let channels = info.color_type.samples();
+    let pixels = data[..info.buffer_size()].chunks(channels).map(|pixel| {
+        let [r, g, b] = match info.color_type {
+            png::ColorType::Grayscale | png::ColorType::GrayscaleAlpha => [pixel[0]; 3],
+            _ => [pixel[0], pixel[1], pixel[2]],
+        };
+        (r as u32) << 16 | (g as u32) << 8 | b as u32
+    }).collect();
+    Ok((pixels, info.width as usize, info.height as usize))
+}
@@ -0,0 +1,138 @@
+//! Screen test subcommand.
+//!
+//! Runs ROMs until a given frame, or until they hit an `LD B,B` breakpoint, and compares the screen pixel by pixel
+//! with a reference PNG. Anything that doesn't match gets a diff image, with the wrong pixels in red,
+//! and a screenshot of what was actually drawn.
+//!
+//! A ROM's reference is the PNG next to it with the same name. The acid2 tests are also found with the names
+//! their releases use, so dmg-acid2 and cgb-acid2 can be run straight from a download.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use gbcore::{CPU, DOTS_PER_SECOND, SCREEN_HEIGHT, SCREEN_WIDTH, SHADES};
+
+use crate::screenshot;
+use crate::testing::{self, Outcome};
+use crate::ModelArg;
+
+// Reference names the acid2 releases use, looked for next to the ROM and in an img directory beside it
+const KNOWN_REFERENCES: [(&str, &str); 2] = [
+    ("dmg-acid2", "reference-dmg.png"),
+    ("cgb-acid2", "reference.png"),
+];
+
+// How references usually draw the four DMG shades, lightest to darkest
+const GRAYS: [u32; 4] = [0x00FFFFFF, 0x00AAAAAA, 0x00555555, 0x00000000];
+
+const DIFF_COLOR: u32 = 0x00FF0000;
+
+#[derive(Args)]
+pub struct ScreenTestArgs {
+    /// Test ROMs to run, or directories to search for them
+    #[arg(required = true)]
+    roms: Vec<PathBuf>,
+
+    /// Compare the screen once this many frames have been drawn, instead of at the LD B,B breakpoint
+    #[arg(long)]
+    frames: Option<u64>,
+
+    /// Give up on a ROM after this many seconds of emulated time
+    #[arg(long, default_value_t = 20)]
+    timeout: u64,
+
+    /// Game Boy model to run the tests on, otherwise whatever each cartridge header asks for
+    #[arg(long, value_enum, default_value_t = ModelArg::Auto)]
+    model: ModelArg,
+
+    /// Directory to write diff images and screenshots of failed tests to
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    diff_dir: PathBuf,
+}
+
+/// Runs every ROM and prints a table of results. Returns true if they all matched their references.
+pub fn run(args: &ScreenTestArgs) -> Result<bool> {
+    testing::run_suite(&args.roms, |rom, dots| {
+        let reference = find_reference(rom)?;
+        let mut cpu = testing::boot_as(rom, args.model.model())?;
+        let finished = run_test(&mut cpu, args);
+        *dots = cpu.elapsed_dots();
+        if !finished? {
+            return Ok(Outcome::TimedOut)
+        }
+        compare(cpu.framebuffer(), &reference, rom, &args.diff_dir)
+    })
+}
+
+/// Runs until the screen is ready to compare. Returns false if the timeout came first.
+fn run_test(cpu: &mut CPU, args: &ScreenTestArgs) -> Result<bool> {
+    let end = args.timeout * DOTS_PER_SECOND;
+    while cpu.elapsed_dots() < end {
+        let done = match args.frames {
+            Some(frames) => cpu.frame_count() >= frames,
+            None => testing::at_breakpoint(cpu),
+        };
+        if done {
+            return Ok(true)
+        }
+        cpu.cycle()?;
+    }
+    Ok(false)
+}
+
+fn find_reference(rom: &Path) -> Result<PathBuf> {
+    let mut candidates = vec![rom.with_extension("png")];
+    let dir = rom.parent().unwrap_or(Path::new(""));
+    let stem = rom.file_stem().unwrap_or_default();
+    for (name, reference) in KNOWN_REFERENCES {
+        if stem == name {
+            candidates.push(dir.join(reference));
+            candidates.push(dir.join("img").join(reference));
+        }
+    }
+    candidates.into_iter().find(|path| path.is_file())
+        .with_context(|| format!("No reference image, expected {}", rom.with_extension("png").display()))
+}
+
+/// Compares a frame with its reference, writing the diff and a screenshot when they don't match.
+fn compare(frame: &[u32], reference: &Path, rom: &Path, diff_dir: &Path) -> Result<Outcome> {
+    let (mut expected, width, height) = screenshot::load_png(reference)?;
+    if (width, height) != (SCREEN_WIDTH, SCREEN_HEIGHT) {
+        bail!("{} is {}x{}, not {}x{}", reference.display(), width, height, SCREEN_WIDTH, SCREEN_HEIGHT);
+    }
+    // A grayscale reference for a DMG frame is in the four DMG shades, so compare it in the ones drawn here.
+    // CGB frames are drawn in their real colors
+    let dmg_frame = frame.iter().all(|pixel| SHADES.contains(pixel));
+    if dmg_frame && expected.iter().all(|pixel| GRAYS.contains(pixel)) {
+        for pixel in &mut expected {
+            *pixel = SHADES[GRAYS.iter().position(|gray| gray == pixel).unwrap_or(0)];
+        }
+    }
+
+    let wrong: Vec<usize> = (0..frame.len()).filter(|&i| frame[i] != expected[i]).collect();
+    let Some(&first) = wrong.first() else {
+        return Ok(Outcome::Passed)
+    };
+
+    let diff: Vec<u32> = expected.iter().zip(frame)
+        .map(|(&expected, &actual)| if expected == actual { fade(expected) } else { DIFF_COLOR })
+        .collect();
+    fs::create_dir_all(diff_dir).with_context(|| format!("Failed to create {}", diff_dir.display()))?;
+    let stem = rom.file_stem().unwrap_or_default().to_string_lossy();
+    let diff_path = diff_dir.join(format!("{}-diff.png", stem));
+    screenshot::save_png(&diff_path, &diff, SCREEN_WIDTH, SCREEN_HEIGHT)?;
+    screenshot::save_png(&diff_dir.join(format!("{}-actual.png", stem)), frame, SCREEN_WIDTH, SCREEN_HEIGHT)?;
+    Ok(Outcome::Failed(format!(
+        "{} pixels differ, first at ({}, {}), see {}",
+        wrong.len(), first % SCREEN_WIDTH, first / SCREEN_WIDTH, diff_path.display(),
+    )))
+}
+
+/// Washes a color out most of the way to white, so the red in a diff stands out.
+fn fade(color: u32) -> u32 {
+    [16, 8, 0].iter().fold(0, |faded, shift| {
+        let channel = color >> shift & 0xFF;
+        faded | (0xFF - (0xFF - channel) / 4) << shift
+    })
+}
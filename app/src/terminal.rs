@@ -0,0 +1,279 @@
+//! Terminal frontend.
+//!
+//! Draws the screen right in the terminal, for playing over SSH. Each character cell is an upper half block
+//! with 24-bit colors, the top pixel in front and the bottom pixel behind, so 160x144 fits in 160x72 cells.
+//! Only cells that changed since the last frame get redrawn. Terminals that speak sixel can get real pixels instead.
+//!
+//! Keys come in through raw-mode stdin. Most terminals never say when a key is let go,
+//! so unless the terminal supports reporting releases, each press holds the button down for a moment.
+
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Stdout, Write};
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use crossterm::{cursor, event, execute, queue, style, terminal};
+use crossterm::event::{Event, KeyCode, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags};
+use gbcore::{Button, CPU, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use crate::pacer::FramePacer;
+
+// How long a press holds a button down for, when the terminal can't report releases
+const HOLD_TIME: Duration = Duration::from_millis(150);
+
+const HALF_BLOCK: char = '▀';
+const MAX_SIXEL_COLORS: usize = 256;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Held {
+    Up,
+    UntilRelease,
+    Until(Instant),
+}
+
+pub struct Terminal {
+    out: BufWriter<Stdout>,
+    // Colors on screen for each cell, top and bottom, or None where it needs drawing regardless
+    cells: Vec<Option<(u32, u32)>>,
+    // Sixel output at this scale, instead of half blocks
+    sixel: Option<usize>,
+    last_frame: Vec<u32>,
+    enhanced_keys: bool,
+    buttons: [Held; 8],
+    pacer: FramePacer,
+}
+
+impl Terminal {
+    /// Takes over the terminal until dropped. With `sixel`, frames are drawn as sixel images at that scale.
+    pub fn open(sixel: Option<usize>) -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        // Built straight away, so dropping it puts the terminal back even if the rest of the setup fails
+        let mut this = Self {
+            out: BufWriter::new(io::stdout()),
+            cells: vec![None; SCREEN_WIDTH * SCREEN_HEIGHT / 2],
+            sixel,
+            last_frame: Vec::new(),
+            enhanced_keys: false,
+            buttons: [Held::Up; 8],
+            pacer: FramePacer::new(),
+        };
+        execute!(this.out, terminal::EnterAlternateScreen, cursor::Hide, terminal::Clear(terminal::ClearType::All))?;
+        if terminal::supports_keyboard_enhancement().unwrap_or(false) {
+            execute!(this.out, event::PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+            this.enhanced_keys = true;
+        }
+        Ok(this)
+    }
+
+    /// Draws the latest frame, passes key presses on to the joypad, and waits until the next frame is due.
+    /// Returns false once Escape, Q, or Ctrl+C is pressed.
+    pub fn present(&mut self, cpu: &mut CPU) -> Result<bool> {
+        if !self.poll_keys(cpu)? {
+            return Ok(false)
+        }
+        match self.sixel {
+            Some(scale) => self.draw_sixel(cpu.framebuffer(), scale)?,
+            None => self.draw_blocks(cpu.framebuffer())?,
+        }
+        self.pacer.wait();
+        Ok(true)
+    }
+
+    fn poll_keys(&mut self, cpu: &mut CPU) -> Result<bool> {
+        let now = Instant::now();
+        while event::poll(Duration::ZERO)? {
+            let key = match event::read()? {
+                Event::Key(key) => key,
+                Event::Resize(..) => {
+                    // Whatever was on screen is gone, so start over
+                    self.cells.fill(None);
+                    self.last_frame.clear();
+                    queue!(self.out, terminal::Clear(terminal::ClearType::All))?;
+                    continue
+                },
+                _ => continue,
+            };
+            let quit = match key.code {
+                KeyCode::Esc | KeyCode::Char('q') => true,
+                KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+                _ => false,
+            };
+            if quit {
+                return Ok(false)
+            }
+            let Some(button) = button_for(key.code) else {
+                continue
+            };
+            self.buttons[button as usize] = match key.kind {
+                KeyEventKind::Release => Held::Up,
+                _ if self.enhanced_keys => Held::UntilRelease,
+                _ => Held::Until(now + HOLD_TIME),
+            };
+        }
+        for button in Button::ALL {
+            let held = &mut self.buttons[button as usize];
+            if matches!(*held, Held::Until(release) if release <= now) {
+                *held = Held::Up;
+            }
+            cpu.set_button(button, *held != Held::Up);
+        }
+        Ok(true)
+    }
+
+    fn draw_blocks(&mut self, framebuffer: &[u32]) -> Result<()> {
+        let (columns, rows) = terminal::size()?;
+        let width = SCREEN_WIDTH.min(columns as usize);
+        let height = (SCREEN_HEIGHT / 2).min(rows as usize);
+        // Colors last set, since neighbouring cells usually share them
+        let mut colors = None;
+        for row in 0..height {
+            // Moving the cursor is only needed when skipping over cells that haven't changed
+            let mut cursor_at = None;
+            for column in 0..width {
+                let top = framebuffer[row * 2 * SCREEN_WIDTH + column];
+                let bottom = framebuffer[(row * 2 + 1) * SCREEN_WIDTH + column];
+                let cell = &mut self.cells[row * SCREEN_WIDTH + column];
+                if *cell == Some((top, bottom)) {
+                    continue
+                }
+                *cell = Some((top, bottom));
+                if cursor_at != Some(column) {
+                    queue!(self.out, cursor::MoveTo(column as u16, row as u16))?;
+                }
+                if colors != Some((top, bottom)) {
+                    queue!(self.out, style::SetColors(style::Colors::new(rgb(top), rgb(bottom))))?;
+                    colors = Some((top, bottom));
+                }
+                queue!(self.out, style::Print(HALF_BLOCK))?;
+                cursor_at = Some(column + 1);
+            }
+        }
+        if colors.is_some() {
+            queue!(self.out, style::ResetColor)?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn draw_sixel(&mut self, framebuffer: &[u32], scale: usize) -> Result<()> {
+        // A sixel image can only be drawn whole, so skip frames that didn't change at all
+        if self.last_frame == framebuffer {
+            return Ok(())
+        }
+        self.last_frame = framebuffer.to_vec();
+        queue!(self.out, cursor::MoveTo(0, 0))?;
+        self.out.write_all(&encode_sixel(framebuffer, scale))?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        // Nothing to be done if this fails, and the terminal is the only place to complain anyway
+        if self.enhanced_keys {
+            let _ = execute!(self.out, event::PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(self.out, style::ResetColor, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn button_for(code: KeyCode) -> Option<Button> {
+    match code {
+        KeyCode::Right => Some(Button::Right),
+        KeyCode::Left => Some(Button::Left),
+        KeyCode::Up => Some(Button::Up),
+        KeyCode::Down => Some(Button::Down),
+        KeyCode::Char('x') => Some(Button::A),
+        KeyCode::Char('z') => Some(Button::B),
+        KeyCode::Backspace => Some(Button::Select),
+        KeyCode::Enter => Some(Button::Start),
+        _ => None,
+    }
+}
+
+fn rgb(color: u32) -> style::Color {
+    style::Color::Rgb {
+        r: (color >> 16) as u8,
+        g: (color >> 8) as u8,
+        b: color as u8,
+    }
+}
+
+/// Encodes a frame as a sixel image, each pixel blown up to a `scale` by `scale` square.
+fn encode_sixel(framebuffer: &[u32], scale: usize) -> Vec<u8> {
+    let width = SCREEN_WIDTH * scale;
+    let height = SCREEN_HEIGHT * scale;
+
+    // Sixel color registers, in order of first appearance
+    let mut registers: HashMap<u32, usize> = HashMap::new();
+    let mut colors = Vec::new();
+    let indices: Vec<usize> = framebuffer.iter().map(|&color| {
+        if let Some(&index) = registers.get(&color) {
+            return index
+        }
+        let index = if colors.len() < MAX_SIXEL_COLORS {
+            colors.push(color);
+            colors.len() - 1
+        } else {
+            nearest_color(&colors, color)
+        };
+        registers.insert(color, index);
+        index
+    }).collect();
+
+    let mut data = Vec::new();
+    // Square pixels, and a fixed size so the terminal doesn't need to work it out
+    data.extend(format!("\x1bP0;1;0q\"1;1;{};{}", width, height).bytes());
+    for (index, color) in colors.iter().enumerate() {
+        let channel = |shift: u32| (color >> shift & 0xFF) * 100 / 255;
+        data.extend(format!("#{};2;{};{};{}", index, channel(16), channel(8), channel(0)).bytes());
+    }
+
+    let pixel = |x: usize, y: usize| indices[(y / scale) * SCREEN_WIDTH + x / scale];
+    for band in (0..height).step_by(6) {
+        let rows = (height - band).min(6);
+        // One pass over the band per color in it, each going back to the start of the band with `$`
+        let mut band_colors: Vec<usize> = (band..band + rows)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| pixel(x, y))
+            .collect();
+        band_colors.sort_unstable();
+        band_colors.dedup();
+        for (pass, &color) in band_colors.iter().enumerate() {
+            if pass > 0 {
+                data.push(b'$');
+            }
+            data.extend(format!("#{}", color).bytes());
+            let column = |x: usize| {
+                let bits = (0..rows).filter(|&row| pixel(x, band + row) == color).fold(0, |bits, row| bits | 1 << row);
+                b'?' + bits as u8
+            };
+            let mut x = 0;
+            while x < width {
+                let sixel = column(x);
+                let run = (x..width).take_while(|&x| column(x) == sixel).count();
+                if run > 3 {
+                    data.extend(format!("!{}", run).bytes());
+                    data.push(sixel);
+                } else {
+                    data.extend(std::iter::repeat_n(sixel, run));
+                }
+                x += run;
+            }
+        }
+        data.push(b'-');
+    }
+    data.extend(b"\x1b\\");
+    data
+}
+
+fn nearest_color(colors: &[u32], color: u32) -> usize {
+    let distance = |other: u32| {
+        [16, 8, 0].iter().map(|shift| {
+            let difference = (color >> shift & 0xFF) as i32 - (other >> shift & 0xFF) as i32;
+            difference * difference
+        }).sum::<i32>()
+    };
+    (0..colors.len()).min_by_key(|&index| distance(colors[index])).unwrap_or(0)
+}
@@ -0,0 +1,144 @@
+//! Test ROM runners.
+//!
+//! The bits shared between the test suite subcommands: finding ROMs, booting them,
+//! surviving emulator panics, and printing results as a table.
+
+use std::any::Any;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context, Result};
+use gbcore::{Cartridge, Model, CPU, DOTS_PER_SECOND};
+
+/// How a test ROM did, with details for the results table.
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    TimedOut,
+    Error(String),
+}
+
+/// Runs every ROM found under `paths` through `test`, and prints a table of results. Returns true if they all passed.
+/// `test` sets `dots` to how long the ROM ran for, so the table can show it even if the test goes wrong.
+pub fn run_suite(paths: &[PathBuf], mut test: impl FnMut(&Path, &mut u64) -> Result<Outcome>) -> Result<bool> {
+    let roms = find_roms(paths)?;
+    let mut rows = vec![vec!["ROM".to_string(), "Result".to_string(), "Time".to_string(), "Details".to_string()]];
+    let mut passed = 0;
+    for rom in &roms {
+        let mut dots = 0;
+        let outcome = catch_panics(|| test(rom, &mut dots)).unwrap_or_else(|err| Outcome::Error(format!("{:#}", err)));
+        let (result, details) = match outcome {
+            Outcome::Passed => ("passed", String::new()),
+            Outcome::Failed(details) => ("FAILED", details),
+            Outcome::TimedOut => ("TIMEOUT", String::new()),
+            Outcome::Error(details) => ("ERROR", details),
+        };
+        if result == "passed" {
+            passed += 1;
+        }
+        let seconds = dots as f64 / DOTS_PER_SECOND as f64;
+        rows.push(vec![display_name(rom, paths), result.to_string(), format!("{:.1}s", seconds), details]);
+    }
+    print_table(&rows);
+    println!("\n{} of {} passed", passed, roms.len());
+    Ok(passed == roms.len())
+}
+
+/// Expands directories into the ROMs found anywhere inside them, sorted by path. Files are kept as they are.
+pub fn find_roms(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut roms = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut found = Vec::new();
+            walk(path, &mut found)?;
+            found.sort();
+            roms.extend(found);
+        } else {
+            roms.push(path.clone());
+        }
+    }
+    Ok(roms)
+}
+
+fn walk(dir: &Path, roms: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(&path, roms)?;
+        } else if path.extension().is_some_and(|ext| ext == "gb" || ext == "gbc") {
+            roms.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Loads a ROM and starts it the way the boot ROM would leave it, on whichever model the header asks for.
+pub fn boot(path: &Path) -> Result<CPU> {
+    boot_as(path, None)
+}
+
+/// Same as [`boot`], but with the model picked by the caller when given.
+pub fn boot_as(path: &Path, model: Option<Model>) -> Result<CPU> {
+    let rom = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let cartridge = Cartridge::load(rom).with_context(|| format!("Failed to load {}", path.display()))?;
+    let model = model.unwrap_or_else(|| Model::preferred_by(cartridge.header()));
+    let mut cpu = CPU::with_model(model);
+    cpu.load_cartridge(cartridge, None)?;
+    Ok(cpu)
+}
+
+/// Opcode of `LD B,B`, which does nothing, so Mooneye's tests and others use it as a breakpoint.
+const LD_B_B: u8 = 0x40;
+
+/// Whether the CPU is about to execute an `LD B,B` breakpoint.
+pub fn at_breakpoint(cpu: &CPU) -> bool {
+    !cpu.halted() && cpu.peek(cpu.program_counter()) == LD_B_B
+}
+
+/// Runs something that might panic inside the emulator (an unimplemented instruction, say),
+/// turning the panic into an error so one bad ROM doesn't take down a whole suite.
+pub fn catch_panics<T>(run: impl FnOnce() -> Result<T>) -> Result<T> {
+    // The default hook would print the panic on top of the results, and it's reported with them anyway
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = panic::catch_unwind(AssertUnwindSafe(run));
+    panic::set_hook(hook);
+    result.unwrap_or_else(|payload| Err(anyhow!("Emulator panicked: {}", panic_message(&payload))))
+}
+
+fn panic_message(payload: &Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown cause".to_string()
+    }
+}
+
+/// The name to show for a ROM in results: its path relative to whichever of `roots` it was found in.
+pub fn display_name(rom: &Path, roots: &[PathBuf]) -> String {
+    roots.iter()
+        .filter_map(|root| rom.strip_prefix(root).ok())
+        .find(|relative| !relative.as_os_str().is_empty())
+        .unwrap_or(rom)
+        .display()
+        .to_string()
+}
+
+/// Prints rows lined up in columns, with the first row as a header.
+pub fn print_table(rows: &[Vec<String>]) {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| rows.iter().filter_map(|row| row.get(column)).map(|cell| cell.chars().count()).max().unwrap_or(0))
+        .collect();
+    for (index, row) in rows.iter().enumerate() {
+        let cells: Vec<String> = row.iter().zip(&widths).map(|(cell, &width)| format!("{:width$}", cell)).collect();
+        println!("{}", cells.join("  ").trim_end());
+        if index == 0 {
+            let rule: Vec<String> = widths.iter().map(|&width| "-".repeat(width)).collect();
+            println!("{}", rule.join("  "));
+        }
+    }
+}
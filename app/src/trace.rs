@@ -0,0 +1,103 @@
+//! Trace compare subcommand.
+//!
+//! Reads a trace log from `--trace` alongside a reference one, say from Gameboy Doctor, a line at a time,
+//! and reports the first line where they differ, along with the lines leading up to it and which registers are off.
+//! One log ending before the other counts as a difference too.
+//! Both are streamed, so logs of millions of instructions are fine. `;` comments, like the labels
+//! traces get with symbols loaded, are left out of the comparison.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Lines};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use clap::Args;
+
+// Stands in for the missing line when one log ends first
+const END: &str = "(end of log)";
+
+#[derive(Args)]
+pub struct TraceCompareArgs {
+    /// Trace log from this emulator
+    ours: PathBuf,
+
+    /// Trace log known to be right
+    reference: PathBuf,
+
+    /// Lines to show from before the first difference
+    #[arg(long, default_value_t = 5)]
+    context: usize,
+}
+
+/// Compares the logs and prints where they differ. Returns true if they match line for line, all the way to the end.
+pub fn compare(args: &TraceCompareArgs) -> Result<bool> {
+    let mut ours = open(&args.ours)?;
+    let mut reference = open(&args.reference)?;
+    let mut before = VecDeque::with_capacity(args.context);
+    let mut number = 0;
+    loop {
+        number += 1;
+        let (line, expected) = (next_line(&mut ours, &args.ours)?, next_line(&mut reference, &args.reference)?);
+        if line.is_none() && expected.is_none() {
+            println!("Traces match, {} lines each", number - 1);
+            return Ok(true)
+        }
+        if line == expected {
+            let line = line.unwrap_or_default();
+            if before.len() == args.context {
+                before.pop_front();
+            }
+            if args.context > 0 {
+                before.push_back(line);
+            }
+            continue
+        }
+
+        println!("Traces differ at line {}\n", number);
+        let width = number.to_string().len();
+        for (offset, line) in before.iter().enumerate() {
+            println!("           {:>width$}  {}", number - before.len() + offset, line);
+        }
+        println!("ours       {:>width$}  {}", number, line.as_deref().unwrap_or(END));
+        println!("reference  {:>width$}  {}", number, expected.as_deref().unwrap_or(END));
+        if let (Some(line), Some(expected)) = (line, expected) {
+            let fields = differing_fields(&line, &expected);
+            if !fields.is_empty() {
+                println!("\nDiffers in {}", fields.join(", "));
+            }
+        }
+        return Ok(false)
+    }
+}
+
+fn open(path: &Path) -> Result<Lines<BufReader<File>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    Ok(BufReader::new(file).lines())
+}
+
+/// The next line, without any comment, trailing whitespace, or carriage return.
+fn next_line(lines: &mut Lines<BufReader<File>>, path: &Path) -> Result<Option<String>> {
+    match lines.next() {
+        Some(line) => {
+            let line = line.with_context(|| format!("Failed to read {}", path.display()))?;
+            let line = line.split(';').next().unwrap_or_default();
+            Ok(Some(line.trim_end().to_string()))
+        },
+        None => Ok(None),
+    }
+}
+
+/// Names of the `NAME:VALUE` fields that don't match between two lines.
+fn differing_fields(line: &str, expected: &str) -> Vec<String> {
+    let fields = |line: &str| -> Vec<(String, String)> {
+        line.split_whitespace()
+            .filter_map(|field| field.split_once(':'))
+            .map(|(name, value)| (name.to_string(), value.to_ascii_uppercase()))
+            .collect()
+    };
+    let ours = fields(line);
+    fields(expected).into_iter()
+        .filter(|(name, value)| !ours.iter().any(|(other, ours)| other == name && ours == value))
+        .map(|(name, _)| name)
+        .collect()
+}
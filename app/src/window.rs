@@ -0,0 +1,95 @@
+//! Window frontend.
+//!
+//! Shows the screen in a desktop window, scaled up by a whole number with plain pixel copies so no GPU is needed,
+//! and turns the keyboard into joypad presses.
+
+use anyhow::{anyhow, Result};
+use gbcore::{Button, CPU, SCREEN_HEIGHT, SCREEN_WIDTH};
+use minifb::{Key, WindowOptions};
+
+use crate::pacer::FramePacer;
+
+const KEYMAP: [(Key, Button); 8] = [
+    (Key::Right, Button::Right),
+    (Key::Left, Button::Left),
+    (Key::Up, Button::Up),
+    (Key::Down, Button::Down),
+    (Key::X, Button::A),
+    (Key::Z, Button::B),
+    (Key::Backspace, Button::Select),
+    (Key::Enter, Button::Start),
+];
+
+pub struct Window {
+    window: minifb::Window,
+    scale: usize,
+    buffer: Vec<u32>,
+    pacer: FramePacer,
+}
+
+impl Window {
+    pub fn open(title: &str, scale: usize) -> Result<Self> {
+        let width = SCREEN_WIDTH * scale;
+        let height = SCREEN_HEIGHT * scale;
+        let mut window = minifb::Window::new(title, width, height, WindowOptions::default())
+            .map_err(|err| anyhow!("Failed to open a window: {}", err))?;
+        // Pacing is done here instead, since minifb only takes a whole number of frames per second
+        window.set_target_fps(0);
+        Ok(Self {
+            window,
+            scale,
+            buffer: vec![0; width * height],
+            pacer: FramePacer::new(),
+        })
+    }
+
+    /// Shows the latest frame, passes the keyboard on to the joypad, and waits until the next frame is due.
+    /// Returns false once the window has been closed, or Escape pressed.
+    pub fn present(&mut self, cpu: &mut CPU) -> Result<bool> {
+        let width = SCREEN_WIDTH * self.scale;
+        for (y, row) in cpu.framebuffer().chunks(SCREEN_WIDTH).enumerate() {
+            let start = y * self.scale * width;
+            for (x, &pixel) in row.iter().enumerate() {
+                self.buffer[start + x * self.scale..start + (x + 1) * self.scale].fill(pixel);
+            }
+            // Every other line of the block is a copy of the first
+            for line in 1..self.scale {
+                self.buffer.copy_within(start..start + width, start + line * width);
+            }
+        }
+        self.window.update_with_buffer(&self.buffer, width, SCREEN_HEIGHT * self.scale)?;
+
+        if !self.window.is_open() || self.window.is_key_down(Key::Escape) {
+            return Ok(false)
+        }
+        for (key, button) in KEYMAP {
+            cpu.set_button(button, self.window.is_key_down(key));
+        }
+        self.pacer.wait();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Needs a display, so it's left out of normal runs. Without one, use a virtual X server:
+    // xvfb-run cargo test -p app -- --ignored window
+    #[test]
+    #[ignore]
+    fn window_opens_and_shows_a_few_frames() {
+        let mut cpu = CPU::new();
+        // JR -2 forever, with the LCD on
+        cpu.load_rom(&[0x18, 0xFE]).unwrap();
+        cpu.poke(0xFF40, 0x91).unwrap();
+        let mut window = Window::open("window test", 2).unwrap();
+        for _ in 0..5 {
+            let frame = cpu.frame_count();
+            while cpu.frame_count() == frame {
+                cpu.cycle().unwrap();
+            }
+            assert!(window.present(&mut cpu).unwrap());
+        }
+    }
+}
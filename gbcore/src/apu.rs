@@ -0,0 +1,919 @@
+//! APU
+//!
+//! The Audio Processing Unit, with its four sound channels:
+//! two pulse channels (the first with a frequency sweep), a wave channel playing back wave RAM,
+//! and a noise channel driven by an LFSR.
+//! Length counters, envelopes, and the sweep are clocked by the 512 Hz frame sequencer,
+//! which runs off of a DIV bit, so writing to DIV moves it along too.
+//! Channels are mixed to stereo through NR51 panning and NR50 master volume,
+//! producing one sample every 2 dots, regardless of CPU speed, which are fed straight into the sample sink.
+
+use crate::midi::MidiExporter;
+use crate::recorder::Recorder;
+use crate::scope::Oscilloscope;
+use crate::sink::SampleSink;
+use crate::vgm::VgmLogger;
+
+pub const APU_START_ADDR: u16 = 0xFF10;
+pub const APU_END_ADDR: u16 = 0xFF3F;
+const NR10_ADDR: u16 = 0xFF10;
+const NR11_ADDR: u16 = 0xFF11;
+const NR12_ADDR: u16 = 0xFF12;
+const NR13_ADDR: u16 = 0xFF13;
+const NR14_ADDR: u16 = 0xFF14;
+const NR21_ADDR: u16 = 0xFF16;
+const NR22_ADDR: u16 = 0xFF17;
+const NR23_ADDR: u16 = 0xFF18;
+const NR24_ADDR: u16 = 0xFF19;
+const NR30_ADDR: u16 = 0xFF1A;
+const NR31_ADDR: u16 = 0xFF1B;
+const NR32_ADDR: u16 = 0xFF1C;
+const NR33_ADDR: u16 = 0xFF1D;
+const NR34_ADDR: u16 = 0xFF1E;
+const NR41_ADDR: u16 = 0xFF20;
+const NR42_ADDR: u16 = 0xFF21;
+const NR43_ADDR: u16 = 0xFF22;
+const NR44_ADDR: u16 = 0xFF23;
+pub const NR50_ADDR: u16 = 0xFF24;
+pub const NR51_ADDR: u16 = 0xFF25;
+pub const NR52_ADDR: u16 = 0xFF26;
+const WAVE_RAM_ADDR: u16 = 0xFF30;
+
+/// Rate at which the APU produces stereo samples, in Hz.
+pub const NATIVE_SAMPLE_RATE: u32 = 2_097_152;
+
+// Bits ORed into register reads, since unused and write-only bits read back as 1
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+const NOISE_DIVISORS: [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// The four sound channels, in register order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioChannel {
+    Pulse1,
+    Pulse2,
+    Wave,
+    Noise,
+}
+
+impl AudioChannel {
+    pub const ALL: [AudioChannel; 4] = [AudioChannel::Pulse1, AudioChannel::Pulse2, AudioChannel::Wave, AudioChannel::Noise];
+
+    /// Channel numbers count from 1, the same as the register names (NR1x through NR4x).
+    pub fn from_number(number: u8) -> Option<Self> {
+        Self::ALL.get((number as usize).wrapping_sub(1)).copied()
+    }
+}
+
+/// What a channel is up to, as far as someone listening could tell.
+pub struct ChannelState {
+    pub enabled: bool,
+    /// Pitch of the tone in Hz, or for the noise channel, how fast the LFSR is clocked.
+    pub frequency: f64,
+    /// Current volume, from 0 to 15.
+    pub volume: u8,
+    /// Number of times the channel has been triggered.
+    pub triggers: u32,
+    /// Noise channel only, true in 7-bit LFSR mode.
+    pub narrow: bool,
+}
+
+/// One stereo sample, each side in the range -1.0 to 1.0.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StereoSample {
+    pub left: f32,
+    pub right: f32,
+}
+
+struct Length {
+    counter: u16,
+    max: u16,
+    enabled: bool,
+}
+
+impl Length {
+    fn new(max: u16) -> Self {
+        Self {
+            counter: 0,
+            max,
+            enabled: false,
+        }
+    }
+
+    fn load(&mut self, data: u8) {
+        self.counter = self.max - (data as u16 & (self.max - 1));
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    // Returns true once the counter runs out and the channel should shut off
+    fn tick(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0
+        }
+        false
+    }
+}
+
+struct Envelope {
+    initial: u8,
+    up: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Self {
+            initial: 0,
+            up: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn load(&mut self, data: u8) {
+        self.initial = data >> 4;
+        self.up = data & 0x08 != 0;
+        self.period = data & 0x07;
+    }
+
+    // The DAC is powered whenever the upper 5 bits of NRx2 are not all 0
+    fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.up
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    fn tick(&mut self) {
+        if self.period == 0 {
+            return
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.up && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.up && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    shadow: u16,
+    enabled: bool,
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Self {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            shadow: 0,
+            enabled: false,
+        }
+    }
+
+    fn load(&mut self, data: u8) {
+        self.period = (data >> 4) & 0x07;
+        self.negate = data & 0x08 != 0;
+        self.shift = data & 0x07;
+    }
+
+    fn reload_timer(&mut self) {
+        // A period of 0 is treated as 8
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.shadow.wrapping_sub(delta)
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+struct Pulse {
+    enabled: bool,
+    duty: u8,
+    duty_pos: usize,
+    frequency: u16,
+    timer: i32,
+    length: Length,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+impl Pulse {
+    fn new(sweep: bool) -> Self {
+        Self {
+            enabled: false,
+            duty: 0,
+            duty_pos: 0,
+            frequency: 0,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            sweep: if sweep { Some(Sweep::new()) } else { None },
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 4
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = self.frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            // The overflow check runs immediately if there is a shift
+            if sweep.shift != 0 && sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn tick_sweep(&mut self) {
+        let Some(sweep) = &mut self.sweep else { return };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer != 0 {
+            return
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period == 0 {
+            return
+        }
+        let frequency = sweep.next_frequency();
+        if frequency > 2047 {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow = frequency;
+            self.frequency = frequency;
+            // Then check for overflow again with the new frequency, without writing it back
+            if sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn step(&mut self, dots: i32) {
+        self.timer -= dots;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_pos = (self.duty_pos + 1) & 0x07;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0
+        }
+        DUTY_TABLE[self.duty as usize][self.duty_pos] * self.envelope.volume
+    }
+}
+
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: i32,
+    position: usize,
+    sample: u8,
+    length: Length,
+    ram: [u8; 16],
+}
+
+impl Wave {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            length: Length::new(256),
+            ram: [0; 16],
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 2
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn step(&mut self, dots: i32) {
+        self.timer -= dots;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) & 0x1F;
+            // Two 4-bit samples per byte, high nibble first
+            let byte = self.ram[self.position / 2];
+            self.sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0
+        }
+        match self.volume_code {
+            0 => 0,
+            code => self.sample >> (code - 1),
+        }
+    }
+}
+
+struct Noise {
+    enabled: bool,
+    shift: u8,
+    narrow: bool,
+    divisor: u8,
+    timer: i32,
+    lfsr: u16,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            shift: 0,
+            narrow: false,
+            divisor: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn period(&self) -> i32 {
+        NOISE_DIVISORS[self.divisor as usize] << self.shift
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn step(&mut self, dots: i32) {
+        self.timer -= dots;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            let xor = (self.lfsr & 0x01) ^ ((self.lfsr >> 1) & 0x01);
+            self.lfsr = (self.lfsr >> 1) | (xor << 14);
+            // In 7-bit mode the feedback also goes into bit 6
+            if self.narrow {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0
+        }
+        (!self.lfsr & 0x01) as u8 * self.envelope.volume
+    }
+}
+
+pub struct Apu {
+    power: bool,
+    registers: [u8; 0x17],
+    pulse1: Pulse,
+    pulse2: Pulse,
+    wave: Wave,
+    noise: Noise,
+    sequencer_step: u8,
+    // A CGB clears the length counters on power off, a DMG keeps them
+    cgb: bool,
+    triggers: [u32; 4],
+    muted: [bool; 4],
+    soloed: [bool; 4],
+    pub sink: SampleSink,
+    pub recorder: Option<Recorder>,
+    pub scope: Oscilloscope,
+    pub vgm: Option<VgmLogger>,
+    pub midi: Option<MidiExporter>,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Self {
+            power: false,
+            registers: [0; 0x17],
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            sequencer_step: 0,
+            cgb: false,
+            triggers: [0; 4],
+            muted: [false; 4],
+            soloed: [false; 4],
+            sink: SampleSink::default(),
+            recorder: None,
+            scope: Oscilloscope::new(),
+            vgm: None,
+            midi: None,
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            NR52_ADDR => {
+                let status = (self.pulse1.enabled as u8)
+                    | (self.pulse2.enabled as u8) << 1
+                    | (self.wave.enabled as u8) << 2
+                    | (self.noise.enabled as u8) << 3;
+                (self.power as u8) << 7 | status | READ_MASKS[0x16]
+            },
+            NR10_ADDR..NR52_ADDR => {
+                let index = (address - APU_START_ADDR) as usize;
+                self.registers[index] | READ_MASKS[index]
+            },
+            WAVE_RAM_ADDR..=APU_END_ADDR => self.wave.ram[(address - WAVE_RAM_ADDR) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) {
+        if let Some(vgm) = &mut self.vgm {
+            vgm.write(address, data);
+        }
+        match address {
+            NR52_ADDR => self.set_power(data & 0x80 != 0),
+            WAVE_RAM_ADDR..=APU_END_ADDR => self.wave.ram[(address - WAVE_RAM_ADDR) as usize] = data,
+            // Everything else is read only while the APU is off, other than the lengths on a DMG
+            NR10_ADDR..NR52_ADDR if self.power => {
+                self.registers[(address - APU_START_ADDR) as usize] = data;
+                self.write_register(address, data);
+            },
+            NR11_ADDR if !self.cgb => self.pulse1.length.load(data),
+            NR21_ADDR if !self.cgb => self.pulse2.length.load(data),
+            NR31_ADDR if !self.cgb => self.wave.length.load(data),
+            NR41_ADDR if !self.cgb => self.noise.length.load(data),
+            _ => (),
+        }
+    }
+
+    fn write_register(&mut self, address: u16, data: u8) {
+        match address {
+            NR10_ADDR => {
+                if let Some(sweep) = &mut self.pulse1.sweep {
+                    sweep.load(data);
+                }
+            },
+            NR11_ADDR => {
+                self.pulse1.duty = data >> 6;
+                self.pulse1.length.load(data);
+            },
+            NR12_ADDR => {
+                self.pulse1.envelope.load(data);
+                self.pulse1.enabled &= self.pulse1.envelope.dac_enabled();
+            },
+            NR13_ADDR => self.pulse1.frequency = (self.pulse1.frequency & 0x700) | data as u16,
+            NR14_ADDR => {
+                self.pulse1.frequency = (self.pulse1.frequency & 0xFF) | (data as u16 & 0x07) << 8;
+                self.pulse1.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.pulse1.trigger();
+                    self.triggers[0] += 1;
+                }
+            },
+            NR21_ADDR => {
+                self.pulse2.duty = data >> 6;
+                self.pulse2.length.load(data);
+            },
+            NR22_ADDR => {
+                self.pulse2.envelope.load(data);
+                self.pulse2.enabled &= self.pulse2.envelope.dac_enabled();
+            },
+            NR23_ADDR => self.pulse2.frequency = (self.pulse2.frequency & 0x700) | data as u16,
+            NR24_ADDR => {
+                self.pulse2.frequency = (self.pulse2.frequency & 0xFF) | (data as u16 & 0x07) << 8;
+                self.pulse2.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.pulse2.trigger();
+                    self.triggers[1] += 1;
+                }
+            },
+            NR30_ADDR => {
+                self.wave.dac_enabled = data & 0x80 != 0;
+                self.wave.enabled &= self.wave.dac_enabled;
+            },
+            NR31_ADDR => self.wave.length.load(data),
+            NR32_ADDR => self.wave.volume_code = (data >> 5) & 0x03,
+            NR33_ADDR => self.wave.frequency = (self.wave.frequency & 0x700) | data as u16,
+            NR34_ADDR => {
+                self.wave.frequency = (self.wave.frequency & 0xFF) | (data as u16 & 0x07) << 8;
+                self.wave.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.wave.trigger();
+                    self.triggers[2] += 1;
+                }
+            },
+            NR41_ADDR => self.noise.length.load(data),
+            NR42_ADDR => {
+                self.noise.envelope.load(data);
+                self.noise.enabled &= self.noise.envelope.dac_enabled();
+            },
+            NR43_ADDR => {
+                self.noise.shift = data >> 4;
+                self.noise.narrow = data & 0x08 != 0;
+                self.noise.divisor = data & 0x07;
+            },
+            NR44_ADDR => {
+                self.noise.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.noise.trigger();
+                    self.triggers[3] += 1;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// Starts logging register writes, first logging the writes needed to recreate the current state.
+    /// Channels that are currently playing get retriggered.
+    pub fn start_vgm(&mut self, mut vgm: VgmLogger) {
+        vgm.write(NR52_ADDR, (self.power as u8) << 7);
+        for (i, data) in self.wave.ram.iter().enumerate() {
+            vgm.write(WAVE_RAM_ADDR + i as u16, *data);
+        }
+        if self.power {
+            let enabled = [self.pulse1.enabled, self.pulse2.enabled, self.wave.enabled, self.noise.enabled];
+            for address in NR10_ADDR..NR52_ADDR {
+                let mut data = self.registers[(address - APU_START_ADDR) as usize];
+                // NRx4 is the last register of each channel, and the one that triggers it
+                let channel = (address - NR10_ADDR) as usize / 5;
+                if channel < 4 && (address - NR10_ADDR) % 5 == 4 {
+                    data = (data & 0x7F) | (enabled[channel] as u8) << 7;
+                }
+                vgm.write(address, data);
+            }
+        }
+        self.vgm = Some(vgm);
+    }
+
+    pub fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+    }
+
+    fn set_power(&mut self, power: bool) {
+        if self.power && !power {
+            // Powering off clears every register, but leaves wave RAM alone, and the length counters on a DMG
+            let lengths = [self.pulse1.length.counter, self.pulse2.length.counter, self.wave.length.counter, self.noise.length.counter];
+            self.registers = [0; 0x17];
+            self.pulse1 = Pulse::new(true);
+            self.pulse2 = Pulse::new(false);
+            self.wave = Wave { ram: self.wave.ram, ..Wave::new() };
+            self.noise = Noise::new();
+            if !self.cgb {
+                self.pulse1.length.counter = lengths[0];
+                self.pulse2.length.counter = lengths[1];
+                self.wave.length.counter = lengths[2];
+                self.noise.length.counter = lengths[3];
+            }
+        } else if !self.power && power {
+            self.sequencer_step = 0;
+        }
+        self.power = power;
+    }
+
+    /// Advances the APU by some number of dots.
+    pub fn step(&mut self, dots: u32) {
+        if let Some(vgm) = &mut self.vgm {
+            vgm.step(dots);
+        }
+        if self.midi.as_mut().is_some_and(|midi| midi.step(dots)) {
+            let states = self.channel_states();
+            if let Some(midi) = &mut self.midi {
+                midi.observe(&states);
+            }
+        }
+        // Nothing in the APU runs finer than 2 dots, so step in pairs
+        for _ in 0..dots / 2 {
+            if self.power {
+                self.pulse1.step(2);
+                self.pulse2.step(2);
+                self.wave.step(2);
+                self.noise.step(2);
+            }
+
+            let outputs = self.channel_outputs();
+            self.scope.push(&outputs);
+            let channels = self.mix_channels(&outputs);
+            let sample = mix(&channels, &self.audible());
+            self.sink.push(sample);
+            if let Some(recorder) = &mut self.recorder {
+                recorder.push(sample, &channels);
+            }
+        }
+    }
+
+    /// Moves the frame sequencer on a step, on a falling edge of its DIV bit.
+    pub fn clock_sequencer(&mut self) {
+        if !self.power {
+            return
+        }
+        // Step:     0  1  2  3  4  5  6  7
+        // Length:   x     x     x     x
+        // Sweep:          x           x
+        // Envelope:                      x
+        if self.sequencer_step.is_multiple_of(2) {
+            if self.pulse1.length.tick() {
+                self.pulse1.enabled = false;
+            }
+            if self.pulse2.length.tick() {
+                self.pulse2.enabled = false;
+            }
+            if self.wave.length.tick() {
+                self.wave.enabled = false;
+            }
+            if self.noise.length.tick() {
+                self.noise.enabled = false;
+            }
+        }
+        if self.sequencer_step == 2 || self.sequencer_step == 6 {
+            self.pulse1.tick_sweep();
+        }
+        if self.sequencer_step == 7 {
+            self.pulse1.envelope.tick();
+            self.pulse2.envelope.tick();
+            self.noise.envelope.tick();
+        }
+        self.sequencer_step = (self.sequencer_step + 1) & 0x07;
+    }
+
+    pub fn channel_states(&self) -> [ChannelState; 4] {
+        let tone = |clock: f64, frequency: u16| clock / (2048 - frequency) as f64;
+        let wave_volume = match self.wave.volume_code {
+            0 => 0,
+            code => 15 >> (code - 1),
+        };
+        [
+            ChannelState {
+                enabled: self.pulse1.enabled,
+                frequency: tone(131072.0, self.pulse1.frequency),
+                volume: self.pulse1.envelope.volume,
+                triggers: self.triggers[0],
+                narrow: false,
+            },
+            ChannelState {
+                enabled: self.pulse2.enabled,
+                frequency: tone(131072.0, self.pulse2.frequency),
+                volume: self.pulse2.envelope.volume,
+                triggers: self.triggers[1],
+                narrow: false,
+            },
+            ChannelState {
+                enabled: self.wave.enabled,
+                frequency: tone(65536.0, self.wave.frequency),
+                volume: wave_volume,
+                triggers: self.triggers[2],
+                narrow: false,
+            },
+            ChannelState {
+                enabled: self.noise.enabled,
+                frequency: 4194304.0 / self.noise.period() as f64,
+                volume: self.noise.envelope.volume,
+                triggers: self.triggers[3],
+                narrow: self.noise.narrow,
+            },
+        ]
+    }
+
+    /// The analog output of each channel's DAC, from -1.0 to 1.0.
+    /// A channel with its DAC turned off outputs 0.
+    fn channel_outputs(&self) -> [f32; 4] {
+        let dac = |enabled: bool, digital: u8| {
+            if enabled { digital as f32 / 7.5 - 1.0 } else { 0.0 }
+        };
+        [
+            dac(self.pulse1.envelope.dac_enabled(), self.pulse1.output()),
+            dac(self.pulse2.envelope.dac_enabled(), self.pulse2.output()),
+            dac(self.wave.dac_enabled, self.wave.output()),
+            dac(self.noise.envelope.dac_enabled(), self.noise.output()),
+        ]
+    }
+
+    pub fn set_muted(&mut self, channel: AudioChannel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    pub fn set_soloed(&mut self, channel: AudioChannel, soloed: bool) {
+        self.soloed[channel as usize] = soloed;
+    }
+
+    /// Which channels make it into the mix. While any channel is soloed, only soloed channels do.
+    pub fn audible(&self) -> [bool; 4] {
+        if self.soloed.contains(&true) {
+            self.soloed
+        } else {
+            self.muted.map(|muted| !muted)
+        }
+    }
+
+    /// Each channel panned through NR51 and scaled by NR50, so that together they add up to the mix.
+    /// Muting is left to the mix, so stems still get every channel.
+    fn mix_channels(&self, outputs: &[f32; 4]) -> [StereoSample; 4] {
+        let mut channels = [StereoSample::default(); 4];
+        if !self.power {
+            return channels
+        }
+        let nr50 = self.registers[(NR50_ADDR - APU_START_ADDR) as usize];
+        let nr51 = self.registers[(NR51_ADDR - APU_START_ADDR) as usize];
+        // Average the 4 channels, then apply the master volume (1-8 out of 8)
+        let left_volume = (((nr50 >> 4) & 0x07) as f32 + 1.0) / 8.0 / 4.0;
+        let right_volume = ((nr50 & 0x07) as f32 + 1.0) / 8.0 / 4.0;
+        for (channel, output) in outputs.iter().enumerate() {
+            if nr51 & (0x10 << channel) != 0 {
+                channels[channel].left = output * left_volume;
+            }
+            if nr51 & (0x01 << channel) != 0 {
+                channels[channel].right = output * right_volume;
+            }
+        }
+        channels
+    }
+
+}
+
+fn mix(channels: &[StereoSample; 4], audible: &[bool; 4]) -> StereoSample {
+    channels.iter().zip(audible)
+        .filter(|(_, audible)| **audible)
+        .fold(StereoSample::default(), |mix, (channel, _)| StereoSample {
+            left: mix.left + channel.left,
+            right: mix.right + channel.right,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered() -> Apu {
+        let mut apu = Apu::new();
+        apu.write(NR52_ADDR, 0x80);
+        apu
+    }
+
+    fn clock(apu: &mut Apu, steps: usize) {
+        for _ in 0..steps {
+            apu.clock_sequencer();
+        }
+    }
+
+    fn pulse1_on(apu: &Apu) -> bool {
+        apu.read(NR52_ADDR) & 0x01 != 0
+    }
+
+    #[test]
+    fn length_expiry_clears_the_status_bit() {
+        let mut apu = powered();
+        apu.write(NR12_ADDR, 0xF0);
+        apu.write(NR11_ADDR, 62);
+        apu.write(NR14_ADDR, 0xC0);
+        assert!(pulse1_on(&apu));
+        // Length is clocked on every other step, starting with this one
+        clock(&mut apu, 2);
+        assert!(pulse1_on(&apu));
+        clock(&mut apu, 1);
+        assert!(!pulse1_on(&apu));
+    }
+
+    #[test]
+    fn sweep_overflow_disables_the_channel() {
+        let mut apu = powered();
+        // Period 1, adding a quarter each time: 0x600 goes to 0x780, and the check after that overflows
+        apu.write(NR10_ADDR, 0x12);
+        apu.write(NR12_ADDR, 0xF0);
+        apu.write(NR13_ADDR, 0x00);
+        apu.write(NR14_ADDR, 0x86);
+        assert!(pulse1_on(&apu));
+        // The sweep is clocked on steps 2 and 6
+        clock(&mut apu, 2);
+        assert!(pulse1_on(&apu));
+        clock(&mut apu, 1);
+        assert!(!pulse1_on(&apu));
+        assert_eq!(apu.pulse1.frequency, 0x780);
+    }
+
+    #[test]
+    fn envelope_steps_on_the_last_sequencer_step() {
+        let mut apu = powered();
+        // Starting at 15 and going down, every envelope clock
+        apu.write(NR12_ADDR, 0xF1);
+        apu.write(NR14_ADDR, 0x80);
+        clock(&mut apu, 7);
+        assert_eq!(apu.channel_states()[0].volume, 15);
+        clock(&mut apu, 1);
+        assert_eq!(apu.channel_states()[0].volume, 14);
+        clock(&mut apu, 8);
+        assert_eq!(apu.channel_states()[0].volume, 13);
+    }
+
+    #[test]
+    fn power_off_clears_registers_but_not_dmg_lengths() {
+        let mut apu = powered();
+        apu.write(NR12_ADDR, 0xF0);
+        apu.write(NR50_ADDR, 0x77);
+        apu.write(NR11_ADDR, 62);
+        apu.write(NR52_ADDR, 0x00);
+        assert_eq!(apu.read(NR12_ADDR), 0x00);
+        assert_eq!(apu.read(NR50_ADDR), 0x00);
+        assert_eq!(apu.read(NR52_ADDR), 0x70);
+
+        // Only the length registers can be written while off
+        apu.write(NR12_ADDR, 0xF0);
+        apu.write(NR21_ADDR, 63);
+        assert_eq!(apu.read(NR12_ADDR), 0x00);
+
+        // Pulse 1 kept its 2 steps, and pulse 2 got 1
+        apu.write(NR52_ADDR, 0x80);
+        apu.write(NR12_ADDR, 0xF0);
+        apu.write(NR14_ADDR, 0xC0);
+        apu.write(NR22_ADDR, 0xF0);
+        apu.write(NR24_ADDR, 0xC0);
+        assert_eq!(apu.read(NR52_ADDR) & 0x03, 0x03);
+        clock(&mut apu, 1);
+        assert_eq!(apu.read(NR52_ADDR) & 0x03, 0x01);
+        clock(&mut apu, 2);
+        assert_eq!(apu.read(NR52_ADDR) & 0x03, 0x00);
+    }
+
+    #[test]
+    fn power_off_clears_cgb_lengths() {
+        let mut apu = Apu::new();
+        apu.set_cgb(true);
+        apu.write(NR52_ADDR, 0x80);
+        apu.write(NR11_ADDR, 63);
+        apu.write(NR52_ADDR, 0x00);
+        apu.write(NR11_ADDR, 63);
+        apu.write(NR52_ADDR, 0x80);
+        // Back to a full 64 steps on the trigger
+        apu.write(NR12_ADDR, 0xF0);
+        apu.write(NR14_ADDR, 0xC0);
+        clock(&mut apu, 3);
+        assert!(pulse1_on(&apu));
+        assert_eq!(apu.pulse1.length.counter, 62);
+    }
+}
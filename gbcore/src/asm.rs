@@ -0,0 +1,511 @@
+//! Assembler
+//!
+//! Turns RGBDS-style assembly into bytes, mostly for writing small test programs without hand-encoding opcodes:
+//!
+//! ```text
+//! Start:
+//!     ld b, 5
+//! .loop:
+//!     dec b
+//!     jr nz, .loop
+//!     halt
+//! ```
+//!
+//! There's one instruction or label per line, comments start with `;`, and labels end in `:`.
+//! Labels starting with `.` are local to the last label that doesn't. Numbers can be decimal, `$` hex, or `%` binary,
+//! and anywhere a number goes, so can a label, `@` for the current address, or a sum of them.
+//! `NAME EQU value` defines a constant, and `db` and `dw` put in raw bytes and words.
+//!
+//! Rather than keeping a second copy of the opcode table, each instruction is matched against what the disassembler
+//! makes of every opcode, so the two can't disagree.
+
+use std::collections::HashMap;
+use anyhow::{anyhow, bail, Context, Result};
+use crate::disasm::{Instruction, Mnemonic, Operand};
+
+const CB_PREFIX: u8 = 0xCB;
+
+/// Assembles a program to be loaded at `origin`, so labels get the right addresses.
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>> {
+    let mut assembler = Assembler {
+        symbols: HashMap::new(),
+        scope: String::new(),
+    };
+
+    // First pass works out where everything goes, the second fills in the values
+    let mut items = Vec::new();
+    let mut address = origin;
+    for (number, line) in source.lines().enumerate() {
+        let parsed = assembler.parse_line(line, address)
+            .with_context(|| format!("Line {}: {}", number + 1, line.trim()))?;
+        if let Some(item) = parsed {
+            address = address.wrapping_add(item.size());
+            items.push((number, line, item));
+        }
+    }
+
+    let mut bytes = Vec::new();
+    let mut address = origin;
+    for (number, line, item) in &items {
+        let encoded = assembler.encode(item, address)
+            .with_context(|| format!("Line {}: {}", number + 1, line.trim()))?;
+        address = address.wrapping_add(encoded.len() as u16);
+        bytes.extend(encoded);
+    }
+    Ok(bytes)
+}
+
+/// An operand as written, before it's matched to an instruction.
+#[derive(Clone, Debug)]
+enum Written {
+    /// A register, condition, or register indirection, normalized to how the disassembler prints it.
+    Fixed(String),
+    Value(Expr),
+    /// A value in brackets.
+    Memory(Expr),
+    /// `sp` plus or minus a value.
+    SpOffset(Expr),
+}
+
+/// An expression, kept as text along with the label scope it appeared in, since it can't be worked out
+/// until every label has an address.
+#[derive(Clone, Debug)]
+struct Expr {
+    text: String,
+    scope: String,
+    address: u16,
+}
+
+enum Item {
+    Instruction {
+        // The opcode after any CB prefix
+        opcode: u8,
+        cb: bool,
+        template: Instruction,
+        operands: Vec<Written>,
+    },
+    Bytes(Vec<Expr>),
+    Words(Vec<Expr>),
+}
+
+impl Item {
+    fn size(&self) -> u16 {
+        match self {
+            Item::Instruction { template, .. } => template.length,
+            Item::Bytes(values) => values.len() as u16,
+            Item::Words(values) => values.len() as u16 * 2,
+        }
+    }
+}
+
+struct Assembler {
+    symbols: HashMap<String, i64>,
+    // The last non-local label, which local labels hang off
+    scope: String,
+}
+
+impl Assembler {
+    fn parse_line(&mut self, line: &str, address: u16) -> Result<Option<Item>> {
+        let mut line = line.split(';').next().unwrap_or("").trim();
+
+        // A label, maybe with an instruction after it
+        if let Some((label, rest)) = line.split_once(':')
+            && is_name(label.trim_start_matches('.')) {
+            let label = label.trim();
+            let name = if label.starts_with('.') {
+                format!("{}{}", self.scope, label)
+            } else {
+                self.scope = label.to_string();
+                label.to_string()
+            };
+            self.define(name, address as i64)?;
+            line = rest.trim_start_matches(':').trim();
+        }
+        if line.is_empty() {
+            return Ok(None)
+        }
+
+        let (mnemonic, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        // NAME EQU value
+        if let Some((keyword, value)) = rest.split_once(char::is_whitespace)
+            && keyword.eq_ignore_ascii_case("equ") {
+            let value = self.evaluate(&self.expr(value, address))?;
+            self.define(mnemonic.to_string(), value)?;
+            return Ok(None)
+        }
+
+        let arguments: Vec<&str> = if rest.is_empty() { Vec::new() } else { rest.split(',').map(str::trim).collect() };
+        match mnemonic.to_ascii_lowercase().as_str() {
+            "db" => return Ok(Some(Item::Bytes(arguments.iter().map(|value| self.expr(value, address)).collect()))),
+            "dw" => return Ok(Some(Item::Words(arguments.iter().map(|value| self.expr(value, address)).collect()))),
+            _ => {},
+        }
+
+        let operands: Vec<Written> = arguments.iter().map(|operand| self.parse_operand(operand, address)).collect();
+        let mnemonic = mnemonic.to_ascii_lowercase();
+        for (mnemonic, operands) in spellings(&mnemonic, operands) {
+            if let Some(item) = self.find_opcode(&mnemonic, operands)? {
+                return Ok(Some(item))
+            }
+        }
+        bail!("No {} instruction takes those operands", mnemonic)
+    }
+
+    /// Finds the opcode the disassembler would show as this mnemonic with these operands.
+    fn find_opcode(&self, mnemonic: &str, operands: Vec<Written>) -> Result<Option<Item>> {
+        let plain = (0..=255).filter(|&opcode| opcode != CB_PREFIX).map(|opcode| (opcode, false));
+        let prefixed = (0..=255).map(|opcode| (opcode, true));
+        for (opcode, cb) in plain.chain(prefixed) {
+            let bytes = if cb { [CB_PREFIX, opcode, 0] } else { [opcode, 0, 0] };
+            let template = Instruction::decode(0, bytes);
+            if template.mnemonic == Mnemonic::Illegal
+                || template.mnemonic.name() != mnemonic
+                || template.operands.len() != operands.len() {
+                continue
+            }
+            let mut matches = true;
+            for (written, operand) in operands.iter().zip(&template.operands) {
+                matches &= self.operand_matches(written, operand)?;
+            }
+            if matches {
+                return Ok(Some(Item::Instruction { opcode, cb, template, operands }))
+            }
+        }
+        Ok(None)
+    }
+
+    fn operand_matches(&self, written: &Written, operand: &Operand) -> Result<bool> {
+        Ok(match (written, operand) {
+            (Written::Fixed(name), _) => operand_is_fixed(operand) && operand.to_string() == *name,
+            // These are part of the opcode, so they have to be known already
+            (Written::Value(expr), Operand::Bit(bit)) => self.evaluate(expr)? == *bit as i64,
+            (Written::Value(expr), Operand::Vector(vector)) => self.evaluate(expr)? == *vector as i64,
+            (Written::Value(_), Operand::Imm8(_) | Operand::Imm16(_) | Operand::Target(_) | Operand::Offset(_)) => true,
+            (Written::Memory(_), Operand::Address(_) | Operand::HighAddress(_)) => true,
+            (Written::SpOffset(_), Operand::SpOffset(_)) => true,
+            _ => false,
+        })
+    }
+
+    fn encode(&self, item: &Item, address: u16) -> Result<Vec<u8>> {
+        let (opcode, cb, template, operands) = match item {
+            Item::Instruction { opcode, cb, template, operands } => (*opcode, *cb, template, operands),
+            Item::Bytes(values) => return values.iter().map(|value| self.byte(value)).collect(),
+            Item::Words(values) => {
+                let mut bytes = Vec::new();
+                for value in values {
+                    bytes.extend(self.word(value)?.to_le_bytes());
+                }
+                return Ok(bytes)
+            },
+        };
+
+        let mut bytes = if cb { vec![CB_PREFIX, opcode] } else { vec![opcode] };
+        for (written, operand) in operands.iter().zip(&template.operands) {
+            match (written, operand) {
+                (Written::Value(expr), Operand::Target(_)) if template.mnemonic == Mnemonic::Jr => {
+                    let offset = self.evaluate(expr)? - (address as i64 + 2);
+                    let offset = i8::try_from(offset).map_err(|_| anyhow!("{} is too far for jr, {} bytes away", expr.text, offset))?;
+                    bytes.push(offset as u8);
+                },
+                (Written::Value(expr), Operand::Imm16(_) | Operand::Target(_)) | (Written::Memory(expr), Operand::Address(_)) => {
+                    bytes.extend(self.word(expr)?.to_le_bytes());
+                },
+                (Written::Memory(expr), Operand::HighAddress(_)) => {
+                    // Written either as the whole address, or just the part after 0xFF00
+                    let value = self.evaluate(expr)?;
+                    let low = match value {
+                        0xFF00..=0xFFFF => value - 0xFF00,
+                        0..=0xFF => value,
+                        _ => bail!("${:X} isn't an address ldh can reach", value),
+                    };
+                    bytes.push(low as u8);
+                },
+                (Written::Value(expr) | Written::SpOffset(expr), Operand::Offset(_) | Operand::SpOffset(_)) => {
+                    let value = self.evaluate(expr)?;
+                    let offset = i8::try_from(value).map_err(|_| anyhow!("{} doesn't fit in a signed byte", value))?;
+                    bytes.push(offset as u8);
+                },
+                (Written::Value(expr), Operand::Imm8(_)) => bytes.push(self.byte(expr)?),
+                _ => {},
+            }
+        }
+        // STOP takes a byte after it that doesn't do anything
+        bytes.resize(template.length as usize, 0);
+        Ok(bytes)
+    }
+
+    fn parse_operand(&self, text: &str, address: u16) -> Written {
+        let normalized: String = text.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_ascii_lowercase();
+        let fixed = match normalized.as_str() {
+            "[hli]" => Some("[hl+]"),
+            "[hld]" => Some("[hl-]"),
+            "[$ff00+c]" | "[c]" => Some("[c]"),
+            "a" | "b" | "c" | "d" | "e" | "h" | "l" | "af" | "bc" | "de" | "hl" | "sp" | "nz" | "z" | "nc"
+                | "[hl]" | "[bc]" | "[de]" | "[hl+]" | "[hl-]" => Some(normalized.as_str()),
+            _ => None,
+        };
+        if let Some(fixed) = fixed {
+            return Written::Fixed(fixed.to_string())
+        }
+        let text = text.trim();
+        if let Some(inner) = text.strip_prefix('[').and_then(|text| text.strip_suffix(']')) {
+            return Written::Memory(self.expr(inner, address))
+        }
+        if normalized.starts_with("sp+") || normalized.starts_with("sp-") {
+            return Written::SpOffset(self.expr(&normalized[2..], address))
+        }
+        Written::Value(self.expr(text, address))
+    }
+
+    fn expr(&self, text: &str, address: u16) -> Expr {
+        Expr {
+            text: text.trim().to_string(),
+            scope: self.scope.clone(),
+            address,
+        }
+    }
+
+    fn define(&mut self, name: String, value: i64) -> Result<()> {
+        if self.symbols.insert(name.clone(), value).is_some() {
+            bail!("{} is already defined", name);
+        }
+        Ok(())
+    }
+
+    /// Works out a sum of terms, each a number, label, or `@`.
+    fn evaluate(&self, expr: &Expr) -> Result<i64> {
+        let mut total = 0;
+        let mut sign = 1;
+        let mut term = String::new();
+        let text: String = expr.text.chars().filter(|c| !c.is_whitespace()).collect();
+        for c in text.chars().chain(std::iter::once('+')) {
+            if (c == '+' || c == '-') && !term.is_empty() {
+                total += sign * self.term(&term, expr)?;
+                term.clear();
+                sign = if c == '-' { -1 } else { 1 };
+            } else if c == '-' {
+                sign = -sign;
+            } else if c != '+' {
+                term.push(c);
+            }
+        }
+        if text.is_empty() {
+            bail!("Expected a value");
+        }
+        Ok(total)
+    }
+
+    fn term(&self, term: &str, expr: &Expr) -> Result<i64> {
+        let number = if let Some(hex) = term.strip_prefix('$') {
+            i64::from_str_radix(hex, 16).ok()
+        } else if let Some(binary) = term.strip_prefix('%') {
+            i64::from_str_radix(binary, 2).ok()
+        } else if term.starts_with(|c: char| c.is_ascii_digit()) {
+            term.parse().ok()
+        } else if term == "@" {
+            Some(expr.address as i64)
+        } else {
+            let name = if term.starts_with('.') { format!("{}{}", expr.scope, term) } else { term.to_string() };
+            return self.symbols.get(&name).copied().ok_or_else(|| anyhow!("{} isn't defined", name))
+        };
+        number.ok_or_else(|| anyhow!("{} isn't a number", term))
+    }
+
+    fn byte(&self, expr: &Expr) -> Result<u8> {
+        let value = self.evaluate(expr)?;
+        match value {
+            -0x80..=0xFF => Ok(value as u8),
+            _ => bail!("{} doesn't fit in a byte", value),
+        }
+    }
+
+    fn word(&self, expr: &Expr) -> Result<u16> {
+        let value = self.evaluate(expr)?;
+        match value {
+            -0x8000..=0xFFFF => Ok(value as u16),
+            _ => bail!("{} doesn't fit in a word", value),
+        }
+    }
+}
+
+/// The ways RGBDS lets an instruction be written that mean the same thing, with the disassembler's own way among them.
+fn spellings(mnemonic: &str, operands: Vec<Written>) -> Vec<(String, Vec<Written>)> {
+    let is_a = |operand: &Written| matches!(operand, Written::Fixed(name) if name == "a");
+    let is_c = |operand: &Written| matches!(operand, Written::Fixed(name) if name == "[c]");
+    match mnemonic {
+        // `a` can be left off of these, or spelled out
+        "sub" | "and" | "xor" | "or" | "cp" if operands.len() == 2 && is_a(&operands[0]) => {
+            vec![(mnemonic.to_string(), operands[1..].to_vec())]
+        },
+        "add" | "adc" | "sbc" if operands.len() == 1 => {
+            let mut with_a = vec![Written::Fixed("a".to_string())];
+            with_a.extend(operands);
+            vec![(mnemonic.to_string(), with_a)]
+        },
+        // The 0xFF00 + C forms work as plain `ld` too
+        "ld" if operands.iter().any(is_c) => vec![("ldh".to_string(), operands)],
+        _ => vec![(mnemonic.to_string(), operands)],
+    }
+}
+
+/// Operands that are spelled the same way every time, rather than carrying a value.
+fn operand_is_fixed(operand: &Operand) -> bool {
+    matches!(operand,
+        Operand::Reg8(_) | Operand::Reg16(_) | Operand::Indirect(_) | Operand::HlIncrement | Operand::HlDecrement
+            | Operand::HighC | Operand::Condition(_))
+}
+
+fn is_name(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registers::{CARRY_FLAG, HALF_CARRY_FLAG, SUB_FLAG, ZERO_FLAG};
+    use crate::{Reg8, Reg16, CPU};
+
+    const ORIGIN: u16 = 0x0100;
+
+    /// Assembles a program, loads it at 0x0100, and runs it until it halts.
+    fn run(source: &str) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load_rom(&assemble(source, ORIGIN).unwrap()).unwrap();
+        cpu.set_stack_pointer(0xFFFE);
+        for _ in 0..10_000 {
+            if cpu.halted() {
+                return cpu
+            }
+            cpu.cycle().unwrap();
+        }
+        panic!("Never halted: {}", cpu);
+    }
+
+    #[test]
+    fn disassembly_assembles_back_to_the_same_bytes() {
+        let prefixed = (0..=255).map(|opcode| [CB_PREFIX, opcode, 0]);
+        // Operands far enough from zero to catch a byte landing in the wrong place or a jump going the wrong way.
+        // STOP's padding byte always assembles to zero, so give it one
+        let plain = (0..=255u8).filter(|&opcode| opcode != CB_PREFIX)
+            .map(|opcode| if opcode == 0x10 { [opcode, 0, 0] } else { [opcode, 0xF0, 0x12] });
+        for bytes in plain.chain(prefixed) {
+            let instruction = Instruction::decode(ORIGIN, bytes);
+            if instruction.mnemonic == Mnemonic::Illegal {
+                continue
+            }
+            let text = instruction.to_string();
+            let assembled = assemble(&text, ORIGIN).unwrap_or_else(|err| panic!("{}: {:#}", text, err));
+            assert_eq!(assembled, bytes[..instruction.length as usize], "{}", text);
+        }
+    }
+
+    #[test]
+    fn labels_constants_and_data() {
+        let source = "
+            COUNT EQU 3
+            Start:
+                ld b, COUNT
+            .loop:
+                dec b
+                jr nz, .loop
+                jp Start + 1
+                db $12, -1, %101
+                dw @
+        ";
+        let expected = [0x06, 0x03, 0x05, 0x20, 0xFD, 0xC3, 0x01, 0x01, 0x12, 0xFF, 0x05, 0x0B, 0x01];
+        assert_eq!(assemble(source, ORIGIN).unwrap(), expected);
+        assert!(assemble("ld b, Nowhere", ORIGIN).is_err());
+        assert!(assemble("jr $2000", ORIGIN).is_err());
+        assert!(assemble("ld [bc], b", ORIGIN).is_err());
+    }
+
+    #[test]
+    fn add_sets_zero_half_carry_and_carry() {
+        let cpu = run("
+            ld a, $3A
+            ld b, $C6
+            add a, b
+            halt
+        ");
+        assert_eq!(cpu.reg8(Reg8::A), 0x00);
+        assert_eq!(cpu.reg8(Reg8::F), ZERO_FLAG | HALF_CARRY_FLAG | CARRY_FLAG);
+    }
+
+    #[test]
+    fn counting_down_a_loop() {
+        let cpu = run("
+            scf
+            ld b, 5
+            ld c, $FF
+        .loop:
+            inc c
+            dec b
+            jr nz, .loop
+            halt
+        ");
+        assert_eq!(cpu.reg8(Reg8::B), 0);
+        assert_eq!(cpu.reg8(Reg8::C), 4);
+        // DEC leaves the carry from SCF alone
+        assert_eq!(cpu.reg8(Reg8::F), ZERO_FLAG | SUB_FLAG | CARRY_FLAG);
+    }
+
+    #[test]
+    fn call_returns_after_itself() {
+        let cpu = run("
+            call Double
+            call Double
+            halt
+        Double:
+            ld a, 3
+            add a, a
+            ld d, a
+            ret
+        ");
+        assert_eq!(cpu.reg8(Reg8::D), 6);
+        assert_eq!(cpu.stack_pointer(), 0xFFFE);
+        assert_eq!(cpu.program_counter(), ORIGIN + 7);
+    }
+
+    #[test]
+    fn loads_and_stores() {
+        let cpu = run("
+            ld a, $5A
+            ld hl, $C000
+            ld [hl+], a
+            ld [$C010], a
+            ldh [$FF80], a
+            xor a
+            ld bc, $C000
+            ld a, [bc]
+            cpl
+            ld e, a
+            ldh a, [$80]
+            ld d, a
+            ld a, [$C010]
+            rlca
+            halt
+        ");
+        assert_eq!(cpu.reg16(Reg16::HL), 0xC001);
+        assert_eq!(cpu.peek(0xC000), 0x5A);
+        assert_eq!(cpu.peek(0xC010), 0x5A);
+        assert_eq!(cpu.peek(0xFF80), 0x5A);
+        assert_eq!(cpu.reg8(Reg8::E), 0xA5);
+        assert_eq!(cpu.reg8(Reg8::D), 0x5A);
+        assert_eq!(cpu.reg8(Reg8::A), 0xB4);
+        assert_eq!(cpu.reg8(Reg8::F), 0);
+    }
+
+    #[test]
+    fn bcd_addition() {
+        let cpu = run("
+            ld a, $19
+            add a, $28
+            daa
+            halt
+        ");
+        assert_eq!(cpu.reg8(Reg8::A), 0x47);
+    }
+}
@@ -0,0 +1,326 @@
+//! Cartridge
+//!
+//! Loads ROM images, reads their headers, and emulates the memory bank controller (MBC) inside the cartridge.
+//! The MBC maps banks of ROM into 0x4000-0x7FFF and banks of external RAM into 0xA000-0xBFFF,
+//! switched by writes to the otherwise read-only ROM area.
+
+use anyhow::{anyhow, Result};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+const HEADER_END: usize = 0x0150;
+
+/// Memory bank controllers, or lack thereof.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mbc {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+/// The parts of the cartridge header at 0x0100-0x014F the emulator cares about.
+#[derive(Clone, Debug)]
+pub struct CartridgeHeader {
+    pub title: String,
+    /// 0x80 if the game supports CGB features, 0xC0 if it only runs on a CGB.
+    pub cgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub header_checksum: u8,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self> {
+        if rom.len() < HEADER_END {
+            return Err(anyhow!("ROM is too small to contain a header: {} bytes", rom.len()))
+        }
+        let title = &rom[0x0134..0x0144];
+        let end = title.iter().position(|&b| b == 0).unwrap_or(title.len());
+        let rom_size = match rom[0x0148] {
+            code @ 0x00..=0x08 => (32 * 1024) << code,
+            code => return Err(anyhow!("Unknown ROM size code in header: {:#04X}", code))
+        };
+        let ram_size = match rom[0x0149] {
+            0x00 => 0,
+            0x02 => 8 * 1024,
+            0x03 => 32 * 1024,
+            0x04 => 128 * 1024,
+            0x05 => 64 * 1024,
+            code => return Err(anyhow!("Unknown RAM size code in header: {:#04X}", code))
+        };
+        Ok(Self {
+            title: String::from_utf8_lossy(&title[..end]).trim_end().to_string(),
+            cgb_flag: rom[0x0143],
+            cartridge_type: rom[0x0147],
+            rom_size,
+            ram_size,
+            header_checksum: rom[0x014D],
+        })
+    }
+
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    /// Which MBC the cartridge type code calls for, and whether it has a battery to keep RAM around.
+    pub fn mbc(&self) -> Result<(Mbc, bool)> {
+        match self.cartridge_type {
+            0x00 | 0x08 => Ok((Mbc::None, false)),
+            0x09 => Ok((Mbc::None, true)),
+            0x01 | 0x02 => Ok((Mbc::Mbc1, false)),
+            0x03 => Ok((Mbc::Mbc1, true)),
+            0x05 => Ok((Mbc::Mbc2, false)),
+            0x06 => Ok((Mbc::Mbc2, true)),
+            0x11 | 0x12 => Ok((Mbc::Mbc3, false)),
+            0x0F | 0x10 | 0x13 => Ok((Mbc::Mbc3, true)),
+            0x19 | 0x1A | 0x1C | 0x1D => Ok((Mbc::Mbc5, false)),
+            0x1B | 0x1E => Ok((Mbc::Mbc5, true)),
+            code => Err(anyhow!("Unsupported cartridge type: {:#04X}", code))
+        }
+    }
+
+    /// Checks the header checksum the same way the boot ROM does.
+    pub fn checksum_valid(rom: &[u8]) -> bool {
+        if rom.len() < HEADER_END {
+            return false
+        }
+        let checksum = rom[0x0134..0x014D].iter().fold(0u8, |sum, &b| sum.wrapping_sub(b).wrapping_sub(1));
+        rom.get(0x014D) == Some(&checksum)
+    }
+}
+
+pub struct Cartridge {
+    header: CartridgeHeader,
+    mbc: Mbc,
+    battery: bool,
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: usize,
+    ram_bank: usize,
+    // MBC1 only: in mode 1, the upper bank bits select the RAM bank instead of the upper ROM bits
+    mbc1_ram_mode: bool,
+    // MBC3 only: an RTC register is mapped in at 0xA000-0xBFFF instead of RAM
+    rtc_selected: bool,
+}
+
+impl Cartridge {
+    /// Loads a ROM image, setting up the MBC and RAM its header asks for.
+    pub fn load(rom: Vec<u8>) -> Result<Self> {
+        let header = CartridgeHeader::parse(&rom)?;
+        let (mbc, battery) = header.mbc()?;
+        if rom.len() < ROM_BANK_SIZE {
+            return Err(anyhow!("ROM is smaller than a single bank: {} bytes", rom.len()))
+        }
+        // MBC2 has 512 half-bytes of RAM built in, whatever the header says
+        let ram_size = if mbc == Mbc::Mbc2 { 512 } else { header.ram_size };
+        Ok(Self::new(header, mbc, battery, rom, ram_size))
+    }
+
+    /// Wraps a raw ROM image with no header to speak of, banked by the given MBC.
+    /// It gets a bank of RAM, already enabled, since code ripped out of a game tends to expect some.
+    pub fn from_image(rom: Vec<u8>, mbc: Mbc) -> Self {
+        let header = CartridgeHeader {
+            title: String::new(),
+            cgb_flag: 0,
+            cartridge_type: 0,
+            rom_size: rom.len(),
+            ram_size: RAM_BANK_SIZE,
+            header_checksum: 0,
+        };
+        let mut cartridge = Self::new(header, mbc, false, rom, RAM_BANK_SIZE);
+        cartridge.ram_enabled = true;
+        cartridge
+    }
+
+    fn new(header: CartridgeHeader, mbc: Mbc, battery: bool, rom: Vec<u8>, ram_size: usize) -> Self {
+        Self {
+            header,
+            mbc,
+            battery,
+            rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            mbc1_ram_mode: false,
+            rtc_selected: false,
+        }
+    }
+
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.header
+    }
+
+    pub fn mbc(&self) -> Mbc {
+        self.mbc
+    }
+
+    /// True if the cartridge RAM is battery backed, and so worth saving.
+    pub fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    /// The ROM bank currently mapped into 0x4000-0x7FFF.
+    pub fn rom_bank(&self) -> usize {
+        self.effective_rom_bank()
+    }
+
+    /// The RAM bank currently mapped into 0xA000-0xBFFF.
+    pub fn ram_bank(&self) -> usize {
+        match self.mbc {
+            Mbc::Mbc1 if !self.mbc1_ram_mode => 0,
+            Mbc::Mbc2 => 0,
+            _ => self.ram_bank,
+        }
+    }
+
+    /// The whole external RAM, for saving.
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    /// Restores the external RAM from a save.
+    pub fn load_ram(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() != self.ram.len() {
+            return Err(anyhow!("Save is {} bytes, but the cartridge has {} bytes of RAM", data.len(), self.ram.len()))
+        }
+        self.ram.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        self.rom.len().div_ceil(ROM_BANK_SIZE)
+    }
+
+    fn effective_rom_bank(&self) -> usize {
+        let bank = match self.mbc {
+            Mbc::Mbc1 => self.rom_bank | self.ram_bank << 5,
+            _ => self.rom_bank,
+        };
+        bank % self.rom_bank_count()
+    }
+
+    /// The ROM bank mapped into 0x0000-0x3FFF, which is only ever not 0 for MBC1 in mode 1.
+    fn low_rom_bank(&self) -> usize {
+        match self.mbc {
+            Mbc::Mbc1 if self.mbc1_ram_mode => (self.ram_bank << 5) % self.rom_bank_count(),
+            _ => 0,
+        }
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        (self.ram_bank() * RAM_BANK_SIZE + (address as usize - 0xA000)) % self.ram.len()
+    }
+
+    /// Reads from 0x0000-0x7FFF or 0xA000-0xBFFF.
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => {
+                let offset = self.low_rom_bank() * ROM_BANK_SIZE + address as usize;
+                self.rom.get(offset).copied().unwrap_or(0xFF)
+            },
+            0x4000..=0x7FFF => {
+                let offset = self.effective_rom_bank() * ROM_BANK_SIZE + (address as usize - ROM_BANK_SIZE);
+                self.rom.get(offset).copied().unwrap_or(0xFF)
+            },
+            // The RTC itself isn't emulated, so its registers read as nothing
+            0xA000..=0xBFFF if self.rtc_selected => 0xFF,
+            0xA000..=0xBFFF if self.ram_enabled && !self.ram.is_empty() => {
+                let data = self.ram[self.ram_offset(address)];
+                // MBC2 RAM is only 4 bits wide
+                if self.mbc == Mbc::Mbc2 { data | 0xF0 } else { data }
+            },
+            _ => 0xFF,
+        }
+    }
+
+    /// Writes to 0x0000-0x7FFF (MBC registers) or 0xA000-0xBFFF (external RAM).
+    pub fn write(&mut self, address: u16, data: u8) {
+        match (self.mbc, address) {
+            (_, 0xA000..=0xBFFF) if self.ram_enabled && !self.rtc_selected && !self.ram.is_empty() => {
+                let offset = self.ram_offset(address);
+                self.ram[offset] = data;
+            },
+            (Mbc::None, _) | (_, 0xA000..=0xBFFF) => (),
+
+            (Mbc::Mbc1, 0x0000..=0x1FFF) => self.ram_enabled = data & 0x0F == 0x0A,
+            (Mbc::Mbc1, 0x2000..=0x3FFF) => self.rom_bank = (data as usize & 0x1F).max(1),
+            (Mbc::Mbc1, 0x4000..=0x5FFF) => self.ram_bank = data as usize & 0x03,
+            (Mbc::Mbc1, 0x6000..=0x7FFF) => self.mbc1_ram_mode = data & 0x01 != 0,
+
+            // Address bit 8 picks between the RAM enable and the ROM bank
+            (Mbc::Mbc2, 0x0000..=0x3FFF) if address & 0x0100 == 0 => self.ram_enabled = data & 0x0F == 0x0A,
+            (Mbc::Mbc2, 0x0000..=0x3FFF) => self.rom_bank = (data as usize & 0x0F).max(1),
+
+            (Mbc::Mbc3, 0x0000..=0x1FFF) => self.ram_enabled = data & 0x0F == 0x0A,
+            (Mbc::Mbc3, 0x2000..=0x3FFF) => self.rom_bank = (data as usize & 0x7F).max(1),
+            // Values 0x08-0x0C select RTC registers, which aren't emulated, but still have to keep writes out of RAM
+            (Mbc::Mbc3, 0x4000..=0x5FFF) => {
+                self.rtc_selected = (0x08..=0x0C).contains(&data);
+                if !self.rtc_selected {
+                    self.ram_bank = data as usize & 0x03;
+                }
+            },
+
+            (Mbc::Mbc5, 0x0000..=0x1FFF) => self.ram_enabled = data & 0x0F == 0x0A,
+            (Mbc::Mbc5, 0x2000..=0x2FFF) => self.rom_bank = (self.rom_bank & 0x100) | data as usize,
+            (Mbc::Mbc5, 0x3000..=0x3FFF) => self.rom_bank = (self.rom_bank & 0xFF) | (data as usize & 0x01) << 8,
+            (Mbc::Mbc5, 0x4000..=0x5FFF) => self.ram_bank = data as usize & 0x0F,
+
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ROM with the given header codes, where each bank starts with its own number.
+    fn rom(cartridge_type: u8, size_code: u8, ram_code: u8) -> Vec<u8> {
+        let mut rom = vec![0; (32 * 1024) << size_code];
+        for (bank, chunk) in rom.chunks_mut(ROM_BANK_SIZE).enumerate() {
+            chunk[0] = bank as u8;
+        }
+        rom[0x0147] = cartridge_type;
+        rom[0x0148] = size_code;
+        rom[0x0149] = ram_code;
+        rom
+    }
+
+    #[test]
+    fn mbc1_upper_bits_bank_rom_in_both_modes() {
+        let mut cartridge = Cartridge::load(rom(0x01, 0x05, 0x00)).unwrap();
+        cartridge.write(0x2000, 0x02);
+        cartridge.write(0x4000, 0x01);
+        assert_eq!(cartridge.read(0x0000), 0x00);
+        assert_eq!(cartridge.read(0x4000), 0x22);
+
+        cartridge.write(0x6000, 0x01);
+        assert_eq!(cartridge.read(0x0000), 0x20);
+        assert_eq!(cartridge.read(0x4000), 0x22);
+
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(cartridge.read(0x4000), 0x21);
+    }
+
+    #[test]
+    fn mbc3_rtc_select_keeps_writes_out_of_ram() {
+        let mut cartridge = Cartridge::load(rom(0x13, 0x02, 0x03)).unwrap();
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x4000, 0x00);
+        cartridge.write(0xA000, 0x11);
+
+        cartridge.write(0x4000, 0x08);
+        cartridge.write(0xA000, 0x99);
+        assert_eq!(cartridge.read(0xA000), 0xFF);
+
+        cartridge.write(0x4000, 0x00);
+        assert_eq!(cartridge.read(0xA000), 0x11);
+        assert_eq!(cartridge.ram()[0], 0x11);
+    }
+}
@@ -0,0 +1,480 @@
+//! Disassembler
+//!
+//! Decodes instructions into a structured form: mnemonic, operands, length, and how many M-cycles they take.
+//! Displaying one gives RGBDS-style assembly, e.g. `ld a, [hl+]` or `jr nz, $0150`.
+//! Opcodes are picked apart by their bit fields (`xx yyy zzz`), the same way the CPU decodes them,
+//! CB-prefixed ones included.
+
+use std::fmt;
+use crate::registers::{Reg8, Reg16};
+
+const R: [Operand; 8] = [
+    Operand::Reg8(Reg8::B),
+    Operand::Reg8(Reg8::C),
+    Operand::Reg8(Reg8::D),
+    Operand::Reg8(Reg8::E),
+    Operand::Reg8(Reg8::H),
+    Operand::Reg8(Reg8::L),
+    Operand::Indirect(Reg16::HL),
+    Operand::Reg8(Reg8::A),
+];
+const RP: [Reg16; 4] = [Reg16::BC, Reg16::DE, Reg16::HL, Reg16::SP];
+const RP2: [Reg16; 4] = [Reg16::BC, Reg16::DE, Reg16::HL, Reg16::AF];
+const CC: [Condition; 4] = [Condition::NZ, Condition::Z, Condition::NC, Condition::C];
+const ALU: [Mnemonic; 8] = [
+    Mnemonic::Add, Mnemonic::Adc, Mnemonic::Sub, Mnemonic::Sbc,
+    Mnemonic::And, Mnemonic::Xor, Mnemonic::Or, Mnemonic::Cp,
+];
+const ROT: [Mnemonic; 8] = [
+    Mnemonic::Rlc, Mnemonic::Rrc, Mnemonic::Rl, Mnemonic::Rr,
+    Mnemonic::Sla, Mnemonic::Sra, Mnemonic::Swap, Mnemonic::Srl,
+];
+const MISC: [Mnemonic; 8] = [
+    Mnemonic::Rlca, Mnemonic::Rrca, Mnemonic::Rla, Mnemonic::Rra,
+    Mnemonic::Daa, Mnemonic::Cpl, Mnemonic::Scf, Mnemonic::Ccf,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mnemonic {
+    Nop, Stop, Halt, Di, Ei,
+    Ld, Ldh, Push, Pop,
+    Inc, Dec, Add, Adc, Sub, Sbc, And, Xor, Or, Cp,
+    Rlca, Rrca, Rla, Rra, Daa, Cpl, Scf, Ccf,
+    Jr, Jp, Call, Ret, Reti, Rst,
+    Rlc, Rrc, Rl, Rr, Sla, Sra, Swap, Srl, Bit, Res, Set,
+    /// Not an instruction at all, shown as the byte it is with `db`.
+    Illegal,
+}
+
+impl Mnemonic {
+    /// The mnemonic as RGBDS writes it, in lowercase.
+    pub fn name(self) -> &'static str {
+        match self {
+            Mnemonic::Nop => "nop",
+            Mnemonic::Stop => "stop",
+            Mnemonic::Halt => "halt",
+            Mnemonic::Di => "di",
+            Mnemonic::Ei => "ei",
+            Mnemonic::Ld => "ld",
+            Mnemonic::Ldh => "ldh",
+            Mnemonic::Push => "push",
+            Mnemonic::Pop => "pop",
+            Mnemonic::Inc => "inc",
+            Mnemonic::Dec => "dec",
+            Mnemonic::Add => "add",
+            Mnemonic::Adc => "adc",
+            Mnemonic::Sub => "sub",
+            Mnemonic::Sbc => "sbc",
+            Mnemonic::And => "and",
+            Mnemonic::Xor => "xor",
+            Mnemonic::Or => "or",
+            Mnemonic::Cp => "cp",
+            Mnemonic::Rlca => "rlca",
+            Mnemonic::Rrca => "rrca",
+            Mnemonic::Rla => "rla",
+            Mnemonic::Rra => "rra",
+            Mnemonic::Daa => "daa",
+            Mnemonic::Cpl => "cpl",
+            Mnemonic::Scf => "scf",
+            Mnemonic::Ccf => "ccf",
+            Mnemonic::Jr => "jr",
+            Mnemonic::Jp => "jp",
+            Mnemonic::Call => "call",
+            Mnemonic::Ret => "ret",
+            Mnemonic::Reti => "reti",
+            Mnemonic::Rst => "rst",
+            Mnemonic::Rlc => "rlc",
+            Mnemonic::Rrc => "rrc",
+            Mnemonic::Rl => "rl",
+            Mnemonic::Rr => "rr",
+            Mnemonic::Sla => "sla",
+            Mnemonic::Sra => "sra",
+            Mnemonic::Swap => "swap",
+            Mnemonic::Srl => "srl",
+            Mnemonic::Bit => "bit",
+            Mnemonic::Res => "res",
+            Mnemonic::Set => "set",
+            Mnemonic::Illegal => "db",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    NZ,
+    Z,
+    NC,
+    C,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Reg8(Reg8),
+    Reg16(Reg16),
+    /// Memory at the address in a register pair, e.g. `[hl]`.
+    Indirect(Reg16),
+    /// `[hl+]`, HL incremented afterwards.
+    HlIncrement,
+    /// `[hl-]`, HL decremented afterwards.
+    HlDecrement,
+    /// `[c]`, the IO register at 0xFF00 + C.
+    HighC,
+    Imm8(u8),
+    Imm16(u16),
+    /// Memory at a fixed address, e.g. `[$C000]`.
+    Address(u16),
+    /// IO register or HRAM at 0xFF00 + the value, as `ldh` uses it.
+    HighAddress(u8),
+    /// A signed value added to SP.
+    Offset(i8),
+    /// `sp` plus a signed offset, as `ld hl, sp+e8` uses it.
+    SpOffset(i8),
+    Condition(Condition),
+    /// Bit number for `bit`, `res` and `set`.
+    Bit(u8),
+    /// Where a jump or call goes, already worked out for relative jumps.
+    Target(u16),
+    /// Where an `rst` goes.
+    Vector(u8),
+}
+
+/// One decoded instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub mnemonic: Mnemonic,
+    pub operands: Vec<Operand>,
+    /// Bytes taken up, including the opcode and any CB prefix.
+    pub length: u16,
+    /// M-cycles taken, or taken when the condition holds for conditional jumps, calls and returns.
+    pub cycles: u8,
+    /// M-cycles taken when a condition doesn't hold, for instructions that have one.
+    pub cycles_not_taken: Option<u8>,
+}
+
+impl Instruction {
+    /// Decodes the instruction starting with `bytes[0]` at `address`. Three bytes is enough for any instruction,
+    /// and whatever follows a shorter one is ignored.
+    pub fn decode(address: u16, bytes: [u8; 3]) -> Self {
+        let [opcode, low, high] = bytes;
+        let n8 = Operand::Imm8(low);
+        let n16 = u16::from_le_bytes([low, high]);
+        let e8 = low as i8;
+        // Relative jumps count from the end of the 2-byte instruction
+        let relative = Operand::Target(address.wrapping_add(2).wrapping_add(e8 as u16));
+
+        let x = opcode >> 6;
+        let y = (opcode >> 3 & 7) as usize;
+        let z = (opcode & 7) as usize;
+        let p = y >> 1;
+        let q = y & 1;
+        // Most things cost an extra cycle or two when they go through [hl]
+        let hl_cost = |operand: Operand, extra: u8| if operand == Operand::Indirect(Reg16::HL) { extra } else { 0 };
+
+        use Mnemonic::*;
+        let instruction = |mnemonic, operands: &[Operand], length, cycles| Self {
+            mnemonic,
+            operands: operands.to_vec(),
+            length,
+            cycles,
+            cycles_not_taken: None,
+        };
+        let conditional = |mnemonic, operands: &[Operand], length, cycles, not_taken| Self {
+            cycles_not_taken: Some(not_taken),
+            ..instruction(mnemonic, operands, length, cycles)
+        };
+        let a = Operand::Reg8(Reg8::A);
+
+        match (x, z) {
+            (0, 0) => match y {
+                0 => instruction(Nop, &[], 1, 1),
+                1 => instruction(Ld, &[Operand::Address(n16), Operand::Reg16(Reg16::SP)], 3, 5),
+                2 => instruction(Stop, &[], 2, 1),
+                3 => instruction(Jr, &[relative], 2, 3),
+                _ => conditional(Jr, &[Operand::Condition(CC[y - 4]), relative], 2, 3, 2),
+            },
+            (0, 1) if q == 0 => instruction(Ld, &[Operand::Reg16(RP[p]), Operand::Imm16(n16)], 3, 3),
+            (0, 1) => instruction(Add, &[Operand::Reg16(Reg16::HL), Operand::Reg16(RP[p])], 1, 2),
+            (0, 2) => {
+                let indirect = [Operand::Indirect(Reg16::BC), Operand::Indirect(Reg16::DE), Operand::HlIncrement, Operand::HlDecrement][p];
+                if q == 0 {
+                    instruction(Ld, &[indirect, a], 1, 2)
+                } else {
+                    instruction(Ld, &[a, indirect], 1, 2)
+                }
+            },
+            (0, 3) => instruction(if q == 0 { Inc } else { Dec }, &[Operand::Reg16(RP[p])], 1, 2),
+            (0, 4) => instruction(Inc, &[R[y]], 1, 1 + hl_cost(R[y], 2)),
+            (0, 5) => instruction(Dec, &[R[y]], 1, 1 + hl_cost(R[y], 2)),
+            (0, 6) => instruction(Ld, &[R[y], n8], 2, 2 + hl_cost(R[y], 1)),
+            (0, _) => instruction(MISC[y], &[], 1, 1),
+            (1, 6) if y == 6 => instruction(Halt, &[], 1, 1),
+            (1, _) => instruction(Ld, &[R[y], R[z]], 1, 1 + hl_cost(R[y], 1) + hl_cost(R[z], 1)),
+            (2, _) => alu(ALU[y], R[z], 1, 1 + hl_cost(R[z], 1)),
+            (3, 0) => match y {
+                0..=3 => conditional(Ret, &[Operand::Condition(CC[y])], 1, 5, 2),
+                4 => instruction(Ldh, &[Operand::HighAddress(low), a], 2, 3),
+                5 => instruction(Add, &[Operand::Reg16(Reg16::SP), Operand::Offset(e8)], 2, 4),
+                6 => instruction(Ldh, &[a, Operand::HighAddress(low)], 2, 3),
+                _ => instruction(Ld, &[Operand::Reg16(Reg16::HL), Operand::SpOffset(e8)], 2, 3),
+            },
+            (3, 1) if q == 0 => instruction(Pop, &[Operand::Reg16(RP2[p])], 1, 3),
+            (3, 1) => match p {
+                0 => instruction(Ret, &[], 1, 4),
+                1 => instruction(Reti, &[], 1, 4),
+                2 => instruction(Jp, &[Operand::Reg16(Reg16::HL)], 1, 1),
+                _ => instruction(Ld, &[Operand::Reg16(Reg16::SP), Operand::Reg16(Reg16::HL)], 1, 2),
+            },
+            (3, 2) => match y {
+                0..=3 => conditional(Jp, &[Operand::Condition(CC[y]), Operand::Target(n16)], 3, 4, 3),
+                4 => instruction(Ldh, &[Operand::HighC, a], 1, 2),
+                5 => instruction(Ld, &[Operand::Address(n16), a], 3, 4),
+                6 => instruction(Ldh, &[a, Operand::HighC], 1, 2),
+                _ => instruction(Ld, &[a, Operand::Address(n16)], 3, 4),
+            },
+            (3, 3) => match y {
+                0 => instruction(Jp, &[Operand::Target(n16)], 3, 4),
+                1 => Self::decode_cb(low),
+                6 => instruction(Di, &[], 1, 1),
+                7 => instruction(Ei, &[], 1, 1),
+                _ => instruction(Illegal, &[Operand::Imm8(opcode)], 1, 1),
+            },
+            (3, 4) if y < 4 => conditional(Call, &[Operand::Condition(CC[y]), Operand::Target(n16)], 3, 6, 3),
+            (3, 5) if q == 0 => instruction(Push, &[Operand::Reg16(RP2[p])], 1, 4),
+            (3, 5) if p == 0 => instruction(Call, &[Operand::Target(n16)], 3, 6),
+            (3, 6) => alu(ALU[y], n8, 2, 2),
+            (3, 7) => instruction(Rst, &[Operand::Vector(y as u8 * 8)], 1, 4),
+            _ => instruction(Illegal, &[Operand::Imm8(opcode)], 1, 1),
+        }
+    }
+
+    /// Decodes the byte after a CB prefix.
+    fn decode_cb(opcode: u8) -> Self {
+        let y = opcode >> 3 & 7;
+        let target = R[(opcode & 7) as usize];
+        let through_hl = target == Operand::Indirect(Reg16::HL);
+        let (mnemonic, operands) = match opcode >> 6 {
+            0 => (ROT[y as usize], vec![target]),
+            1 => (Mnemonic::Bit, vec![Operand::Bit(y), target]),
+            2 => (Mnemonic::Res, vec![Operand::Bit(y), target]),
+            _ => (Mnemonic::Set, vec![Operand::Bit(y), target]),
+        };
+        // BIT only reads [hl], so it skips the write back
+        let cycles = match (through_hl, mnemonic) {
+            (false, _) => 2,
+            (true, Mnemonic::Bit) => 3,
+            (true, _) => 4,
+        };
+        Self { mnemonic, operands, length: 2, cycles, cycles_not_taken: None }
+    }
+
+    /// True for anything that can send PC somewhere other than the next instruction.
+    pub fn is_branch(&self) -> bool {
+        matches!(self.mnemonic, Mnemonic::Jr | Mnemonic::Jp | Mnemonic::Call | Mnemonic::Ret | Mnemonic::Reti | Mnemonic::Rst)
+    }
+
+    /// True for instructions that push a return address and jump, coming back afterwards.
+    pub fn is_call(&self) -> bool {
+        matches!(self.mnemonic, Mnemonic::Call | Mnemonic::Rst)
+    }
+
+    /// True for returns, conditional or not.
+    pub fn is_return(&self) -> bool {
+        matches!(self.mnemonic, Mnemonic::Ret | Mnemonic::Reti)
+    }
+
+    /// Formats it with jump targets and memory addresses shown as labels where `label` has one for them.
+    pub fn to_string_with_labels(&self, label: impl Fn(u16) -> Option<String>) -> String {
+        let operands: Vec<String> = self.operands.iter().map(|operand| {
+            let named = match *operand {
+                Operand::Target(address) => label(address),
+                Operand::Address(address) => label(address).map(|label| format!("[{}]", label)),
+                Operand::HighAddress(low) => label(0xFF00 | low as u16).map(|label| format!("[{}]", label)),
+                _ => None,
+            };
+            named.unwrap_or_else(|| operand.to_string())
+        }).collect();
+        if operands.is_empty() {
+            self.mnemonic.name().to_string()
+        } else {
+            format!("{} {}", self.mnemonic.name(), operands.join(", "))
+        }
+    }
+}
+
+/// Prints as RGBDS assembly.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mnemonic.name())?;
+        for (index, operand) in self.operands.iter().enumerate() {
+            write!(f, "{}{}", if index == 0 { " " } else { ", " }, operand)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Operand::Reg8(register) => write!(f, "{}", reg8_name(register)),
+            Operand::Reg16(register) => write!(f, "{}", reg16_name(register)),
+            Operand::Indirect(register) => write!(f, "[{}]", reg16_name(register)),
+            Operand::HlIncrement => write!(f, "[hl+]"),
+            Operand::HlDecrement => write!(f, "[hl-]"),
+            Operand::HighC => write!(f, "[c]"),
+            Operand::Imm8(value) => write!(f, "${:02X}", value),
+            Operand::Imm16(value) | Operand::Target(value) => write!(f, "${:04X}", value),
+            Operand::Address(address) => write!(f, "[${:04X}]", address),
+            Operand::HighAddress(low) => write!(f, "[$FF{:02X}]", low),
+            Operand::Offset(offset) => write!(f, "{}", offset),
+            Operand::SpOffset(offset) if offset < 0 => write!(f, "sp{}", offset),
+            Operand::SpOffset(offset) => write!(f, "sp+{}", offset),
+            Operand::Condition(condition) => write!(f, "{}", match condition {
+                Condition::NZ => "nz",
+                Condition::Z => "z",
+                Condition::NC => "nc",
+                Condition::C => "c",
+            }),
+            Operand::Bit(bit) => write!(f, "{}", bit),
+            Operand::Vector(vector) => write!(f, "${:02X}", vector),
+        }
+    }
+}
+
+fn alu(mnemonic: Mnemonic, operand: Operand, length: u16, cycles: u8) -> Instruction {
+    // RGBDS wants the `a` spelled out for these three, and allows leaving it off for the rest
+    let operands = match mnemonic {
+        Mnemonic::Add | Mnemonic::Adc | Mnemonic::Sbc => vec![Operand::Reg8(Reg8::A), operand],
+        _ => vec![operand],
+    };
+    Instruction { mnemonic, operands, length, cycles, cycles_not_taken: None }
+}
+
+fn reg8_name(register: Reg8) -> &'static str {
+    match register {
+        Reg8::A => "a",
+        Reg8::F => "f",
+        Reg8::B => "b",
+        Reg8::C => "c",
+        Reg8::D => "d",
+        Reg8::E => "e",
+        Reg8::H => "h",
+        Reg8::L => "l",
+    }
+}
+
+fn reg16_name(register: Reg16) -> &'static str {
+    match register {
+        Reg16::AF => "af",
+        Reg16::BC => "bc",
+        Reg16::DE => "de",
+        Reg16::HL => "hl",
+        Reg16::SP => "sp",
+        Reg16::PC => "pc",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Instruction {
+        let mut padded = [0; 3];
+        padded[..bytes.len()].copy_from_slice(bytes);
+        Instruction::decode(0x0150, padded)
+    }
+
+    #[test]
+    fn operand_formats() {
+        let cases: [(&[u8], &str, u16); 22] = [
+            (&[0x00], "nop", 1),
+            (&[0x01, 0x34, 0x12], "ld bc, $1234", 3),
+            (&[0x08, 0x00, 0xC0], "ld [$C000], sp", 3),
+            (&[0x22], "ld [hl+], a", 1),
+            (&[0x3A], "ld a, [hl-]", 1),
+            (&[0x0A], "ld a, [bc]", 1),
+            (&[0x36, 0x7F], "ld [hl], $7F", 2),
+            (&[0x10, 0x00], "stop", 2),
+            (&[0x18, 0xFE], "jr $0150", 2),
+            (&[0x20, 0x05], "jr nz, $0157", 2),
+            (&[0x76], "halt", 1),
+            (&[0x78], "ld a, b", 1),
+            (&[0x86], "add a, [hl]", 1),
+            (&[0xA9], "xor c", 1),
+            (&[0xE0, 0x44], "ldh [$FF44], a", 2),
+            (&[0xF2], "ldh a, [c]", 1),
+            (&[0xE8, 0xF8], "add sp, -8", 2),
+            (&[0xF8, 0x02], "ld hl, sp+2", 2),
+            (&[0xF8, 0x80], "ld hl, sp-128", 2),
+            (&[0xEA, 0x00, 0xD0], "ld [$D000], a", 3),
+            (&[0xE9], "jp hl", 1),
+            (&[0xFF], "rst $38", 1),
+        ];
+        for (bytes, text, length) in cases {
+            let instruction = decode(bytes);
+            assert_eq!(instruction.to_string(), text, "{:02X?}", bytes);
+            assert_eq!(instruction.length, length, "{}", text);
+        }
+    }
+
+    #[test]
+    fn cb_prefix() {
+        let cases: [(u8, &str, u8); 8] = [
+            (0x00, "rlc b", 2),
+            (0x1F, "rr a", 2),
+            (0x36, "swap [hl]", 4),
+            (0x3E, "srl [hl]", 4),
+            (0x7C, "bit 7, h", 2),
+            (0x46, "bit 0, [hl]", 3),
+            (0x9E, "res 3, [hl]", 4),
+            (0xFF, "set 7, a", 2),
+        ];
+        for (opcode, text, cycles) in cases {
+            let instruction = decode(&[0xCB, opcode]);
+            assert_eq!(instruction.to_string(), text);
+            assert_eq!(instruction.length, 2, "{}", text);
+            assert_eq!(instruction.cycles, cycles, "{}", text);
+        }
+    }
+
+    #[test]
+    fn illegal_opcodes() {
+        for opcode in [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD] {
+            let instruction = decode(&[opcode]);
+            assert_eq!(instruction.mnemonic, Mnemonic::Illegal);
+            assert_eq!(instruction.to_string(), format!("db ${:02X}", opcode));
+            assert_eq!(instruction.length, 1);
+        }
+        let legal = (0..=255).filter(|&opcode| decode(&[opcode]).mnemonic != Mnemonic::Illegal).count();
+        assert_eq!(legal, 245);
+    }
+
+    #[test]
+    fn cycle_counts() {
+        // Taken and not taken for conditional branches, None for everything else
+        let cases: [(&[u8], u8, Option<u8>); 12] = [
+            (&[0x18, 0x00], 3, None),
+            (&[0x28, 0x00], 3, Some(2)),
+            (&[0xC3, 0x00, 0x00], 4, None),
+            (&[0xDA, 0x00, 0x00], 4, Some(3)),
+            (&[0xCD, 0x00, 0x00], 6, None),
+            (&[0xC4, 0x00, 0x00], 6, Some(3)),
+            (&[0xC9], 4, None),
+            (&[0xD0], 5, Some(2)),
+            (&[0xD9], 4, None),
+            (&[0xC7], 4, None),
+            (&[0x34], 3, None),
+            (&[0x70], 2, None),
+        ];
+        for (bytes, cycles, not_taken) in cases {
+            let instruction = decode(bytes);
+            assert_eq!((instruction.cycles, instruction.cycles_not_taken), (cycles, not_taken), "{}", instruction);
+        }
+    }
+
+    #[test]
+    fn labels_replace_addresses() {
+        let label = |address| (address == 0xFF44).then(|| "rLY".to_string());
+        assert_eq!(decode(&[0xF0, 0x44]).to_string_with_labels(label), "ldh a, [rLY]");
+        assert_eq!(decode(&[0xF0, 0x45]).to_string_with_labels(label), "ldh a, [$FF45]");
+    }
+}
@@ -0,0 +1,198 @@
+//! GBS
+//!
+//! Plays Game Boy Sound rips: the sound driver and music data pulled out of a game, with a small header
+//! saying where to load it and which routines to call. The player sets up just enough of a system around
+//! the CPU and APU to run the driver: the data mapped in as banked ROM with some RAM, RST vectors redirected to it,
+//! and the play routine called on every VBlank or timer overflow, depending on what the header asks for.
+
+use anyhow::{anyhow, Result};
+use crate::{Model, CPU};
+use crate::cartridge::{Cartridge, Mbc};
+use crate::apu::{NR50_ADDR, NR51_ADDR, NR52_ADDR};
+use crate::timer::{TAC_ADDR, TMA_ADDR};
+use crate::scheduler::{DOTS_PER_FRAME, DOTS_PER_SECOND};
+
+const HEADER_SIZE: usize = 0x70;
+
+// Routines return into an endless `JR -2` loop here, which is how the player knows they're done
+const IDLE_ADDR: u16 = 0x00F0;
+const IDLE_LOOP: [u8; 2] = [0x18, 0xFE];
+
+/// Input clocks for the timer, in Hz, selected by the low bits of TAC.
+const TIMER_CLOCKS: [u64; 4] = [4096, 262144, 65536, 16384];
+
+/// Everything in the 0x70 byte header at the start of a GBS file.
+#[derive(Clone, Debug)]
+pub struct GbsHeader {
+    pub version: u8,
+    pub song_count: u8,
+    /// The song to start on, counting from 1.
+    pub first_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+impl GbsHeader {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_SIZE {
+            return Err(anyhow!("File is too short to be a GBS file: {} bytes", data.len()))
+        }
+        if &data[0..3] != b"GBS" {
+            return Err(anyhow!("Not a GBS file, missing the GBS signature"))
+        }
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let text = |offset: usize| {
+            let field = &data[offset..offset + 32];
+            let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..end]).into_owned()
+        };
+        let header = Self {
+            version: data[0x03],
+            song_count: data[0x04],
+            first_song: data[0x05],
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: data[0x0E],
+            timer_control: data[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+        };
+        if header.load_address < 0x0400 || header.load_address >= 0x8000 {
+            return Err(anyhow!("Unsupported GBS load address: {:#06X}", header.load_address))
+        }
+        Ok(header)
+    }
+
+    /// True if the play routine runs off the timer interrupt instead of VBlank.
+    pub fn uses_timer(&self) -> bool {
+        self.timer_control & 0x04 != 0
+    }
+
+    /// True if the rip wants the CGB's double-speed mode.
+    pub fn double_speed(&self) -> bool {
+        self.timer_control & 0x80 != 0
+    }
+
+    /// Time between calls to the play routine, in dots.
+    pub fn play_interval(&self) -> u64 {
+        if !self.uses_timer() {
+            return DOTS_PER_FRAME
+        }
+        let clock = TIMER_CLOCKS[(self.timer_control & 0x03) as usize];
+        let interval = DOTS_PER_SECOND * (256 - self.timer_modulo as u64) / clock;
+        if self.double_speed() { interval / 2 } else { interval }
+    }
+}
+
+pub struct GbsPlayer {
+    header: GbsHeader,
+    rom: Vec<u8>,
+    cpu: CPU,
+    song: u8,
+    next_play: u64,
+}
+
+impl GbsPlayer {
+    /// Loads a GBS file and starts its first song.
+    pub fn new(data: &[u8]) -> Result<Self> {
+        let header = GbsHeader::parse(data)?;
+
+        // Lay the data out as a ROM image, the same way the original cartridge had it
+        let load = header.load_address as usize;
+        let mut rom = vec![0; load + data.len() - HEADER_SIZE];
+        rom[load..].copy_from_slice(&data[HEADER_SIZE..]);
+        // RST vectors get redirected into the rip, since its code expects them at the load address
+        for vector in (0x00..0x40).step_by(8) {
+            let [low, high] = (header.load_address + vector as u16).to_le_bytes();
+            rom[vector..vector + 3].copy_from_slice(&[0xC3, low, high]); // JP imm16
+        }
+        rom[IDLE_ADDR as usize..IDLE_ADDR as usize + 2].copy_from_slice(&IDLE_LOOP);
+
+        let first_song = header.first_song.max(1);
+        let mut player = Self {
+            header,
+            rom,
+            cpu: CPU::new(),
+            song: first_song,
+            next_play: 0,
+        };
+        player.start_song(first_song)?;
+        Ok(player)
+    }
+
+    pub fn header(&self) -> &GbsHeader {
+        &self.header
+    }
+
+    /// The song currently playing, counting from 1.
+    pub fn song(&self) -> u8 {
+        self.song
+    }
+
+    /// The system underneath, for getting at the audio output.
+    pub fn cpu(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    /// Resets the whole system and starts a song, counting from 1.
+    /// Anything set up on the CPU (sample rate, recordings) has to be set up again afterwards.
+    pub fn start_song(&mut self, song: u8) -> Result<()> {
+        if song == 0 || song > self.header.song_count {
+            return Err(anyhow!("Song {} is out of range, this file has {} songs", song, self.header.song_count))
+        }
+        self.song = song;
+
+        // Double speed needs a CGB, anything else plays fine on either
+        let model = if self.header.double_speed() { Model::Cgb } else { Model::Dmg };
+        let mut cpu = CPU::with_model(model);
+        cpu.memory.load_cartridge(Cartridge::from_image(self.rom.clone(), Mbc::Mbc5));
+        if self.header.double_speed() {
+            cpu.scheduler.switch_speed();
+            cpu.memory.set_double_speed(true);
+        }
+
+        // Sound on at full volume, then the timer set up the way the rip asks for
+        cpu.memory.write_byte(NR52_ADDR, 0x80)?;
+        cpu.memory.write_byte(NR51_ADDR, 0xFF)?;
+        cpu.memory.write_byte(NR50_ADDR, 0x77)?;
+        cpu.memory.write_byte(TMA_ADDR, self.header.timer_modulo)?;
+        cpu.memory.write_byte(TAC_ADDR, self.header.timer_control)?;
+
+        // Call the init routine with the song index (counting from 0) in A
+        cpu.memory.stack_pointer = self.header.stack_pointer;
+        cpu.memory.push_stack(IDLE_ADDR)?;
+        cpu.memory.program_counter = self.header.init_address;
+        cpu.af.high = song - 1;
+
+        self.cpu = cpu;
+        self.next_play = 0;
+        Ok(())
+    }
+
+    /// Runs the driver for some number of dots, calling the play routine whenever it's due.
+    /// A call that runs long pushes back the following ones, just like a slow interrupt handler would.
+    pub fn run(&mut self, dots: u64) -> Result<()> {
+        let end = self.cpu.elapsed_dots() + dots;
+        while self.cpu.elapsed_dots() < end {
+            let idle = self.cpu.memory.program_counter == IDLE_ADDR;
+            let now = self.cpu.elapsed_dots();
+            if idle && now >= self.next_play {
+                self.cpu.memory.push_stack(IDLE_ADDR)?;
+                self.cpu.memory.program_counter = self.header.play_address;
+                self.next_play = (self.next_play + self.header.play_interval()).max(now);
+            }
+            self.cpu.cycle()?;
+        }
+        Ok(())
+    }
+}
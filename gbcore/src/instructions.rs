@@ -7,13 +7,10 @@
 //! Functions return the passed time in machine cycles.
 
 use crate::CPU;
+use crate::scheduler::SPEED_SWITCH_CYCLES;
 use anyhow::{anyhow, Result};
 use bitmatch::bitmatch;
-
-const ZERO_FLAG: u8 = 1 << 7;
-const SUB_FLAG: u8 = 1 << 6;
-const HALF_CARRY_FLAG: u8 = 1 << 5;
-const CARRY_FLAG: u8 = 1 << 4;
+use crate::registers::{CARRY_FLAG, HALF_CARRY_FLAG, SUB_FLAG, ZERO_FLAG};
 
 /// Block 0 contains an assortment of instructions.
 #[bitmatch]
@@ -60,14 +57,14 @@ pub(super) fn block0(cpu: &mut CPU, opcode: u8) -> Result<i32> {
 
         "00ss1010" => { // LD a, [r16mem]
             match s {
-                0 => cpu.af.set_pair(cpu.memory.read_two_bytes(cpu.bc.get_pair())?),
-                1 => cpu.af.set_pair(cpu.memory.read_two_bytes(cpu.de.get_pair())?),
+                0 => cpu.af.high = cpu.memory.read_byte(cpu.bc.get_pair())?,
+                1 => cpu.af.high = cpu.memory.read_byte(cpu.de.get_pair())?,
                 2 => {
-                    cpu.af.set_pair(cpu.memory.read_two_bytes(cpu.hl.get_pair())?);
+                    cpu.af.high = cpu.memory.read_byte(cpu.hl.get_pair())?;
                     cpu.hl.inc_pair();
                 },
                 3 => {
-                    cpu.af.set_pair(cpu.memory.read_two_bytes(cpu.hl.get_pair())?);
+                    cpu.af.high = cpu.memory.read_byte(cpu.hl.get_pair())?;
                     cpu.hl.dec_pair();
                 },
                 _ => return Err(anyhow!("Somehow extracted the value {} from {} bits. Impossible!", s, 2))
@@ -85,7 +82,7 @@ pub(super) fn block0(cpu: &mut CPU, opcode: u8) -> Result<i32> {
                 0 => cpu.bc.inc_pair(),
                 1 => cpu.de.inc_pair(),
                 2 => cpu.hl.inc_pair(),
-                3 => cpu.memory.stack_pointer += 1,
+                3 => cpu.memory.stack_pointer = cpu.memory.stack_pointer.wrapping_add(1),
                 _ => return Err(anyhow!("Somehow extracted the value {} from {} bits. Impossible!", 0, 2))
             }
         },
@@ -95,7 +92,7 @@ pub(super) fn block0(cpu: &mut CPU, opcode: u8) -> Result<i32> {
                 0 => cpu.bc.dec_pair(),
                 1 => cpu.de.dec_pair(),
                 2 => cpu.hl.dec_pair(),
-                3 => cpu.memory.stack_pointer -= 1,
+                3 => cpu.memory.stack_pointer = cpu.memory.stack_pointer.wrapping_sub(1),
                 _ => return Err(anyhow!("Somehow extracted the value {} from {} bits. Impossible!", 0, 2))
             }
         },
@@ -109,26 +106,26 @@ pub(super) fn block0(cpu: &mut CPU, opcode: u8) -> Result<i32> {
                 _ => return Err(anyhow!("Somehow extracted the value {} from {} bits. Impossible!", o, 2))
             };
             cpu.hl.set_pair(result);
-            cpu.af.low = flags;
+            cpu.af.low = flags | (cpu.af.low & ZERO_FLAG);
         },
 
-        "11ooo100" => { // INC r8
+        "00ooo100" => { // INC r8
             cycles = 1;
             match o {
-                0 => (cpu.bc.high, cpu.af.low) = inc8(cpu.bc.high),
-                1 => (cpu.bc.low, cpu.af.low) = inc8(cpu.bc.low),
-                2 => (cpu.de.high, cpu.af.low) = inc8(cpu.de.high),
-                3 => (cpu.de.low, cpu.af.low) = inc8(cpu.de.low),
-                4 => (cpu.hl.high, cpu.af.low) = inc8(cpu.hl.high),
-                5 => (cpu.hl.low, cpu.af.low) = inc8(cpu.hl.low),
+                0 => (cpu.bc.high, cpu.af.low) = inc8(cpu.bc.high, cpu.af.low),
+                1 => (cpu.bc.low, cpu.af.low) = inc8(cpu.bc.low, cpu.af.low),
+                2 => (cpu.de.high, cpu.af.low) = inc8(cpu.de.high, cpu.af.low),
+                3 => (cpu.de.low, cpu.af.low) = inc8(cpu.de.low, cpu.af.low),
+                4 => (cpu.hl.high, cpu.af.low) = inc8(cpu.hl.high, cpu.af.low),
+                5 => (cpu.hl.low, cpu.af.low) = inc8(cpu.hl.low, cpu.af.low),
                 6 => {
                     let addr = cpu.hl.get_pair();
-                    let (result, flags) = inc8(cpu.memory.read_byte(addr)?);
+                    let (result, flags) = inc8(cpu.memory.read_byte(addr)?, cpu.af.low);
                     cpu.memory.write_byte(addr, result)?;
                     cpu.af.low = flags;
                     cycles = 3;
                 },
-                7 => (cpu.af.high, cpu.af.low) = inc8(cpu.af.high),
+                7 => (cpu.af.high, cpu.af.low) = inc8(cpu.af.high, cpu.af.low),
                 _ => return Err(anyhow!("Somehow extracted the value {} from {} bits. Impossible!", o, 3))
             }
         },
@@ -136,20 +133,20 @@ pub(super) fn block0(cpu: &mut CPU, opcode: u8) -> Result<i32> {
         "00ooo101" => { // DEC r8
             cycles = 1;
             match o {
-                0 => (cpu.bc.high, cpu.af.low) = dec8(cpu.bc.high),
-                1 => (cpu.bc.low, cpu.af.low) = dec8(cpu.bc.low),
-                2 => (cpu.de.high, cpu.af.low) = dec8(cpu.de.high),
-                3 => (cpu.de.low, cpu.af.low) = dec8(cpu.de.low),
-                4 => (cpu.hl.high, cpu.af.low) = dec8(cpu.hl.high),
-                5 => (cpu.hl.low, cpu.af.low) = dec8(cpu.hl.low),
+                0 => (cpu.bc.high, cpu.af.low) = dec8(cpu.bc.high, cpu.af.low),
+                1 => (cpu.bc.low, cpu.af.low) = dec8(cpu.bc.low, cpu.af.low),
+                2 => (cpu.de.high, cpu.af.low) = dec8(cpu.de.high, cpu.af.low),
+                3 => (cpu.de.low, cpu.af.low) = dec8(cpu.de.low, cpu.af.low),
+                4 => (cpu.hl.high, cpu.af.low) = dec8(cpu.hl.high, cpu.af.low),
+                5 => (cpu.hl.low, cpu.af.low) = dec8(cpu.hl.low, cpu.af.low),
                 6 => {
                     let addr = cpu.hl.get_pair();
-                    let (result, flags) = dec8(cpu.memory.read_byte(addr)?);
+                    let (result, flags) = dec8(cpu.memory.read_byte(addr)?, cpu.af.low);
                     cpu.memory.write_byte(addr, result)?;
                     cpu.af.low = flags;
                     cycles = 3;
                 },
-                7 => (cpu.af.high, cpu.af.low) = dec8(cpu.af.high),
+                7 => (cpu.af.high, cpu.af.low) = dec8(cpu.af.high, cpu.af.low),
                 _ => return Err(anyhow!("Somehow extracted the value {} from {} bits. Impossible!", o, 3))
             }
         },
@@ -167,46 +164,55 @@ pub(super) fn block0(cpu: &mut CPU, opcode: u8) -> Result<i32> {
                     cpu.memory.write_byte(cpu.hl.get_pair(), data)?;
                     cycles = 3
                 },
-                7 => cpu.hl.high = cpu.memory.fetch_byte()?,
+                7 => cpu.af.high = cpu.memory.fetch_byte()?,
                 _ => return Err(anyhow!("Somehow extracted the value {} from {} bits. Impossible!", d, 3))
             }
         },
 
         "00000111" => { // RLCA
-            todo!()
+            (cpu.af.high, cpu.af.low) = rlc8(cpu.af.high, cpu.af.low);
+            cpu.af.low &= !ZERO_FLAG;
+            cycles = 1;
         },
 
         "00001111" => { // RRCA
-            todo!()
+            (cpu.af.high, cpu.af.low) = rrc8(cpu.af.high, cpu.af.low);
+            cpu.af.low &= !ZERO_FLAG;
+            cycles = 1;
         },
 
         "00010111" => { // RLA
-            todo!()
+            (cpu.af.high, cpu.af.low) = rl8(cpu.af.high, cpu.af.low);
+            cpu.af.low &= !ZERO_FLAG;
+            cycles = 1;
         },
 
         "00011111" => { // RRA
-            todo!()
+            (cpu.af.high, cpu.af.low) = rr8(cpu.af.high, cpu.af.low);
+            cpu.af.low &= !ZERO_FLAG;
+            cycles = 1;
         },
 
         "00100111" => { // DAA
-            todo!()
+            (cpu.af.high, cpu.af.low) = daa8(cpu.af.high, cpu.af.low);
+            cycles = 1;
         },
 
         "00101111" => { // CPL
             cpu.af.high = !cpu.af.high;
-            cpu.af.low |= SUB_FLAG & HALF_CARRY_FLAG;
+            cpu.af.low |= SUB_FLAG | HALF_CARRY_FLAG;
             cycles = 1;
         },
 
         "00110111" => { // SCF
             cpu.af.low |= CARRY_FLAG;
-            cpu.af.low &= !(SUB_FLAG & HALF_CARRY_FLAG);
+            cpu.af.low &= !(SUB_FLAG | HALF_CARRY_FLAG);
             cycles = 1;
         },
 
         "00111111" => { // CCF
             cpu.af.low ^= CARRY_FLAG;
-            cpu.af.low &= !(SUB_FLAG & HALF_CARRY_FLAG);
+            cpu.af.low &= !(SUB_FLAG | HALF_CARRY_FLAG);
             cycles = 1;
         },
 
@@ -229,7 +235,20 @@ pub(super) fn block0(cpu: &mut CPU, opcode: u8) -> Result<i32> {
             }
         },
 
-        "00010000" => todo!(), // STOP
+        "00010000" => { // STOP
+            // STOP is followed by a padding byte that gets skipped
+            cpu.memory.fetch_byte()?;
+            cpu.memory.timer.reset_div();
+            cycles = 1;
+            if cpu.memory.speed_switch_armed() {
+                // KEY1 was armed, so this is a CGB speed switch rather than a real stop
+                cpu.scheduler.switch_speed();
+                cpu.memory.set_double_speed(cpu.scheduler.double_speed());
+                cycles += SPEED_SWITCH_CYCLES;
+            } else {
+                cpu.stopped = true;
+            }
+        },
 
         _ => return Err(anyhow!("Undefined opcode: {}", opcode))
     }
@@ -313,7 +332,10 @@ pub(super) fn block1(cpu: &mut CPU, opcode: u8) -> Result<i32> {
                 3 => cpu.memory.write_byte(cpu.hl.get_pair(), cpu.de.low)?,
                 4 => cpu.memory.write_byte(cpu.hl.get_pair(), cpu.hl.high)?,
                 5 => cpu.memory.write_byte(cpu.hl.get_pair(), cpu.hl.low)?,
-                6 => todo!(), // HALT
+                6 => { // HALT
+                    cpu.halted = true;
+                    return Ok(1)
+                },
                 7 => cpu.memory.write_byte(cpu.hl.get_pair(), cpu.af.high)?,
                 _ => return Err(anyhow!("Somehow extracted the value {} from {} bits. Impossible!", d, 3))
             }
@@ -400,15 +422,45 @@ pub(super) fn block2(cpu: &mut CPU, opcode: u8) -> Result<i32> {
         },
 
         "10100ooo" => { // AND a, r8
-            todo!()
+            (cpu.af.high, cpu.af.low) = match o {
+                0 => and8(cpu.af.high, cpu.bc.high),
+                1 => and8(cpu.af.high, cpu.bc.low),
+                2 => and8(cpu.af.high, cpu.de.high),
+                3 => and8(cpu.af.high, cpu.de.low),
+                4 => and8(cpu.af.high, cpu.hl.high),
+                5 => and8(cpu.af.high, cpu.hl.low),
+                6 => {cycles = 2; and8(cpu.af.high, cpu.memory.read_byte(cpu.hl.get_pair())?)},
+                7 => and8(cpu.af.high, cpu.af.high),
+                _ => return Err(anyhow!("Somehow extracted the value {} from {} bits. Impossible!", o, 3))
+            };
         },
 
         "10101ooo" => { // XOR a, r8
-            todo!()
+            (cpu.af.high, cpu.af.low) = match o {
+                0 => xor8(cpu.af.high, cpu.bc.high),
+                1 => xor8(cpu.af.high, cpu.bc.low),
+                2 => xor8(cpu.af.high, cpu.de.high),
+                3 => xor8(cpu.af.high, cpu.de.low),
+                4 => xor8(cpu.af.high, cpu.hl.high),
+                5 => xor8(cpu.af.high, cpu.hl.low),
+                6 => {cycles = 2; xor8(cpu.af.high, cpu.memory.read_byte(cpu.hl.get_pair())?)},
+                7 => xor8(cpu.af.high, cpu.af.high),
+                _ => return Err(anyhow!("Somehow extracted the value {} from {} bits. Impossible!", o, 3))
+            };
         },
 
         "10110ooo" => { // OR a, r8
-            todo!()
+            (cpu.af.high, cpu.af.low) = match o {
+                0 => or8(cpu.af.high, cpu.bc.high),
+                1 => or8(cpu.af.high, cpu.bc.low),
+                2 => or8(cpu.af.high, cpu.de.high),
+                3 => or8(cpu.af.high, cpu.de.low),
+                4 => or8(cpu.af.high, cpu.hl.high),
+                5 => or8(cpu.af.high, cpu.hl.low),
+                6 => {cycles = 2; or8(cpu.af.high, cpu.memory.read_byte(cpu.hl.get_pair())?)},
+                7 => or8(cpu.af.high, cpu.af.high),
+                _ => return Err(anyhow!("Somehow extracted the value {} from {} bits. Impossible!", o, 3))
+            };
         },
 
         "10111ooo" => { // CP a, r8
@@ -455,15 +507,15 @@ pub(super) fn block3(cpu: &mut CPU, opcode: u8) -> Result<i32> {
         },
 
         "11100110" => { // AND a, imm8
-            todo!()
+            (cpu.af.high, cpu.af.low) = and8(cpu.af.high, cpu.memory.fetch_byte()?);
         },
 
         "11101110" => { // XOR a, imm8
-            todo!()
+            (cpu.af.high, cpu.af.low) = xor8(cpu.af.high, cpu.memory.fetch_byte()?);
         },
 
         "11110110" => { // OR a, imm8
-            todo!()
+            (cpu.af.high, cpu.af.low) = or8(cpu.af.high, cpu.memory.fetch_byte()?);
         },
 
         "11111110" => { // CP a, imm8
@@ -518,8 +570,9 @@ pub(super) fn block3(cpu: &mut CPU, opcode: u8) -> Result<i32> {
         },
 
         "110cc100" => { // CALL cond, imm16
-            let pc = cpu.memory.program_counter;
             let addr = cpu.memory.fetch_two_bytes()?;
+            // The return address is the instruction after the call
+            let pc = cpu.memory.program_counter;
             cycles = 6;
             match (c, cpu.af.low) {
                 (0, f) if (f & ZERO_FLAG == 0) => {
@@ -544,8 +597,9 @@ pub(super) fn block3(cpu: &mut CPU, opcode: u8) -> Result<i32> {
         },
 
         "11001101" => { // CALL imm16
+            let addr = cpu.memory.fetch_two_bytes()?;
             cpu.memory.push_stack(cpu.memory.program_counter)?;
-            cpu.memory.program_counter = cpu.memory.fetch_two_bytes()?;
+            cpu.memory.program_counter = addr;
             cycles = 6;
         },
 
@@ -587,15 +641,13 @@ pub(super) fn block3(cpu: &mut CPU, opcode: u8) -> Result<i32> {
 
         "11100000" => { // LDH [imm8], a
             let addr = cpu.memory.fetch_byte()?;
-            cpu.af.high = cpu.memory.read_byte(0xFF00 + addr as u16)?;
+            cpu.memory.write_byte(0xFF00 + addr as u16, cpu.af.high)?;
             cycles = 3;
         },
 
         "11101010" => { // LD [imm16], a
             let addr = cpu.memory.fetch_two_bytes()?;
-            if (0xFF00..=0xFFFF).contains(&addr) {
-                cpu.memory.write_byte(addr, cpu.af.high)?;
-            }
+            cpu.memory.write_byte(addr, cpu.af.high)?;
             cycles = 4;
         },
 
@@ -668,54 +720,42 @@ pub(super) fn block3(cpu: &mut CPU, opcode: u8) -> Result<i32> {
 /// These instructions are only accessible using the prefix byte 0xCB.
 #[bitmatch]
 pub(super) fn blockcb(cpu: &mut CPU, opcode: u8) -> Result<i32> {
-    let mut cycles = 8;
+    let mut cycles = 2;
     #[bitmatch]
     match opcode {
         "00000ooo" => { // RLC r8
-            todo!()
+            cycles = shift_r8(cpu, o, rlc8)?;
         },
 
         "00001ooo" => { // RRC r8
-            todo!()
+            cycles = shift_r8(cpu, o, rrc8)?;
         },
 
         "00010ooo" => { // RL r8
-            todo!()
+            cycles = shift_r8(cpu, o, rl8)?;
         },
 
         "00011ooo" => { // RR r8
-            todo!()
+            cycles = shift_r8(cpu, o, rr8)?;
         },
 
         "00100ooo" => { // SLA r8
-            todo!()
+            cycles = shift_r8(cpu, o, sla8)?;
         },
 
         "00101ooo" => { // SRA r8
-            todo!()
+            cycles = shift_r8(cpu, o, sra8)?;
         },
 
         "00110ooo" => { // SWAP r8
-            cycles = 2;
-            match o {
-                0 => swap8(cpu.bc.high),
-                1 => swap8(cpu.bc.low),
-                2 => swap8(cpu.de.high),
-                3 => swap8(cpu.de.low),
-                4 => swap8(cpu.hl.high),
-                5 => swap8(cpu.hl.low),
-                6 => {cycles = 4; swap8(cpu.memory.read_byte(cpu.hl.get_pair())?)},
-                7 => swap8(cpu.af.high),
-                _ => return Err(anyhow!("Somehow extracted the value {} from {} bits. Impossible!", o, 3))
-            };
+            cycles = shift_r8(cpu, o, swap8)?;
         },
 
         "00111ooo" => { // SRL r8
-            todo!()
+            cycles = shift_r8(cpu, o, srl8)?;
         },
 
         "01bbbooo" => { // BIT b3, r8
-            cycles = 2;
             let bit = 1 << b;
             let val = match o {
                 0 => cpu.bc.high,
@@ -733,7 +773,6 @@ pub(super) fn blockcb(cpu: &mut CPU, opcode: u8) -> Result<i32> {
         },
 
         "10bbbooo" => { // RES b3, r8
-            cycles = 2;
             let bit = !(1 << b);
             match o {
                 0 => cpu.bc.high &= bit,
@@ -754,7 +793,6 @@ pub(super) fn blockcb(cpu: &mut CPU, opcode: u8) -> Result<i32> {
         },
 
         "11bbbooo" => { // SET b3, r8
-            cycles = 2;
             let bit = 1 << b;
             match o {
                 0 => cpu.bc.high |= bit,
@@ -785,7 +823,7 @@ fn add8(lhs: u8, rhs: u8) -> (u8, u8) {
     let result = lhs.wrapping_add(rhs);
     let c = (((lhs as u16 & 0xFF) + (rhs as u16 & 0xFF)) >> 8) as u8;
     let h = ((lhs & 0xF) + (rhs & 0xF)) >> 4;
-    let z: u8 = match result {0 => 0, _ => 1};
+    let z = (result == 0) as u8;
     let flags = bitpack!("z0hc0000");
     (result, flags)
 }
@@ -797,7 +835,7 @@ fn adc8(lhs: u8, rhs: u8, carry: u8) -> (u8, u8) {
     let result = lhs.wrapping_add(rhs).wrapping_add(carry);
     let c = (((lhs as u16 & 0xFF) + (rhs as u16 & 0xFF) + (carry as u16)) >> 8) as u8;
     let h = ((lhs & 0xF) + (rhs & 0xF) + carry) >> 4;
-    let z: u8 = match result {0 => 0, _ => 1};
+    let z = (result == 0) as u8;
     let flags = bitpack!("z0hc0000");
     (result, flags)
 }
@@ -808,7 +846,7 @@ fn sub8(lhs: u8, rhs: u8) -> (u8, u8) {
     let result = lhs.wrapping_sub(rhs);
     let c: u8 = match rhs > lhs {true => 1, false => 0};
     let h: u8 = match (rhs & 0xF) > (lhs & 0xF) {true => 1, false => 0};
-    let z: u8 = match result {0 => 0, _ => 1};
+    let z = (result == 0) as u8;
     let flags = bitpack!("z1hc0000");
     (result, flags)
 }
@@ -819,13 +857,13 @@ fn sbc8(lhs: u8, rhs: u8, carry: u8) -> (u8, u8) {
     // let carry = carry & 0x01; // Mask the carry to only the LSB
     let result = lhs.wrapping_sub(rhs).wrapping_sub(carry);
     let c: u8 = match (rhs as u16 + carry as u16) > lhs as u16 {true => 1, false => 0};
-    let h: u8 = match ((rhs + carry) & 0xF) > (lhs & 0xF) {true => 1, false => 0};
-    let z: u8 = match result {0 => 0, _ => 1};
+    let h: u8 = match (rhs & 0xF) + carry > (lhs & 0xF) {true => 1, false => 0};
+    let z = (result == 0) as u8;
     let flags = bitpack!("z1hc0000");
     (result, flags)
 }
 
-// Add two unsigned 16-bit values, returning a tuple with the result and flags.
+// Add two unsigned 16-bit values, returning a tuple with the result and flags (other than Z, which is left alone).
 #[bitmatch]
 fn add16(lhs: u16, rhs: u16) -> (u16, u8) {
     let result = lhs.wrapping_add(rhs);
@@ -836,27 +874,203 @@ fn add16(lhs: u16, rhs: u16) -> (u16, u8) {
     (result, flags)
 }
 
-// Increment an 8-bit value with wrapping, returning a tuple with the result and flags.
+// Increment an 8-bit value with wrapping, returning a tuple with the result and flags (keeping the old carry).
 #[bitmatch]
-fn inc8(arg: u8) -> (u8, u8) {
+fn inc8(arg: u8, flags: u8) -> (u8, u8) {
     let result = arg.wrapping_add(1);
     let h = ((arg & 0xF) == 0xF) as u8;
-    let z: u8 = match result {0 => 0, _ => 1};
-    let flags = bitpack!("z0h00000");
+    let z = (result == 0) as u8;
+    let flags = bitpack!("z0h00000") | (flags & CARRY_FLAG);
     (result, flags)
 }
 
-// Increment an 8-bit value with wrapping, returning a tuple with the result and flags.
+// Decrement an 8-bit value with wrapping, returning a tuple with the result and flags (keeping the old carry).
 #[bitmatch]
-fn dec8(arg: u8) -> (u8, u8) {
+fn dec8(arg: u8, flags: u8) -> (u8, u8) {
     let result = arg.wrapping_sub(1);
     let h = ((arg & 0xF) == 0x0) as u8;
-    let z: u8 = match result {0 => 0, _ => 1};
-    let flags = bitpack!("z0h00000");
+    let z = (result == 0) as u8;
+    let flags = bitpack!("z1h00000") | (flags & CARRY_FLAG);
     (result, flags)
 }
 
-// Swap upper and lower nibbles of an 8-bit value
-fn swap8(arg: u8) -> u8 {
-    ((arg & 0xF) << 4) | (arg >> 4)
-}
\ No newline at end of file
+// Bitwise AND of two 8-bit values, returning a tuple with the result and flags.
+#[bitmatch]
+fn and8(lhs: u8, rhs: u8) -> (u8, u8) {
+    let result = lhs & rhs;
+    let z = (result == 0) as u8;
+    (result, bitpack!("z0100000"))
+}
+
+// Bitwise XOR of two 8-bit values, returning a tuple with the result and flags.
+#[bitmatch]
+fn xor8(lhs: u8, rhs: u8) -> (u8, u8) {
+    let result = lhs ^ rhs;
+    let z = (result == 0) as u8;
+    (result, bitpack!("z0000000"))
+}
+
+// Bitwise OR of two 8-bit values, returning a tuple with the result and flags.
+#[bitmatch]
+fn or8(lhs: u8, rhs: u8) -> (u8, u8) {
+    let result = lhs | rhs;
+    let z = (result == 0) as u8;
+    (result, bitpack!("z0000000"))
+}
+
+// Decimal adjust A after a BCD add or subtract, returning a tuple with the result and flags.
+fn daa8(arg: u8, flags: u8) -> (u8, u8) {
+    let mut adjust = 0;
+    let mut carry = flags & CARRY_FLAG != 0;
+    let result = if flags & SUB_FLAG == 0 {
+        if carry || arg > 0x99 {
+            adjust |= 0x60;
+            carry = true;
+        }
+        if flags & HALF_CARRY_FLAG != 0 || arg & 0xF > 0x9 {
+            adjust |= 0x06;
+        }
+        arg.wrapping_add(adjust)
+    } else {
+        if carry {adjust |= 0x60}
+        if flags & HALF_CARRY_FLAG != 0 {adjust |= 0x06}
+        arg.wrapping_sub(adjust)
+    };
+    let mut new_flags = flags & SUB_FLAG;
+    if result == 0 {new_flags |= ZERO_FLAG}
+    if carry {new_flags |= CARRY_FLAG}
+    (result, new_flags)
+}
+
+// Runs one of the CB rotates or shifts below on r8 in place, returning the machine cycles taken.
+fn shift_r8(cpu: &mut CPU, o: u8, op: fn(u8, u8) -> (u8, u8)) -> Result<i32> {
+    let flags = cpu.af.low;
+    let register = match o {
+        0 => &mut cpu.bc.high,
+        1 => &mut cpu.bc.low,
+        2 => &mut cpu.de.high,
+        3 => &mut cpu.de.low,
+        4 => &mut cpu.hl.high,
+        5 => &mut cpu.hl.low,
+        6 => {
+            let addr = cpu.hl.get_pair();
+            let result;
+            (result, cpu.af.low) = op(cpu.memory.read_byte(addr)?, flags);
+            cpu.memory.write_byte(addr, result)?;
+            return Ok(4)
+        },
+        7 => &mut cpu.af.high,
+        _ => return Err(anyhow!("Somehow extracted the value {} from {} bits. Impossible!", o, 3))
+    };
+    (*register, cpu.af.low) = op(*register, flags);
+    Ok(2)
+}
+
+// The rotates and shifts all take the old flags for the carry going in, and return the result and new flags.
+#[bitmatch]
+fn rlc8(arg: u8, _flags: u8) -> (u8, u8) {
+    let result = arg.rotate_left(1);
+    let c = arg >> 7;
+    let z = (result == 0) as u8;
+    (result, bitpack!("z00c0000"))
+}
+
+#[bitmatch]
+fn rrc8(arg: u8, _flags: u8) -> (u8, u8) {
+    let result = arg.rotate_right(1);
+    let c = arg & 1;
+    let z = (result == 0) as u8;
+    (result, bitpack!("z00c0000"))
+}
+
+#[bitmatch]
+fn rl8(arg: u8, flags: u8) -> (u8, u8) {
+    let result = (arg << 1) | ((flags & CARRY_FLAG != 0) as u8);
+    let c = arg >> 7;
+    let z = (result == 0) as u8;
+    (result, bitpack!("z00c0000"))
+}
+
+#[bitmatch]
+fn rr8(arg: u8, flags: u8) -> (u8, u8) {
+    let result = (arg >> 1) | (((flags & CARRY_FLAG != 0) as u8) << 7);
+    let c = arg & 1;
+    let z = (result == 0) as u8;
+    (result, bitpack!("z00c0000"))
+}
+
+#[bitmatch]
+fn sla8(arg: u8, _flags: u8) -> (u8, u8) {
+    let result = arg << 1;
+    let c = arg >> 7;
+    let z = (result == 0) as u8;
+    (result, bitpack!("z00c0000"))
+}
+
+#[bitmatch]
+fn sra8(arg: u8, _flags: u8) -> (u8, u8) {
+    let result = (arg >> 1) | (arg & 0x80);
+    let c = arg & 1;
+    let z = (result == 0) as u8;
+    (result, bitpack!("z00c0000"))
+}
+
+#[bitmatch]
+fn srl8(arg: u8, _flags: u8) -> (u8, u8) {
+    let result = arg >> 1;
+    let c = arg & 1;
+    let z = (result == 0) as u8;
+    (result, bitpack!("z00c0000"))
+}
+
+#[bitmatch]
+fn swap8(arg: u8, _flags: u8) -> (u8, u8) {
+    let result = ((arg & 0xF) << 4) | (arg >> 4);
+    let z = (result == 0) as u8;
+    (result, bitpack!("z0000000"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_flag_is_set_on_zero() {
+        assert_eq!(add8(0xFF, 0x01), (0x00, ZERO_FLAG | HALF_CARRY_FLAG | CARRY_FLAG));
+        assert_eq!(sub8(0x10, 0x01), (0x0F, SUB_FLAG | HALF_CARRY_FLAG));
+        assert_eq!(sbc8(0x00, 0xFF, 1), (0x00, ZERO_FLAG | SUB_FLAG | HALF_CARRY_FLAG | CARRY_FLAG));
+        assert_eq!(and8(0xF0, 0x0F), (0x00, ZERO_FLAG | HALF_CARRY_FLAG));
+        assert_eq!(xor8(0x5A, 0x5A), (0x00, ZERO_FLAG));
+        assert_eq!(or8(0x50, 0x0A), (0x5A, 0));
+    }
+
+    #[test]
+    fn inc_and_dec_keep_the_carry() {
+        assert_eq!(inc8(0xFF, CARRY_FLAG), (0x00, ZERO_FLAG | HALF_CARRY_FLAG | CARRY_FLAG));
+        assert_eq!(dec8(0x01, 0), (0x00, ZERO_FLAG | SUB_FLAG));
+        assert_eq!(dec8(0x10, CARRY_FLAG), (0x0F, SUB_FLAG | HALF_CARRY_FLAG | CARRY_FLAG));
+    }
+
+    #[test]
+    fn rotates_and_shifts() {
+        assert_eq!(rlc8(0x85, 0), (0x0B, CARRY_FLAG));
+        assert_eq!(rrc8(0x01, 0), (0x80, CARRY_FLAG));
+        assert_eq!(rl8(0x80, 0), (0x00, ZERO_FLAG | CARRY_FLAG));
+        assert_eq!(rl8(0x00, CARRY_FLAG), (0x01, 0));
+        assert_eq!(rr8(0x01, CARRY_FLAG), (0x80, CARRY_FLAG));
+        assert_eq!(sla8(0xC0, 0), (0x80, CARRY_FLAG));
+        assert_eq!(sra8(0x81, 0), (0xC0, CARRY_FLAG));
+        assert_eq!(srl8(0x81, 0), (0x40, CARRY_FLAG));
+        assert_eq!(swap8(0xF1, CARRY_FLAG), (0x1F, 0));
+    }
+
+    #[test]
+    fn daa_adjusts_bcd() {
+        // 0x19 + 0x28 = 0x41, which should read 47
+        assert_eq!(daa8(0x41, HALF_CARRY_FLAG), (0x47, 0));
+        // 0x99 + 0x01 = 0x9A, which should read 00 and carry
+        assert_eq!(daa8(0x9A, 0), (0x00, ZERO_FLAG | CARRY_FLAG));
+        // 0x20 - 0x01 = 0x1F, which should read 19
+        assert_eq!(daa8(0x1F, SUB_FLAG | HALF_CARRY_FLAG), (0x19, SUB_FLAG));
+    }
+}
@@ -0,0 +1,84 @@
+//! Joypad
+//!
+//! The eight buttons, read through P1 as two groups of four: the D-pad and the rest.
+//! Bits 4 and 5 select which groups show up in the low nibble. Everything is active low,
+//! so a 0 means selected or pressed.
+
+pub const P1_ADDR: u16 = 0xFF00;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+}
+
+pub struct Joypad {
+    select: u8,
+    // One bit per button in declaration order, so the D-pad is the low nibble and the rest the high one
+    pressed: u8,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            select: 0x30,
+            pressed: 0,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        let mut lines = 0x0F;
+        if self.select & 0x10 == 0 {
+            lines &= !self.pressed & 0x0F;
+        }
+        if self.select & 0x20 == 0 {
+            lines &= !(self.pressed >> 4);
+        }
+        0xC0 | self.select | lines
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.select = data & 0x30;
+    }
+
+    pub fn pressed(&self, button: Button) -> bool {
+        self.pressed & 1 << button as u8 != 0
+    }
+
+    /// Presses or releases a button. Returns true if that pulled one of the selected lines low,
+    /// which is what requests the joypad interrupt.
+    pub fn set(&mut self, button: Button, pressed: bool) -> bool {
+        let before = self.read() & 0x0F;
+        if pressed {
+            self.pressed |= 1 << button as u8;
+        } else {
+            self.pressed &= !(1 << button as u8);
+        }
+        before & !self.read() != 0
+    }
+}
@@ -6,13 +6,77 @@
 mod registers;
 mod memory;
 mod instructions;
+mod cartridge;
+mod apu;
+mod ppu;
+mod joypad;
+mod serial;
+mod sink;
+mod recorder;
+mod scope;
+mod vgm;
+mod gbs;
+mod midi;
+mod wav;
+mod scheduler;
+mod timer;
+mod trace;
+mod disasm;
+mod asm;
+mod watch;
+mod symbols;
 
+use std::path::Path;
 use anyhow::{anyhow, Ok, Result};
 use registers::RegisterPair;
+pub use registers::{CpuState, Reg8, Reg16};
+pub use disasm::{Condition, Instruction, Mnemonic, Operand};
+pub use asm::assemble;
+pub use symbols::Symbols;
+pub use watch::{WatchCondition, WatchHit, WatchKind, Watchpoint};
 use memory::Memory;
+use scheduler::Scheduler;
+use recorder::Recorder;
+use vgm::VgmLogger;
+use midi::MidiExporter;
+use trace::TraceLogger;
+
+pub use cartridge::{Cartridge, CartridgeHeader, Mbc};
+pub use ppu::{SCREEN_WIDTH, SCREEN_HEIGHT, SHADES};
+pub use joypad::Button;
+pub use apu::{AudioChannel, StereoSample, NATIVE_SAMPLE_RATE};
+pub use scope::{SCOPE_LENGTH, SCOPE_RATE};
+pub use gbs::{GbsHeader, GbsPlayer};
+pub use sink::DEFAULT_SAMPLE_RATE;
+pub use scheduler::{DOTS_PER_FRAME, DOTS_PER_SECOND};
 
 const ROM_ADDR: u16 = 0x0100;
 
+/// IO registers the boot ROM leaves set up: sound on, LCD on, and the VBlank interrupt pending.
+const POST_BOOT_IO: [(u16, u8); 6] = [
+    (0xFF26, 0x80), // NR52
+    (0xFF25, 0xF3), // NR51
+    (0xFF24, 0x77), // NR50
+    (0xFF40, 0x91), // LCDC
+    (0xFF47, 0xFC), // BGP
+    (0xFF0F, 0xE1), // IF
+];
+
+/// Which Game Boy is being emulated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Model {
+    #[default]
+    Dmg,
+    Cgb,
+}
+
+impl Model {
+    /// The model a cartridge would rather run on: a CGB if it has any CGB support, otherwise a DMG.
+    pub fn preferred_by(header: &CartridgeHeader) -> Self {
+        if header.supports_cgb() { Model::Cgb } else { Model::Dmg }
+    }
+}
+
 /// This contains all components of the CPU
 pub struct CPU {
     af: RegisterPair,
@@ -20,8 +84,28 @@ pub struct CPU {
     de: RegisterPair,
     hl: RegisterPair,
     memory: Memory,
+    scheduler: Scheduler,
     ime: bool,
     set_ime: i32,
+    stopped: bool,
+    halted: bool,
+    model: Model,
+    trace: Option<TraceLogger>,
+    symbols: Option<Symbols>,
+}
+
+/// Shows the registers, e.g. for a summary after a run.
+impl std::fmt::Display for CPU {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match (self.halted, self.stopped) {
+            (true, _) => " (halted)",
+            (_, true) => " (stopped)",
+            _ => "",
+        };
+        write!(f, "AF={:02X}{:02X} BC={:02X}{:02X} DE={:02X}{:02X} HL={:02X}{:02X} SP={:04X} PC={:04X} IME={}{}",
+            self.af.high, self.af.low, self.bc.high, self.bc.low, self.de.high, self.de.low, self.hl.high, self.hl.low,
+            self.memory.stack_pointer, self.memory.program_counter, self.ime as u8, state)
+    }
 }
 
 impl Default for CPU {
@@ -32,27 +116,487 @@ impl Default for CPU {
 
 impl CPU {
     pub fn new() -> Self {
+        Self::with_model(Model::default())
+    }
+
+    pub fn with_model(model: Model) -> Self {
+        let mut memory = Memory::new(0, 0);
+        memory.set_cgb(model == Model::Cgb);
         Self {
             af: RegisterPair::new(),
             bc: RegisterPair::new(),
             de: RegisterPair::new(),
             hl: RegisterPair::new(),
-            memory: Memory::new(0, 0),
+            memory,
+            scheduler: Scheduler::new(),
             ime: false,
-            set_ime: 0,
+            set_ime: -1,
+            stopped: false,
+            halted: false,
+            model,
+            trace: None,
+            symbols: None,
         }
     }
 
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
     /// Loads instructions into memory from some slice (probably a Vector)
     pub fn load_rom(&mut self, buffer: &[u8]) -> Result<()> {
         self.memory.load_memory(buffer, ROM_ADDR)
     }
 
+    /// Inserts a cartridge and gets ready to run it.
+    /// With a boot ROM, execution starts at 0x0000 inside it, just like powering on.
+    /// Without one, everything is set up the way the boot ROM would have left it, starting at 0x0100.
+    pub fn load_cartridge(&mut self, cartridge: Cartridge, boot_rom: Option<&[u8]>) -> Result<()> {
+        self.memory.load_cartridge(cartridge);
+        if let Some(boot_rom) = boot_rom {
+            let size = match self.model {
+                Model::Dmg => 0x100,
+                Model::Cgb => 0x900,
+            };
+            if boot_rom.len() != size {
+                return Err(anyhow!("Boot ROM should be {} bytes for {:?}, got {}", size, self.model, boot_rom.len()))
+            }
+            self.memory.load_boot_rom(boot_rom.to_vec());
+            self.memory.program_counter = 0x0000;
+            return Ok(())
+        }
+
+        let (af, bc, de, hl) = match self.model {
+            Model::Dmg => (0x01B0, 0x0013, 0x00D8, 0x014D),
+            Model::Cgb => (0x1180, 0x0000, 0xFF56, 0x000D),
+        };
+        self.af.set_pair(af);
+        self.bc.set_pair(bc);
+        self.de.set_pair(de);
+        self.hl.set_pair(hl);
+        self.memory.stack_pointer = 0xFFFE;
+        self.memory.program_counter = ROM_ADDR;
+        if self.model == Model::Cgb && !self.memory.cartridge().is_some_and(|cartridge| cartridge.header().supports_cgb()) {
+            self.memory.set_dmg_compatibility();
+        }
+        for (address, data) in POST_BOOT_IO {
+            self.memory.write_byte(address, data)?;
+        }
+        Ok(())
+    }
+
+    pub fn cartridge(&self) -> Option<&Cartridge> {
+        self.memory.cartridge()
+    }
+
+    /// The cartridge, for restoring its RAM from a save.
+    pub fn cartridge_mut(&mut self) -> Option<&mut Cartridge> {
+        self.memory.cartridge_mut()
+    }
+
+    /// True while the CPU is running in CGB double-speed mode.
+    pub fn double_speed(&self) -> bool {
+        self.scheduler.double_speed()
+    }
+
+    /// Total time elapsed since power on, in dots (4 MiHz ticks, independent of CPU speed).
+    pub fn elapsed_dots(&self) -> u64 {
+        self.scheduler.dots()
+    }
+
+    /// The last frame drawn, [`SCREEN_WIDTH`] by [`SCREEN_HEIGHT`] pixels row by row, as 0x00RRGGBB.
+    pub fn framebuffer(&self) -> &[u32] {
+        self.memory.ppu.framebuffer()
+    }
+
+    /// Number of frames the PPU has finished since power on.
+    pub fn frame_count(&self) -> u64 {
+        self.memory.ppu.frames()
+    }
+
+    /// Presses or releases a button. Pressing anything also wakes the CPU from STOP.
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if self.memory.joypad.set(button, pressed) {
+            self.memory.request_interrupt(memory::JOYPAD_INTERRUPT);
+        }
+        if pressed {
+            self.stopped = false;
+        }
+    }
+
+    pub fn button_pressed(&self, button: Button) -> bool {
+        self.memory.joypad.pressed(button)
+    }
+
+    /// True while the CPU is waiting in HALT.
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// True while the CPU is stopped, waiting for a button press.
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn reg8(&self, register: Reg8) -> u8 {
+        match register {
+            Reg8::A => self.af.high,
+            Reg8::F => self.af.low,
+            Reg8::B => self.bc.high,
+            Reg8::C => self.bc.low,
+            Reg8::D => self.de.high,
+            Reg8::E => self.de.low,
+            Reg8::H => self.hl.high,
+            Reg8::L => self.hl.low,
+        }
+    }
+
+    pub fn set_reg8(&mut self, register: Reg8, value: u8) {
+        match register {
+            // The low nibble of F doesn't exist, so it always reads back as zero
+            Reg8::F => self.af.low = value & 0xF0,
+            Reg8::A => self.af.high = value,
+            Reg8::B => self.bc.high = value,
+            Reg8::C => self.bc.low = value,
+            Reg8::D => self.de.high = value,
+            Reg8::E => self.de.low = value,
+            Reg8::H => self.hl.high = value,
+            Reg8::L => self.hl.low = value,
+        }
+    }
+
+    pub fn reg16(&self, register: Reg16) -> u16 {
+        match register {
+            Reg16::SP => self.memory.stack_pointer,
+            Reg16::PC => self.memory.program_counter,
+            Reg16::AF => self.af.get_pair(),
+            Reg16::BC => self.bc.get_pair(),
+            Reg16::DE => self.de.get_pair(),
+            Reg16::HL => self.hl.get_pair(),
+        }
+    }
+
+    pub fn set_reg16(&mut self, register: Reg16, value: u16) {
+        match register {
+            Reg16::SP => self.memory.stack_pointer = value,
+            Reg16::PC => self.memory.program_counter = value,
+            pair => {
+                let (high, low) = pair_halves(pair);
+                self.set_reg8(high, (value >> 8) as u8);
+                self.set_reg8(low, value as u8);
+            },
+        }
+    }
+
+    /// A snapshot of all the registers, IME, and whether the CPU is halted.
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            a: self.af.high,
+            f: self.af.low,
+            b: self.bc.high,
+            c: self.bc.low,
+            d: self.de.high,
+            e: self.de.low,
+            h: self.hl.high,
+            l: self.hl.low,
+            sp: self.memory.stack_pointer,
+            pc: self.memory.program_counter,
+            ime: self.ime,
+            halted: self.halted,
+        }
+    }
+
+    /// Puts back a snapshot from [`CPU::state`], or one made up from scratch.
+    /// An EI still waiting to take effect is dropped, so IME is exactly what the state says.
+    pub fn set_state(&mut self, state: &CpuState) {
+        self.af.high = state.a;
+        self.af.low = state.f & 0xF0;
+        self.bc.high = state.b;
+        self.bc.low = state.c;
+        self.de.high = state.d;
+        self.de.low = state.e;
+        self.hl.high = state.h;
+        self.hl.low = state.l;
+        self.memory.stack_pointer = state.sp;
+        self.memory.program_counter = state.pc;
+        self.ime = state.ime;
+        self.set_ime = -1;
+        self.halted = state.halted;
+    }
+
+    pub fn program_counter(&self) -> u16 {
+        self.memory.program_counter
+    }
+
+    pub fn set_program_counter(&mut self, address: u16) {
+        self.memory.program_counter = address;
+    }
+
+    pub fn stack_pointer(&self) -> u16 {
+        self.memory.stack_pointer
+    }
+
+    pub fn set_stack_pointer(&mut self, address: u16) {
+        self.memory.stack_pointer = address;
+    }
+
+    /// Whether interrupts are enabled.
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    /// Reads a byte the way the CPU would see it, without running into any trouble along the way.
+    pub fn peek(&self, address: u16) -> u8 {
+        self.memory.peek_byte(address).unwrap_or(0xFF)
+    }
+
+    /// Writes a byte the way the CPU would, so writes to ROM switch banks and writes to IO registers take effect.
+    /// Watchpoints don't see it, though.
+    pub fn poke(&mut self, address: u16, data: u8) -> Result<()> {
+        self.memory.poke_byte(address, data)
+    }
+
+    /// Starts watching for the CPU reading or writing somewhere. See [`CPU::take_watch_hit`].
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.memory.watches.points.push(watchpoint);
+    }
+
+    /// Stops watching somewhere. Returns false if there was no such watchpoint.
+    /// Only one copy goes when the same watchpoint was added more than once, like GDB expects.
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let points = &mut self.memory.watches.points;
+        match points.iter().position(|point| point == watchpoint) {
+            Some(index) => {
+                points.remove(index);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.memory.watches.points
+    }
+
+    /// The first watchpoint to go off since this was last called, if any did. Check it after each [`CPU::cycle`]
+    /// to stop right after the instruction that set it off.
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.memory.watches.take_hit()
+    }
+
+    /// Labels for the debugger, disassembler, and trace to show addresses with.
+    pub fn set_symbols(&mut self, symbols: Option<Symbols>) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> Option<&Symbols> {
+        self.symbols.as_ref()
+    }
+
+    /// The bank switched into the area of memory an address is in, which is 0 for areas that don't switch.
+    /// Work RAM doesn't switch banks here, so 0xD000-0xDFFF is always bank 1.
+    pub fn bank_at(&self, address: u16) -> u16 {
+        match (address, self.cartridge()) {
+            (0x4000..=0x7FFF, Some(cartridge)) => cartridge.rom_bank() as u16,
+            (0x4000..=0x7FFF, None) => 1,
+            (0xA000..=0xBFFF, Some(cartridge)) => cartridge.ram_bank() as u16,
+            (0xD000..=0xDFFF, _) => 1,
+            _ => 0,
+        }
+    }
+
+    /// The address as `Label` or `Label+offset`, going by the banks switched in right now, if there's a label for it.
+    pub fn label(&self, address: u16) -> Option<String> {
+        self.symbols.as_ref()?.label(self.bank_at(address), address)
+    }
+
+    /// Decodes the instruction at an address.
+    pub fn disassemble(&self, address: u16) -> Instruction {
+        let bytes = [0, 1, 2].map(|offset| self.peek(address.wrapping_add(offset)));
+        Instruction::decode(address, bytes)
+    }
+
+    /// Everything sent out of the link port since power on.
+    pub fn serial_output(&self) -> &[u8] {
+        self.memory.serial.output()
+    }
+
+    /// Sets the rate audio is resampled to, in Hz ([`DEFAULT_SAMPLE_RATE`] unless changed).
+    /// Passing [`NATIVE_SAMPLE_RATE`] skips resampling altogether.
+    pub fn set_sample_rate(&mut self, rate: u32) -> Result<()> {
+        self.memory.apu.sink.set_rate(rate)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.memory.apu.sink.rate()
+    }
+
+    /// Appends the audio produced since the last drain to a buffer, as interleaved stereo `f32` samples.
+    /// Call at least once a frame, since only about a second of audio is held on to.
+    pub fn drain_samples_f32(&mut self, buffer: &mut Vec<f32>) {
+        self.memory.apu.sink.drain_f32(buffer);
+    }
+
+    /// Appends the audio produced since the last drain to a buffer, as interleaved stereo `i16` samples.
+    /// Call at least once a frame, since only about a second of audio is held on to.
+    pub fn drain_samples_i16(&mut self, buffer: &mut Vec<i16>) {
+        self.memory.apu.sink.drain_i16(buffer);
+    }
+
+    /// Starts recording the audio output to a WAV file, at the current sample rate.
+    /// With `stems`, each channel also gets recorded to its own file next to it, named `<name>.ch1.wav` and so on.
+    /// Any recording already going is finished first.
+    pub fn start_recording(&mut self, path: impl AsRef<Path>, stems: bool) -> Result<()> {
+        self.stop_recording()?;
+        let recorder = Recorder::start(path.as_ref(), self.sample_rate(), stems)?;
+        self.memory.apu.recorder = Some(recorder);
+        Ok(())
+    }
+
+    /// Finishes the current recording, if there is one.
+    pub fn stop_recording(&mut self) -> Result<()> {
+        match self.memory.apu.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.memory.apu.recorder.is_some()
+    }
+
+    /// Starts logging every APU register write to a VGM file, which gets written out once logging stops.
+    /// The log opens with writes that recreate the current APU state. Any log already going is finished first.
+    pub fn start_vgm_log(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        self.stop_vgm_log()?;
+        let vgm = VgmLogger::create(path.as_ref())?;
+        self.memory.apu.start_vgm(vgm);
+        Ok(())
+    }
+
+    /// Marks the current point in the VGM log as the loop point. Marking again moves it.
+    pub fn mark_vgm_loop(&mut self) {
+        if let Some(vgm) = &mut self.memory.apu.vgm {
+            vgm.mark_loop();
+        }
+    }
+
+    /// Ends the current VGM log, if there is one, and writes out the file.
+    pub fn stop_vgm_log(&mut self) -> Result<()> {
+        match self.memory.apu.vgm.take() {
+            Some(vgm) => vgm.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_vgm_logging(&self) -> bool {
+        self.memory.apu.vgm.is_some()
+    }
+
+    /// Starts logging the registers before every instruction to a file, in Gameboy Doctor's format.
+    /// Any trace already going is finished first.
+    pub fn start_trace(&mut self, path: impl AsRef<Path>, labels: bool) -> Result<()> {
+        self.stop_trace()?;
+        self.trace = Some(TraceLogger::create(path.as_ref(), labels)?);
+        Ok(())
+    }
+
+    /// Ends the current trace, if there is one, and flushes the file.
+    pub fn stop_trace(&mut self) -> Result<()> {
+        match self.trace.take() {
+            Some(trace) => trace.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.trace.is_some()
+    }
+
+    /// Makes LY read as this value instead of the line being drawn, or as normal again with None.
+    /// Gameboy Doctor's logs expect 0x90, since then waiting for VBlank always finishes straight away.
+    pub fn set_ly_stub(&mut self, ly: Option<u8>) {
+        self.memory.ppu.ly_stub = ly;
+    }
+
+    /// Starts turning the sound channels' activity into MIDI, which gets written out once exporting stops.
+    /// Any export already going is finished first.
+    pub fn start_midi_export(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        self.stop_midi_export()?;
+        let midi = MidiExporter::create(path.as_ref())?;
+        self.memory.apu.midi = Some(midi);
+        Ok(())
+    }
+
+    /// Ends the current MIDI export, if there is one, and writes out the file.
+    pub fn stop_midi_export(&mut self) -> Result<()> {
+        match self.memory.apu.midi.take() {
+            Some(midi) => midi.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_midi_exporting(&self) -> bool {
+        self.memory.apu.midi.is_some()
+    }
+
+    /// Silences one channel in the audio output. Recorded stems and the oscilloscope still get it.
+    pub fn set_channel_muted(&mut self, channel: AudioChannel, muted: bool) {
+        self.memory.apu.set_muted(channel, muted);
+    }
+
+    /// Soloes one channel in the audio output. While any channel is soloed, all the others are silent.
+    pub fn set_channel_soloed(&mut self, channel: AudioChannel, soloed: bool) {
+        self.memory.apu.set_soloed(channel, soloed);
+    }
+
+    /// True if the channel currently makes it into the audio output, given the mutes and solos.
+    pub fn channel_audible(&self, channel: AudioChannel) -> bool {
+        self.memory.apu.audible()[channel as usize]
+    }
+
+    /// The last [`SCOPE_LENGTH`] points of a channel's raw output (-1.0 to 1.0), oldest first,
+    /// captured at [`SCOPE_RATE`].
+    pub fn oscilloscope(&self, channel: AudioChannel) -> Vec<f32> {
+        self.memory.apu.scope.channel(channel as usize)
+    }
+
     /// Performs one fetch-execute cycle, including interrupt handling.
-    /// Returns the machine cycles completed (4x the number of clock cycles).
+    /// Returns the machine cycles completed (4 clock cycles each, or 2 dots each in double speed).
     pub fn cycle(&mut self) -> Result<i32> {
+        if self.stopped {
+            // Nothing but the joypad wakes the CPU from STOP, and the divider sits still until then
+            self.scheduler.freeze(1);
+            let clocks = self.scheduler.advance(1);
+            self.memory.step(clocks);
+            return Ok(1)
+        }
+
+        if self.halted {
+            // HALT waits for an interrupt to be pending, even one IME won't let through
+            if self.memory.pending_interrupts() == 0 {
+                let clocks = self.scheduler.advance(1);
+                self.memory.step(clocks);
+                return Ok(1)
+            }
+            self.halted = false;
+        }
+
+        if self.ime && self.memory.pending_interrupts() != 0 {
+            let cycles = self.service_interrupt()?;
+            let clocks = self.scheduler.advance(cycles);
+            self.memory.step(clocks);
+            return Ok(cycles)
+        }
+
+        if let Some(mut trace) = self.trace.take() {
+            let logged = trace.log(self);
+            self.trace = Some(trace);
+            logged?;
+        }
+        self.memory.watches.start_instruction(self.memory.program_counter);
         let opcode = self.memory.fetch_byte()?;
-        let cycles = self.execute(opcode)? / 4;
+        let cycles = self.execute(opcode)?;
 
         // EI does not actually set IME until after the next instruction.
         // EI sets set_ime to the number of cycles to delay setting IME.
@@ -64,9 +608,23 @@ impl CPU {
             self.set_ime = -1;
         }
 
+        let clocks = self.scheduler.advance(cycles);
+        self.memory.step(clocks);
+
         Ok(cycles)
     }
 
+    // Takes the highest priority pending interrupt: IME goes off, and the CPU calls its vector
+    fn service_interrupt(&mut self) -> Result<i32> {
+        let bit = self.memory.pending_interrupts().trailing_zeros() as u16;
+        self.memory.acknowledge_interrupt(1 << bit);
+        self.ime = false;
+        self.set_ime = -1;
+        self.memory.push_stack(self.memory.program_counter)?;
+        self.memory.program_counter = 0x0040 + bit * 8;
+        Ok(5)
+    }
+
     fn execute(&mut self, opcode: u8) -> Result<i32> {
         // CB prefix
         if opcode == 0xCB {
@@ -104,3 +662,163 @@ impl CPU {
         }
     }
 }
+
+/// The 8-bit registers making up a pair, high then low.
+fn pair_halves(register: Reg16) -> (Reg8, Reg8) {
+    match register {
+        Reg16::AF => (Reg8::A, Reg8::F),
+        Reg16::BC => (Reg8::B, Reg8::C),
+        Reg16::DE => (Reg8::D, Reg8::E),
+        _ => (Reg8::H, Reg8::L),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boot(program: &[u8]) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.load_rom(program).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn halt_wakes_into_the_interrupt_vector() {
+        // EI, HALT, with the timer interrupt already requested and enabled
+        let mut cpu = boot(&[0xFB, 0x76, 0x00]);
+        cpu.set_stack_pointer(0xFFFE);
+        cpu.poke(0xFFFF, memory::TIMER_INTERRUPT).unwrap();
+        cpu.poke(0xFF0F, memory::TIMER_INTERRUPT).unwrap();
+        cpu.cycle().unwrap();
+        cpu.cycle().unwrap();
+        assert!(cpu.halted() && cpu.ime());
+        assert_eq!(cpu.cycle().unwrap(), 5);
+        assert_eq!(cpu.program_counter(), 0x0050);
+        assert!(!cpu.halted() && !cpu.ime());
+        assert_eq!(cpu.peek(0xFF0F) & memory::TIMER_INTERRUPT, 0);
+        assert_eq!(cpu.stack_pointer(), 0xFFFC);
+        assert_eq!(cpu.peek(0xFFFC), 0x02);
+        assert_eq!(cpu.peek(0xFFFD), 0x01);
+    }
+
+    #[test]
+    fn duplicate_watchpoints_are_removed_one_at_a_time() {
+        let mut cpu = CPU::new();
+        let watchpoint = Watchpoint::new(0xC000, 0xC000, WatchKind::Write);
+        cpu.add_watchpoint(watchpoint);
+        cpu.add_watchpoint(watchpoint);
+        assert!(cpu.remove_watchpoint(&watchpoint));
+        assert_eq!(cpu.watchpoints(), [watchpoint]);
+        assert!(cpu.remove_watchpoint(&watchpoint));
+        assert!(!cpu.remove_watchpoint(&watchpoint));
+    }
+
+    #[test]
+    fn watch_hits_come_from_cpu_data_accesses_only() {
+        // NOP, then LD [$C000],A, which is 3 bytes long
+        let mut cpu = boot(&[0x00, 0xEA, 0x00, 0xC0]);
+        cpu.add_watchpoint(Watchpoint::new(0x0100, 0x0103, WatchKind::Access));
+        cpu.add_watchpoint(Watchpoint::new(0xC000, 0xC000, WatchKind::Access));
+        cpu.poke(0xC000, 0x12).unwrap();
+        cpu.peek(0xC000);
+        assert_eq!(cpu.take_watch_hit(), None);
+
+        // Fetching the instructions, operands included, doesn't count as reading them
+        cpu.cycle().unwrap();
+        assert_eq!(cpu.take_watch_hit(), None);
+        cpu.cycle().unwrap();
+        let hit = cpu.take_watch_hit().unwrap();
+        assert_eq!((hit.pc, hit.address, hit.write), (0x0101, 0xC000, true));
+    }
+
+    #[test]
+    fn cycles_are_machine_cycles() {
+        let mut cpu = boot(&[0x00, 0x00]);
+        assert_eq!(cpu.cycle().unwrap(), 1);
+        assert_eq!(cpu.elapsed_dots(), 4);
+        assert_eq!(cpu.cycle().unwrap(), 1);
+        assert_eq!(cpu.elapsed_dots(), 8);
+    }
+
+    // STOP then nothing but NOPs, on a CGB with KEY1 armed so the STOP switches to double speed
+    fn armed_for_double_speed() -> CPU {
+        let mut program = vec![0x00; 0x4000];
+        program[0] = 0x10;
+        let mut cpu = CPU::with_model(Model::Cgb);
+        cpu.load_rom(&program).unwrap();
+        cpu.poke(memory::KEY1_ADDR, 0x01).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn key1_shows_the_speed_and_armed_switch() {
+        let mut cpu = CPU::with_model(Model::Cgb);
+        assert_eq!(cpu.peek(memory::KEY1_ADDR), 0x7E);
+        // Only the armed bit is writable
+        cpu.poke(memory::KEY1_ADDR, 0xFF).unwrap();
+        assert_eq!(cpu.peek(memory::KEY1_ADDR), 0x7F);
+
+        let mut cpu = armed_for_double_speed();
+        cpu.cycle().unwrap();
+        assert!(cpu.double_speed());
+        assert_eq!(cpu.peek(memory::KEY1_ADDR), 0xFE);
+
+        // A DMG has no KEY1, so STOP really stops
+        let mut cpu = CPU::new();
+        cpu.load_rom(&[0x10, 0x00]).unwrap();
+        cpu.poke(memory::KEY1_ADDR, 0x01).unwrap();
+        assert_eq!(cpu.peek(memory::KEY1_ADDR), 0xFF);
+        cpu.cycle().unwrap();
+        assert!(cpu.stopped() && !cpu.double_speed());
+    }
+
+    #[test]
+    fn speed_switch_pauses_the_cpu_clock() {
+        let mut cpu = armed_for_double_speed();
+        assert_eq!(cpu.cycle().unwrap(), 1 + scheduler::SPEED_SWITCH_CYCLES);
+        // The fixed clock keeps going through the pause, already at the new speed
+        assert_eq!(cpu.elapsed_dots(), (1 + scheduler::SPEED_SWITCH_CYCLES as u64) * 2);
+        // But the divider only saw the one cycle of the STOP itself after resetting
+        assert_eq!(cpu.peek(timer::DIV_ADDR), 0);
+    }
+
+    #[test]
+    fn double_speed_only_speeds_up_the_cpu_clock() {
+        let mut cpu = armed_for_double_speed();
+        cpu.cycle().unwrap();
+        cpu.poke(ppu::LCDC_ADDR, 0x80).unwrap();
+        // Pulse 1 at full volume, with 3 length steps left
+        cpu.poke(apu::NR52_ADDR, 0x80).unwrap();
+        cpu.poke(0xFF12, 0xF0).unwrap();
+        cpu.poke(0xFF11, 61).unwrap();
+        cpu.poke(0xFF14, 0xC0).unwrap();
+
+        let start = cpu.elapsed_dots();
+        let mut expired = None;
+        while expired.is_none() {
+            assert_eq!(cpu.cycle().unwrap(), 1);
+            let dots = cpu.elapsed_dots() - start;
+            assert_eq!(dots % 2, 0, "NOPs take 2 dots in double speed");
+            if dots == 10 * 456 {
+                assert_eq!(cpu.peek(ppu::LY_ADDR), 10);
+                // 4 CPU clocks from the STOP, then twice as many as the dots since
+                assert_eq!(cpu.peek(timer::DIV_ADDR), ((4 + 2 * dots) >> 8) as u8);
+            }
+            if cpu.peek(apu::NR52_ADDR) & 0x01 == 0 {
+                expired = Some(dots);
+            }
+        }
+        // Length steps come every 16384 dots, so the third one is somewhere after the first two whole periods
+        assert!((32768..=49152).contains(&expired.unwrap()), "expired after {} dots", expired.unwrap());
+    }
+
+    #[test]
+    fn cb_instruction_cycles() {
+        // SWAP b, BIT 0,[hl], RLC [hl], SET 7,[hl], RES 0,a
+        let mut cpu = boot(&[0xCB, 0x30, 0xCB, 0x46, 0xCB, 0x06, 0xCB, 0xFE, 0xCB, 0x87]);
+        cpu.set_reg16(Reg16::HL, 0xC000);
+        let cycles: Vec<i32> = (0..5).map(|_| cpu.cycle().unwrap()).collect();
+        assert_eq!(cycles, [2, 3, 4, 4, 2]);
+    }
+}
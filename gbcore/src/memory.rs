@@ -1,11 +1,43 @@
 use anyhow::{anyhow, Result};
+use crate::apu::{Apu, APU_START_ADDR, APU_END_ADDR};
+use crate::cartridge::Cartridge;
+use crate::joypad::{Joypad, P1_ADDR};
+use crate::serial::{Serial, SB_ADDR, SC_ADDR};
+use crate::ppu::{Ppu, BCPS_ADDR, DMA_ADDR, LCDC_ADDR, OAM_END_ADDR, OAM_SIZE, OAM_START_ADDR, OCPD_ADDR, VBK_ADDR, VRAM_END_ADDR, VRAM_START_ADDR, WX_ADDR};
+use crate::scheduler::Clocks;
+use crate::timer::{Timer, DIV_ADDR, TAC_ADDR};
+use crate::watch::Watches;
 
 const MEM_SIZE: usize = 0x10000;
 
+pub const IF_ADDR: u16 = 0xFF0F;
+pub const IE_ADDR: u16 = 0xFFFF;
+pub const KEY0_ADDR: u16 = 0xFF4C;
+pub const KEY1_ADDR: u16 = 0xFF4D;
+pub const BOOT_ADDR: u16 = 0xFF50;
+
+pub const VBLANK_INTERRUPT: u8 = 1 << 0;
+pub const STAT_INTERRUPT: u8 = 1 << 1;
+pub const TIMER_INTERRUPT: u8 = 1 << 2;
+pub const SERIAL_INTERRUPT: u8 = 1 << 3;
+pub const JOYPAD_INTERRUPT: u8 = 1 << 4;
+
 pub struct Memory {
     ram: [u8; MEM_SIZE],
+    // Mapped over 0x0000-0x7FFF and 0xA000-0xBFFF. None when the ROM lives in the flat RAM instead.
+    cartridge: Option<Cartridge>,
+    // Mapped over the start of the cartridge until a write to BOOT_ADDR
+    boot_rom: Option<Vec<u8>>,
+    // KEY1 only exists on the CGB
+    cgb: bool,
     pub program_counter: u16,
     pub stack_pointer: u16,
+    pub timer: Timer,
+    pub apu: Apu,
+    pub ppu: Ppu,
+    pub joypad: Joypad,
+    pub serial: Serial,
+    pub watches: Watches,
 }
 
 impl Default for Memory {
@@ -18,8 +50,17 @@ impl Memory {
     pub fn new(program_counter: u16, stack_pointer: u16) -> Self {
         Self {
             ram: [0; MEM_SIZE],
+            cartridge: None,
+            boot_rom: None,
+            cgb: false,
             program_counter,
             stack_pointer,
+            timer: Timer::new(),
+            apu: Apu::new(),
+            ppu: Ppu::new(),
+            joypad: Joypad::new(),
+            serial: Serial::new(),
+            watches: Watches::default(),
         }
     }
 
@@ -34,17 +75,44 @@ impl Memory {
         Ok(())
     }
 
+    pub fn load_cartridge(&mut self, cartridge: Cartridge) {
+        self.cartridge = Some(cartridge);
+    }
+
+    pub fn cartridge(&self) -> Option<&Cartridge> {
+        self.cartridge.as_ref()
+    }
+
+    pub fn cartridge_mut(&mut self) -> Option<&mut Cartridge> {
+        self.cartridge.as_mut()
+    }
+
+    /// Maps a boot ROM over the cartridge: 0x0000-0x00FF for a DMG boot ROM,
+    /// and 0x0200-0x08FF as well for a CGB one, leaving the cartridge header visible in between.
+    pub fn load_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom);
+    }
+
+    pub fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+        self.apu.set_cgb(cgb);
+        self.ppu.set_cgb(cgb);
+    }
+
+    fn boot_rom_byte(&self, address: u16) -> Option<u8> {
+        match address {
+            0x0100..=0x01FF => None,
+            _ => self.boot_rom.as_ref()?.get(address as usize).copied(),
+        }
+    }
+
     pub fn fetch_byte(&mut self) -> Result<u8> {
-        match self.ram.get(self.program_counter as usize) {
-            Some(byte) => {
-                match self.program_counter.checked_add(1) {
-                    Some(x) => self.program_counter = x,
-                    None => return Err(anyhow!("Program counter overflow"))
-                }
-                Ok(*byte)
-            },
-            None => Err(anyhow!("Program counter is out of bounds: {}", self.program_counter))
+        let byte = self.peek_byte(self.program_counter)?;
+        match self.program_counter.checked_add(1) {
+            Some(x) => self.program_counter = x,
+            None => return Err(anyhow!("Program counter overflow"))
         }
+        Ok(byte)
     }
 
     pub fn fetch_two_bytes(&mut self) -> Result<u16> {
@@ -53,7 +121,31 @@ impl Memory {
         Ok((data_high as u16) << 8 | data_low as u16)
     }
 
+    /// Reads a byte for the CPU, which watchpoints see.
     pub fn read_byte(&self, address: u16) -> Result<u8> {
+        let byte = self.peek_byte(address)?;
+        if self.watches.is_watching() {
+            self.watches.read(address, byte);
+        }
+        Ok(byte)
+    }
+
+    /// Reads a byte without any watchpoints seeing it.
+    pub fn peek_byte(&self, address: u16) -> Result<u8> {
+        match address {
+            P1_ADDR => return Ok(self.joypad.read()),
+            SB_ADDR..=SC_ADDR => return Ok(self.serial.read(address)),
+            DIV_ADDR..=TAC_ADDR => return Ok(self.timer.read(address)),
+            APU_START_ADDR..=APU_END_ADDR => return Ok(self.apu.read(address)),
+            VRAM_START_ADDR..=VRAM_END_ADDR | OAM_START_ADDR..=OAM_END_ADDR => return Ok(self.ppu.read(address)),
+            DMA_ADDR => (),
+            LCDC_ADDR..=WX_ADDR | VBK_ADDR | BCPS_ADDR..=OCPD_ADDR => return Ok(self.ppu.read(address)),
+            KEY1_ADDR if self.cgb => return Ok(self.ram[address as usize] | 0x7E),
+            KEY1_ADDR => return Ok(0xFF),
+            0x0000..=0x08FF if let Some(byte) = self.boot_rom_byte(address) => return Ok(byte),
+            0x0000..=0x7FFF | 0xA000..=0xBFFF if let Some(cartridge) = &self.cartridge => return Ok(cartridge.read(address)),
+            _ => (),
+        }
         match self.ram.get(address as usize) {
             Some(byte) => Ok(*byte),
             None => Err(anyhow!("Attempted to read outside of RAM at address: {}", address ))
@@ -66,7 +158,72 @@ impl Memory {
         Ok((data_high as u16) << 8 | data_low as u16)
     }
 
+    /// Writes a byte for the CPU, which watchpoints see.
     pub fn write_byte(&mut self, address: u16, data: u8) -> Result<()> {
+        if self.watches.is_watching() {
+            let old = self.peek_byte(address).unwrap_or(0xFF);
+            self.watches.write(address, old, data);
+        }
+        self.poke_byte(address, data)
+    }
+
+    /// Writes a byte without any watchpoints seeing it.
+    pub fn poke_byte(&mut self, address: u16, data: u8) -> Result<()> {
+        match address {
+            P1_ADDR => {
+                self.joypad.write(data);
+                return Ok(())
+            },
+            SB_ADDR..=SC_ADDR => {
+                self.serial.write(address, data);
+                return Ok(())
+            },
+            DIV_ADDR..=TAC_ADDR => {
+                self.timer.write(address, data);
+                return Ok(())
+            },
+            APU_START_ADDR..=APU_END_ADDR => {
+                self.apu.write(address, data);
+                return Ok(())
+            },
+            VRAM_START_ADDR..=VRAM_END_ADDR | OAM_START_ADDR..=OAM_END_ADDR => {
+                self.ppu.write(address, data);
+                return Ok(())
+            },
+            // OAM DMA copies 160 bytes from data * 0x100 all at once, rather than over 160 M-cycles
+            DMA_ADDR => {
+                let source = (data as u16) << 8;
+                let mut oam = [0; OAM_SIZE];
+                for (offset, byte) in oam.iter_mut().enumerate() {
+                    *byte = self.peek_byte(source + offset as u16)?;
+                }
+                self.ppu.write_oam(&oam);
+            },
+            LCDC_ADDR..=WX_ADDR | VBK_ADDR | BCPS_ADDR..=OCPD_ADDR => {
+                self.ppu.write(address, data);
+                return Ok(())
+            },
+            // Only the "prepare speed switch" bit is writable, the current speed is read only
+            KEY1_ADDR => {
+                if self.cgb {
+                    let key1 = &mut self.ram[address as usize];
+                    *key1 = (*key1 & 0x80) | (data & 0x01);
+                }
+                return Ok(())
+            },
+            // The CGB boot ROM sets bit 2 here for cartridges without CGB support, which get drawn like on a DMG
+            KEY0_ADDR if self.cgb && self.boot_rom.is_some() => {
+                self.ppu.set_cgb(data & 0x04 == 0);
+                return Ok(())
+            },
+            // Any write here unmaps the boot ROM for good
+            BOOT_ADDR if data != 0 => self.boot_rom = None,
+            0x0000..=0x7FFF | 0xA000..=0xBFFF if let Some(cartridge) = &mut self.cartridge => {
+                cartridge.write(address, data);
+                return Ok(())
+            },
+            _ => (),
+        }
         match self.ram.get_mut(address as usize) {
             Some(byte) => {
                 *byte = data;
@@ -99,4 +256,50 @@ impl Memory {
         self.write_two_bytes(self.stack_pointer, data)?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    pub fn request_interrupt(&mut self, interrupt: u8) {
+        self.ram[IF_ADDR as usize] |= interrupt;
+    }
+
+    /// Clears an interrupt's IF bit once the CPU has taken it.
+    pub fn acknowledge_interrupt(&mut self, interrupt: u8) {
+        self.ram[IF_ADDR as usize] &= !interrupt;
+    }
+
+    /// Interrupts both requested and enabled, whether or not IME lets them through.
+    pub fn pending_interrupts(&self) -> u8 {
+        self.ram[IE_ADDR as usize] & self.ram[IF_ADDR as usize] & 0x1F
+    }
+
+    /// Draws a cartridge without CGB support the way a DMG would, like the CGB boot ROM arranges through KEY0.
+    pub fn set_dmg_compatibility(&mut self) {
+        self.ppu.set_cgb(false);
+    }
+
+    /// True when KEY1 has been armed so the next STOP switches speed.
+    pub fn speed_switch_armed(&self) -> bool {
+        self.cgb && self.ram[KEY1_ADDR as usize] & 0x01 != 0
+    }
+
+    /// Reflects the current CPU speed in KEY1, which also disarms the switch.
+    pub fn set_double_speed(&mut self, double_speed: bool) {
+        self.ram[KEY1_ADDR as usize] = (double_speed as u8) << 7;
+        self.timer.set_double_speed(double_speed);
+    }
+
+    /// Advances everything on the bus by the time taken by the last instruction.
+    pub fn step(&mut self, clocks: Clocks) {
+        if self.timer.step(clocks.cpu) {
+            self.request_interrupt(TIMER_INTERRUPT);
+        }
+        if self.serial.step(clocks.cpu) {
+            self.request_interrupt(SERIAL_INTERRUPT);
+        }
+        for _ in 0..self.timer.take_sequencer_clocks() {
+            self.apu.clock_sequencer();
+        }
+        self.apu.step(clocks.dots);
+        let interrupts = self.ppu.step(clocks.dots);
+        self.request_interrupt(interrupts);
+    }
+}
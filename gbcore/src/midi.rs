@@ -0,0 +1,265 @@
+//! MIDI
+//!
+//! Turns what the APU channels are doing into an editable Standard MIDI File.
+//! Channel state is sampled a few thousand times a second: triggers become note-ons,
+//! pitch changes within a couple of semitones become pitch bends (anything further starts a new note),
+//! and envelope volume changes become expression changes.
+//! Each channel gets its own track, with the noise channel mapped to General MIDI percussion.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use anyhow::{Context, Result};
+use crate::apu::ChannelState;
+use crate::scheduler::DOTS_PER_SECOND;
+
+// Dots between looks at the channel state, a little over 4 kHz
+const POLL_INTERVAL: u32 = 1024;
+
+// 480 ticks per quarter note at 120 BPM, so 960 ticks per second
+const TICKS_PER_QUARTER: u16 = 480;
+const MICROSECONDS_PER_QUARTER: u32 = 500_000;
+const TICKS_PER_SECOND: u64 = 960;
+
+// Semitones covered by the full pitch bend range, the General MIDI default
+const BEND_RANGE: f64 = 2.0;
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+const PROGRAM_CHANGE: u8 = 0xC0;
+const PITCH_BEND: u8 = 0xE0;
+const EXPRESSION: u8 = 11;
+
+const PERCUSSION_CHANNEL: u8 = 9;
+const KICK: u8 = 36;
+const SNARE: u8 = 38;
+const CLOSED_HI_HAT: u8 = 42;
+
+const TRACK_NAMES: [&str; 4] = ["Pulse 1", "Pulse 2", "Wave", "Noise"];
+// MIDI channel and General MIDI program for each APU channel
+const CHANNELS: [u8; 4] = [0, 1, 2, PERCUSSION_CHANNEL];
+const PROGRAMS: [u8; 4] = [80, 80, 81, 0]; // Square lead, square lead, sawtooth lead
+
+struct Track {
+    channel: u8,
+    percussion: bool,
+    events: Vec<(u64, Vec<u8>)>,
+    note: Option<u8>,
+    // Volume the current note started at, which expression is measured against
+    note_volume: u8,
+    volume: u8,
+    frequency: f64,
+    triggers: u32,
+}
+
+impl Track {
+    fn new(index: usize) -> Self {
+        let channel = CHANNELS[index];
+        let mut events = Vec::new();
+        let mut name = vec![0xFF, 0x03, TRACK_NAMES[index].len() as u8];
+        name.extend(TRACK_NAMES[index].bytes());
+        events.push((0, name));
+        if channel != PERCUSSION_CHANNEL {
+            events.push((0, vec![PROGRAM_CHANGE | channel, PROGRAMS[index]]));
+        }
+        Self {
+            channel,
+            percussion: channel == PERCUSSION_CHANNEL,
+            events,
+            note: None,
+            note_volume: 0,
+            volume: 0,
+            frequency: 0.0,
+            triggers: 0,
+        }
+    }
+
+    fn observe(&mut self, tick: u64, state: &ChannelState) {
+        let audible = state.enabled && state.volume > 0;
+        let triggered = state.triggers != self.triggers;
+        self.triggers = state.triggers;
+
+        if self.note.is_some() && (!audible || triggered) {
+            self.note_off(tick);
+        }
+        if !audible {
+            return
+        }
+
+        if self.note.is_none() {
+            if !self.percussion {
+                self.note_on(tick, nearest_note(state.frequency), state);
+            } else if triggered {
+                // Drums only start on a trigger, not when a fading hit gets louder again
+                self.note_on(tick, drum_for(state), state);
+            }
+        } else if !self.percussion {
+            if state.frequency != self.frequency {
+                self.frequency = state.frequency;
+                let note = self.note.unwrap_or_default();
+                let offset = semitones(state.frequency) - note as f64;
+                if offset.abs() <= BEND_RANGE {
+                    self.bend(tick, offset);
+                } else {
+                    // Too far to bend, so slide into a new note
+                    self.note_off(tick);
+                    self.note_on(tick, nearest_note(state.frequency), state);
+                }
+            }
+            if state.volume != self.volume {
+                self.volume = state.volume;
+                let expression = (127 * state.volume as u32 / self.note_volume as u32).min(127) as u8;
+                self.events.push((tick, vec![CONTROL_CHANGE | self.channel, EXPRESSION, expression]));
+            }
+        }
+    }
+
+    fn note_on(&mut self, tick: u64, note: u8, state: &ChannelState) {
+        let velocity = (state.volume as u32 * 127 / 15).max(1) as u8;
+        if !self.percussion {
+            self.events.push((tick, vec![CONTROL_CHANGE | self.channel, EXPRESSION, 127]));
+            self.bend(tick, semitones(state.frequency) - note as f64);
+        }
+        self.events.push((tick, vec![NOTE_ON | self.channel, note, velocity]));
+        self.note = Some(note);
+        self.note_volume = state.volume;
+        self.volume = state.volume;
+        self.frequency = state.frequency;
+    }
+
+    fn note_off(&mut self, tick: u64) {
+        if let Some(note) = self.note.take() {
+            self.events.push((tick, vec![NOTE_OFF | self.channel, note, 0]));
+        }
+    }
+
+    fn bend(&mut self, tick: u64, semitones: f64) {
+        let value = (8192.0 + semitones / BEND_RANGE * 8191.0).round().clamp(0.0, 16383.0) as u16;
+        self.events.push((tick, vec![PITCH_BEND | self.channel, (value & 0x7F) as u8, (value >> 7) as u8]));
+    }
+
+    fn write(&self, file: &mut impl Write, end: u64) -> Result<()> {
+        let mut data = Vec::new();
+        let mut last = 0;
+        for (tick, event) in &self.events {
+            write_vlq(&mut data, tick - last);
+            data.extend(event);
+            last = *tick;
+        }
+        // End of track
+        write_vlq(&mut data, end - last);
+        data.extend([0xFF, 0x2F, 0x00]);
+        write_chunk(file, b"MTrk", &data)
+    }
+}
+
+pub struct MidiExporter {
+    file: File,
+    tracks: Vec<Track>,
+    dots: u64,
+    countdown: u32,
+}
+
+impl MidiExporter {
+    /// Creates the file up front, so a bad path fails right away rather than when exporting stops.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(Self {
+            file,
+            tracks: (0..4).map(Track::new).collect(),
+            dots: 0,
+            countdown: POLL_INTERVAL,
+        })
+    }
+
+    /// Moves time forward, returning true when it's time to look at the channels again.
+    pub fn step(&mut self, dots: u32) -> bool {
+        self.dots += dots as u64;
+        match self.countdown.checked_sub(dots) {
+            Some(countdown) if countdown > 0 => {
+                self.countdown = countdown;
+                false
+            },
+            _ => {
+                self.countdown = POLL_INTERVAL;
+                true
+            },
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.dots * TICKS_PER_SECOND / DOTS_PER_SECOND
+    }
+
+    pub fn observe(&mut self, states: &[ChannelState; 4]) {
+        let tick = self.tick();
+        for (track, state) in self.tracks.iter_mut().zip(states) {
+            track.observe(tick, state);
+        }
+    }
+
+    /// Ends any notes still playing and writes out the whole file.
+    pub fn finish(mut self) -> Result<()> {
+        let end = self.tick();
+        for track in &mut self.tracks {
+            track.note_off(end);
+        }
+
+        let mut file = BufWriter::new(self.file);
+        // Format 1 with a tempo track ahead of the channel tracks
+        let mut header = Vec::new();
+        header.extend(1u16.to_be_bytes());
+        header.extend((self.tracks.len() as u16 + 1).to_be_bytes());
+        header.extend(TICKS_PER_QUARTER.to_be_bytes());
+        write_chunk(&mut file, b"MThd", &header)?;
+
+        let mut tempo = vec![0x00, 0xFF, 0x51, 0x03];
+        tempo.extend(&MICROSECONDS_PER_QUARTER.to_be_bytes()[1..]);
+        tempo.extend([0x00, 0xFF, 0x2F, 0x00]);
+        write_chunk(&mut file, b"MTrk", &tempo)?;
+
+        for track in &self.tracks {
+            track.write(&mut file, end)?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+// Fractional MIDI note number for a frequency, where 69 is A4 at 440 Hz
+fn semitones(frequency: f64) -> f64 {
+    69.0 + 12.0 * (frequency / 440.0).log2()
+}
+
+fn nearest_note(frequency: f64) -> u8 {
+    semitones(frequency).round().clamp(0.0, 127.0) as u8
+}
+
+// Rough guess at which drum a noise hit sounds like, from how fast the LFSR is clocked
+fn drum_for(state: &ChannelState) -> u8 {
+    if state.narrow || state.frequency >= 32768.0 {
+        CLOSED_HI_HAT
+    } else if state.frequency >= 4096.0 {
+        SNARE
+    } else {
+        KICK
+    }
+}
+
+fn write_vlq(data: &mut Vec<u8>, value: u64) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    data.extend(bytes.iter().rev());
+}
+
+fn write_chunk(file: &mut impl Write, id: &[u8; 4], data: &[u8]) -> Result<()> {
+    file.write_all(id)?;
+    file.write_all(&(data.len() as u32).to_be_bytes())?;
+    file.write_all(data)?;
+    Ok(())
+}
@@ -0,0 +1,540 @@
+//! PPU
+//!
+//! Draws the picture into a 160x144 framebuffer, one scanline at a time.
+//! Each visible line goes through OAM scan (mode 2), drawing (mode 3), and HBlank (mode 0),
+//! then 10 lines of VBlank (mode 1) finish off the frame.
+//! Drawing always takes its minimum 172 dots here, and the whole line is drawn in one go when it ends.
+//!
+//! On a CGB there's a second VRAM bank, holding tile attributes for the maps and more tile data,
+//! and colors come from palette RAM instead of the DMG's 4 shades.
+
+use crate::memory::{STAT_INTERRUPT, VBLANK_INTERRUPT};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+pub const VRAM_START_ADDR: u16 = 0x8000;
+pub const VRAM_END_ADDR: u16 = 0x9FFF;
+pub const OAM_START_ADDR: u16 = 0xFE00;
+pub const OAM_END_ADDR: u16 = 0xFE9F;
+pub const OAM_SIZE: usize = 0xA0;
+
+pub const LCDC_ADDR: u16 = 0xFF40;
+pub const STAT_ADDR: u16 = 0xFF41;
+pub const SCY_ADDR: u16 = 0xFF42;
+pub const SCX_ADDR: u16 = 0xFF43;
+pub const LY_ADDR: u16 = 0xFF44;
+pub const LYC_ADDR: u16 = 0xFF45;
+pub const DMA_ADDR: u16 = 0xFF46;
+pub const BGP_ADDR: u16 = 0xFF47;
+pub const OBP0_ADDR: u16 = 0xFF48;
+pub const OBP1_ADDR: u16 = 0xFF49;
+pub const WY_ADDR: u16 = 0xFF4A;
+pub const WX_ADDR: u16 = 0xFF4B;
+pub const VBK_ADDR: u16 = 0xFF4F;
+pub const BCPS_ADDR: u16 = 0xFF68;
+pub const BCPD_ADDR: u16 = 0xFF69;
+pub const OCPS_ADDR: u16 = 0xFF6A;
+pub const OCPD_ADDR: u16 = 0xFF6B;
+
+const DOTS_PER_LINE: u32 = 456;
+const LINES_PER_FRAME: u8 = 154;
+const OAM_SCAN_END: u32 = 80;
+const DRAWING_END: u32 = OAM_SCAN_END + 172;
+const SPRITES_PER_LINE: usize = 10;
+const VRAM_BANK_SIZE: usize = 0x2000;
+// 8 palettes of 4 colors, 2 bytes each
+const PALETTE_RAM_SIZE: usize = 64;
+
+/// The four DMG shades, lightest to darkest, as 0x00RRGGBB.
+pub const SHADES: [u32; 4] = [0x00E0F8D0, 0x0088C070, 0x00346856, 0x00081820];
+
+// LCDC bits
+const LCD_ENABLE: u8 = 1 << 7;
+const WINDOW_MAP: u8 = 1 << 6;
+const WINDOW_ENABLE: u8 = 1 << 5;
+const TILE_DATA: u8 = 1 << 4;
+const BG_MAP: u8 = 1 << 3;
+const SPRITE_SIZE: u8 = 1 << 2;
+const SPRITE_ENABLE: u8 = 1 << 1;
+const BG_ENABLE: u8 = 1 << 0;
+
+// STAT interrupt sources
+const LYC_SOURCE: u8 = 1 << 6;
+const OAM_SOURCE: u8 = 1 << 5;
+const VBLANK_SOURCE: u8 = 1 << 4;
+const HBLANK_SOURCE: u8 = 1 << 3;
+
+// CGB BG map attribute and OAM attribute bits. The palette and bank bits are the same for both
+const PRIORITY: u8 = 1 << 7;
+const Y_FLIP: u8 = 1 << 6;
+const X_FLIP: u8 = 1 << 5;
+const DMG_PALETTE: u8 = 1 << 4;
+const BANK: u8 = 1 << 3;
+const CGB_PALETTE: u8 = 0x07;
+
+// Palette index register bits
+const AUTO_INCREMENT: u8 = 1 << 7;
+const PALETTE_INDEX: u8 = 0x3F;
+
+/// One set of CGB palette RAM, reached through an index register and a data register.
+struct PaletteRam {
+    data: [u8; PALETTE_RAM_SIZE],
+    index: u8,
+}
+
+impl PaletteRam {
+    fn new() -> Self {
+        // Starts out all white
+        Self { data: [0xFF; PALETTE_RAM_SIZE], index: 0 }
+    }
+
+    fn read_index(&self) -> u8 {
+        0x40 | self.index
+    }
+
+    fn write_index(&mut self, data: u8) {
+        self.index = data & (AUTO_INCREMENT | PALETTE_INDEX);
+    }
+
+    fn read_data(&self) -> u8 {
+        self.data[(self.index & PALETTE_INDEX) as usize]
+    }
+
+    fn write_data(&mut self, data: u8) {
+        self.data[(self.index & PALETTE_INDEX) as usize] = data;
+        if self.index & AUTO_INCREMENT != 0 {
+            self.index = AUTO_INCREMENT | (self.index + 1) & PALETTE_INDEX;
+        }
+    }
+
+    /// A color as 0x00RRGGBB, scaling each 5 bit channel up to 8 bits.
+    fn color(&self, palette: u8, color: u8) -> u32 {
+        let offset = (palette as usize * 4 + color as usize) * 2;
+        let rgb555 = u16::from_le_bytes([self.data[offset], self.data[offset + 1]]) as u32;
+        let channel = |shift: u32| {
+            let channel = rgb555 >> shift & 0x1F;
+            channel << 3 | channel >> 2
+        };
+        channel(0) << 16 | channel(5) << 8 | channel(10)
+    }
+}
+
+pub struct Ppu {
+    // Both banks back to back, though a DMG only ever uses the first
+    vram: [u8; VRAM_BANK_SIZE * 2],
+    vram_bank: u8,
+    bg_palettes: PaletteRam,
+    obj_palettes: PaletteRam,
+    // Drawing with CGB attributes and palettes, rather than as a DMG
+    cgb: bool,
+    oam: [u8; OAM_SIZE],
+    lcdc: u8,
+    // Only the interrupt source bits, mode and coincidence are worked out on read
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    // Position within the current line
+    line_dots: u32,
+    // The window keeps its own line count, which only moves on lines it actually shows up on
+    window_line: u8,
+    // STAT interrupts fire on the rising edge of all the sources ORed together
+    stat_line: bool,
+    framebuffer: Vec<u32>,
+    frames: u64,
+    // What LY reads as regardless of the real line, for matching logs from emulators that stub it
+    pub ly_stub: Option<u8>,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Self {
+            vram: [0; VRAM_BANK_SIZE * 2],
+            vram_bank: 0,
+            bg_palettes: PaletteRam::new(),
+            obj_palettes: PaletteRam::new(),
+            cgb: false,
+            oam: [0; OAM_SIZE],
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            line_dots: 0,
+            window_line: 0,
+            stat_line: false,
+            framebuffer: vec![SHADES[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frames: 0,
+            ly_stub: None,
+        }
+    }
+
+    /// The last frame drawn, row by row, as 0x00RRGGBB.
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.framebuffer
+    }
+
+    /// Number of frames finished since power on, counted at the start of each VBlank.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Switches between drawing as a CGB and as a DMG, which also decides whether the CGB registers exist.
+    pub fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+        let blank = self.blank();
+        self.framebuffer.fill(blank);
+    }
+
+    // What a switched off screen shows
+    fn blank(&self) -> u32 {
+        if self.cgb { 0x00FFFFFF } else { SHADES[0] }
+    }
+
+    fn vram_offset(&self, address: u16) -> usize {
+        self.vram_bank as usize * VRAM_BANK_SIZE + (address - VRAM_START_ADDR) as usize
+    }
+
+    fn enabled(&self) -> bool {
+        self.lcdc & LCD_ENABLE != 0
+    }
+
+    fn mode(&self) -> u8 {
+        if !self.enabled() {
+            return 0
+        }
+        match self.line_dots {
+            _ if self.ly as usize >= SCREEN_HEIGHT => 1,
+            0..OAM_SCAN_END => 2,
+            OAM_SCAN_END..DRAWING_END => 3,
+            _ => 0,
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            VRAM_START_ADDR..=VRAM_END_ADDR => self.vram[self.vram_offset(address)],
+            OAM_START_ADDR..=OAM_END_ADDR => self.oam[(address - OAM_START_ADDR) as usize],
+            LCDC_ADDR => self.lcdc,
+            STAT_ADDR => 0x80 | self.stat | ((self.ly == self.lyc) as u8) << 2 | self.mode(),
+            SCY_ADDR => self.scy,
+            SCX_ADDR => self.scx,
+            LY_ADDR => self.ly_stub.unwrap_or(self.ly),
+            LYC_ADDR => self.lyc,
+            BGP_ADDR => self.bgp,
+            OBP0_ADDR => self.obp0,
+            OBP1_ADDR => self.obp1,
+            WY_ADDR => self.wy,
+            WX_ADDR => self.wx,
+            VBK_ADDR if self.cgb => 0xFE | self.vram_bank,
+            BCPS_ADDR if self.cgb => self.bg_palettes.read_index(),
+            BCPD_ADDR if self.cgb => self.bg_palettes.read_data(),
+            OCPS_ADDR if self.cgb => self.obj_palettes.read_index(),
+            OCPD_ADDR if self.cgb => self.obj_palettes.read_data(),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) {
+        match address {
+            VRAM_START_ADDR..=VRAM_END_ADDR => self.vram[self.vram_offset(address)] = data,
+            OAM_START_ADDR..=OAM_END_ADDR => self.oam[(address - OAM_START_ADDR) as usize] = data,
+            LCDC_ADDR => {
+                let was_enabled = self.enabled();
+                self.lcdc = data;
+                // Switching off parks the PPU at the start of the frame and blanks the screen
+                if was_enabled && !self.enabled() {
+                    self.ly = 0;
+                    self.line_dots = 0;
+                    self.window_line = 0;
+                    let blank = self.blank();
+                    self.framebuffer.fill(blank);
+                }
+            },
+            STAT_ADDR => self.stat = data & 0x78,
+            SCY_ADDR => self.scy = data,
+            SCX_ADDR => self.scx = data,
+            LYC_ADDR => self.lyc = data,
+            BGP_ADDR => self.bgp = data,
+            OBP0_ADDR => self.obp0 = data,
+            OBP1_ADDR => self.obp1 = data,
+            WY_ADDR => self.wy = data,
+            WX_ADDR => self.wx = data,
+            VBK_ADDR if self.cgb => self.vram_bank = data & 0x01,
+            BCPS_ADDR if self.cgb => self.bg_palettes.write_index(data),
+            BCPD_ADDR if self.cgb => self.bg_palettes.write_data(data),
+            OCPS_ADDR if self.cgb => self.obj_palettes.write_index(data),
+            OCPD_ADDR if self.cgb => self.obj_palettes.write_data(data),
+            // LY is read only
+            _ => (),
+        }
+    }
+
+    /// Fills OAM in one go, the result of an OAM DMA transfer.
+    pub fn write_oam(&mut self, data: &[u8; OAM_SIZE]) {
+        self.oam = *data;
+    }
+
+    /// Advances by some number of dots, returning the interrupts requested along the way.
+    pub fn step(&mut self, dots: u32) -> u8 {
+        let mut interrupts = 0;
+        if !self.enabled() {
+            return interrupts
+        }
+        let mut remaining = dots;
+        while remaining > 0 {
+            // Move up to the next mode change at most, so nothing gets skipped over
+            let boundary = match self.line_dots {
+                0..OAM_SCAN_END => OAM_SCAN_END,
+                OAM_SCAN_END..DRAWING_END => DRAWING_END,
+                _ => DOTS_PER_LINE,
+            };
+            let dots = (boundary - self.line_dots).min(remaining);
+            self.line_dots += dots;
+            remaining -= dots;
+
+            if self.line_dots == DRAWING_END && (self.ly as usize) < SCREEN_HEIGHT {
+                self.draw_line();
+            }
+            if self.line_dots == DOTS_PER_LINE {
+                self.line_dots = 0;
+                self.ly = (self.ly + 1) % LINES_PER_FRAME;
+                if self.ly as usize == SCREEN_HEIGHT {
+                    self.frames += 1;
+                    interrupts |= VBLANK_INTERRUPT;
+                }
+                if self.ly == 0 {
+                    self.window_line = 0;
+                }
+            }
+
+            let stat_line = match self.mode() {
+                0 => self.stat & HBLANK_SOURCE != 0,
+                1 => self.stat & VBLANK_SOURCE != 0,
+                2 => self.stat & OAM_SOURCE != 0,
+                _ => false,
+            } || (self.stat & LYC_SOURCE != 0 && self.ly == self.lyc);
+            if stat_line && !self.stat_line {
+                interrupts |= STAT_INTERRUPT;
+            }
+            self.stat_line = stat_line;
+        }
+        interrupts
+    }
+
+    // Color index (0-3) of one pixel from a tile map, before the palette, along with the tile's CGB attributes
+    fn tile_pixel(&self, high_map: bool, x: u8, y: u8) -> (u8, u8) {
+        let map = if high_map { 0x1C00 } else { 0x1800 };
+        let entry = map + (y as usize / 8) * 32 + x as usize / 8;
+        let tile = self.vram[entry];
+        let attributes = if self.cgb { self.vram[VRAM_BANK_SIZE + entry] } else { 0 };
+        // Either 0x8000 with unsigned tile numbers, or 0x9000 with signed ones
+        let tile_addr = if self.lcdc & TILE_DATA != 0 {
+            tile as usize * 16
+        } else {
+            (0x1000 + tile as i8 as isize * 16) as usize
+        };
+        let bank = if attributes & BANK != 0 { VRAM_BANK_SIZE } else { 0 };
+        let row = if attributes & Y_FLIP != 0 { 7 - y % 8 } else { y % 8 };
+        let row = bank + tile_addr + row as usize * 2;
+        let bit = if attributes & X_FLIP != 0 { x % 8 } else { 7 - x % 8 };
+        ((self.vram[row + 1] >> bit & 1) << 1 | self.vram[row] >> bit & 1, attributes)
+    }
+
+    fn draw_line(&mut self) {
+        let mut line = [self.blank(); SCREEN_WIDTH];
+        // Background and window color indices, since sprites can hide behind anything but color 0
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+        // CGB tiles that want to be drawn over sprites
+        let mut bg_priority = [false; SCREEN_WIDTH];
+
+        // On a CGB the background is always drawn, and LCDC bit 0 only decides whether it can cover sprites
+        if self.cgb || self.lcdc & BG_ENABLE != 0 {
+            let window_visible = self.lcdc & WINDOW_ENABLE != 0 && self.ly >= self.wy;
+            let mut window_drawn = false;
+            for x in 0..SCREEN_WIDTH {
+                let (color, attributes) = if window_visible && x + 7 >= self.wx as usize {
+                    window_drawn = true;
+                    self.tile_pixel(self.lcdc & WINDOW_MAP != 0, (x + 7 - self.wx as usize) as u8, self.window_line)
+                } else {
+                    self.tile_pixel(self.lcdc & BG_MAP != 0, (x as u8).wrapping_add(self.scx), self.ly.wrapping_add(self.scy))
+                };
+                bg_colors[x] = color;
+                bg_priority[x] = attributes & PRIORITY != 0;
+                line[x] = if self.cgb {
+                    self.bg_palettes.color(attributes & CGB_PALETTE, color)
+                } else {
+                    shade(self.bgp, color)
+                };
+            }
+            if window_drawn {
+                self.window_line += 1;
+            }
+        }
+
+        if self.lcdc & SPRITE_ENABLE != 0 {
+            let height = if self.lcdc & SPRITE_SIZE != 0 { 16 } else { 8 };
+            let mut sprites: Vec<&[u8]> = self.oam.chunks(4)
+                .filter(|sprite| ((self.ly as i16 + 16 - sprite[0] as i16) as u16) < height)
+                .take(SPRITES_PER_LINE)
+                .collect();
+            // On a DMG lower X wins, then earlier in OAM, so draw the winners last.
+            // A CGB only goes by OAM order
+            if !self.cgb {
+                sprites.sort_by_key(|sprite| sprite[1]);
+            }
+            let master_priority = !self.cgb || self.lcdc & BG_ENABLE != 0;
+            for sprite in sprites.iter().rev() {
+                let [y, x, tile, attributes] = [sprite[0], sprite[1], sprite[2], sprite[3]];
+                let mut row = (self.ly as u16 + 16 - y as u16) as usize;
+                if attributes & Y_FLIP != 0 {
+                    row = height as usize - 1 - row;
+                }
+                let tile = if height == 16 { tile & 0xFE } else { tile };
+                let bank = if self.cgb && attributes & BANK != 0 { VRAM_BANK_SIZE } else { 0 };
+                let addr = bank + tile as usize * 16 + row * 2;
+                let (low, high) = (self.vram[addr], self.vram[addr + 1]);
+                let palette = if attributes & DMG_PALETTE != 0 { self.obp1 } else { self.obp0 };
+                for column in 0..8 {
+                    let Some(screen_x) = (x as usize + column).checked_sub(8).filter(|&x| x < SCREEN_WIDTH) else {
+                        continue
+                    };
+                    let bit = if attributes & X_FLIP != 0 { column } else { 7 - column };
+                    let color = (high >> bit & 1) << 1 | low >> bit & 1;
+                    let behind = master_priority && bg_colors[screen_x] != 0
+                        && (attributes & PRIORITY != 0 || bg_priority[screen_x]);
+                    if color != 0 && !behind {
+                        line[screen_x] = if self.cgb {
+                            self.obj_palettes.color(attributes & CGB_PALETTE, color)
+                        } else {
+                            shade(palette, color)
+                        };
+                    }
+                }
+            }
+        }
+
+        let start = self.ly as usize * SCREEN_WIDTH;
+        self.framebuffer[start..start + SCREEN_WIDTH].copy_from_slice(&line);
+    }
+}
+
+fn shade(palette: u8, color: u8) -> u32 {
+    SHADES[(palette >> (color * 2) & 0x03) as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_data_auto_increments_and_scales_to_rgb888() {
+        let mut ppu = Ppu::new();
+        ppu.set_cgb(true);
+        ppu.write(BCPS_ADDR, AUTO_INCREMENT | 0x3E);
+        // Pure red at the very end of palette RAM, then wrapping around to blue at the start
+        ppu.write(BCPD_ADDR, 0x1F);
+        ppu.write(BCPD_ADDR, 0x00);
+        ppu.write(BCPD_ADDR, 0x00);
+        ppu.write(BCPD_ADDR, 0x7C);
+        assert_eq!(ppu.read(BCPS_ADDR), 0xC0 | 0x02);
+        assert_eq!(ppu.bg_palettes.color(7, 3), 0x00FF0000);
+        assert_eq!(ppu.bg_palettes.color(0, 0), 0x000000FF);
+
+        // Without auto increment, the index stays put
+        ppu.write(OCPS_ADDR, 0x01);
+        ppu.write(OCPD_ADDR, 0x42);
+        assert_eq!(ppu.read(OCPS_ADDR), 0x41);
+        assert_eq!(ppu.read(OCPD_ADDR), 0x42);
+    }
+
+    /// Sets color `index` of a palette to a single 5 bit red value, enough to tell colors apart.
+    fn set_red(ppu: &mut Ppu, spec: u16, palette: u8, index: u8, red: u8) {
+        ppu.write(spec, palette * 8 + index * 2);
+        ppu.write(spec + 1, red);
+        ppu.write(spec, palette * 8 + index * 2 + 1);
+        ppu.write(spec + 1, 0);
+    }
+
+    fn red(pixel: u32) -> u8 {
+        (pixel >> 19) as u8
+    }
+
+    #[test]
+    fn cgb_draws_bg_attributes_and_object_priority() {
+        let mut ppu = Ppu::new();
+        ppu.set_cgb(true);
+        // Tile 0 in bank 1 has its top row's left half in color 1, and bank 0's tile 0 is left blank
+        ppu.write(VBK_ADDR, 1);
+        ppu.write(VRAM_START_ADDR, 0xF0);
+        // The first map entry uses bank 1, palette 2 and x flip, and the second one wants to be over sprites
+        ppu.write(0x9800, BANK | X_FLIP | 2);
+        ppu.write(0x9801, PRIORITY | BANK | 3);
+        ppu.write(VBK_ADDR, 0);
+        set_red(&mut ppu, BCPS_ADDR, 2, 0, 1);
+        set_red(&mut ppu, BCPS_ADDR, 2, 1, 2);
+        set_red(&mut ppu, BCPS_ADDR, 3, 0, 3);
+        set_red(&mut ppu, BCPS_ADDR, 3, 1, 4);
+        // Two overlapping sprites from tile 1, all color 1, with the later one in OAM further left
+        ppu.write(VRAM_START_ADDR + 16, 0xFF);
+        ppu.write_oam(&{
+            let mut oam = [0; OAM_SIZE];
+            oam[..8].copy_from_slice(&[16, 8 + 12, 1, 5, 16, 8 + 8, 1, 6]);
+            oam
+        });
+        set_red(&mut ppu, OCPS_ADDR, 5, 1, 10);
+        set_red(&mut ppu, OCPS_ADDR, 6, 1, 11);
+
+        ppu.write(LCDC_ADDR, LCD_ENABLE | TILE_DATA | SPRITE_ENABLE | BG_ENABLE);
+        ppu.step(DRAWING_END);
+        let line: Vec<u8> = ppu.framebuffer()[..20].iter().map(|&pixel| red(pixel)).collect();
+        // Flipped, so color 1 is on the right of the first tile
+        assert_eq!(line[..8], [1, 1, 1, 1, 2, 2, 2, 2]);
+        // The BG priority bit puts colors 1-3 over sprites, but not color 0
+        assert_eq!(line[8..12], [4, 4, 4, 4]);
+        // The first sprite wins by OAM order, despite the second one's lower X
+        assert_eq!(line[12..20], [10, 10, 10, 10, 10, 10, 10, 10]);
+
+        // Without LCDC bit 0, sprites go over everything. Switching off first starts the frame again
+        ppu.write(LCDC_ADDR, 0);
+        ppu.write(LCDC_ADDR, LCD_ENABLE | TILE_DATA | SPRITE_ENABLE);
+        ppu.step(DRAWING_END);
+        assert_eq!(red(ppu.framebuffer()[8]), 11);
+    }
+
+    #[test]
+    fn cgb_registers_only_exist_on_a_cgb() {
+        let mut ppu = Ppu::new();
+        ppu.write(VBK_ADDR, 0x01);
+        ppu.write(VRAM_START_ADDR, 0x12);
+        assert_eq!(ppu.read(VBK_ADDR), 0xFF);
+        assert_eq!(ppu.read(BCPS_ADDR), 0xFF);
+
+        ppu.set_cgb(true);
+        ppu.write(VBK_ADDR, 0x01);
+        assert_eq!(ppu.read(VBK_ADDR), 0xFF);
+        ppu.write(VRAM_START_ADDR, 0x34);
+        ppu.write(VBK_ADDR, 0x00);
+        assert_eq!(ppu.read(VBK_ADDR), 0xFE);
+        assert_eq!(ppu.read(VRAM_START_ADDR), 0x12);
+    }
+}
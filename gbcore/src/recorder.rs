@@ -0,0 +1,115 @@
+//! Recorder
+//!
+//! Captures the audio output to WAV files for later listening.
+//! The mixed stereo stream always gets recorded, and each channel can optionally get its own stem file.
+//! Every stream goes through its own sample sink, so the files sound just like what the frontend plays.
+
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use crate::apu::StereoSample;
+use crate::sink::SampleSink;
+use crate::wav::WavWriter;
+
+// Native samples between flushes of the sinks out to the files
+const FLUSH_INTERVAL: u32 = 4096;
+
+struct Track {
+    sink: SampleSink,
+    writer: WavWriter,
+}
+
+impl Track {
+    fn create(path: &Path, rate: u32) -> Result<Self> {
+        let mut sink = SampleSink::default();
+        sink.set_rate(rate)?;
+        Ok(Self {
+            sink,
+            writer: WavWriter::create(path, rate)?,
+        })
+    }
+
+    fn flush(&mut self, buffer: &mut Vec<i16>) -> Result<()> {
+        buffer.clear();
+        self.sink.drain_i16(buffer);
+        self.writer.write_samples(buffer)
+    }
+}
+
+pub struct Recorder {
+    mix: Track,
+    stems: Option<Vec<Track>>,
+    buffer: Vec<i16>,
+    countdown: u32,
+    // Writes happen in the middle of emulation, so hold on to the first failure until recording stops
+    error: Option<anyhow::Error>,
+}
+
+impl Recorder {
+    /// Starts recording to `path`. Stems go next to it, as `<name>.ch1.wav` through `<name>.ch4.wav`.
+    pub fn start(path: &Path, rate: u32, stems: bool) -> Result<Self> {
+        let mix = Track::create(path, rate)?;
+        let stems = if stems {
+            let tracks = (1..=4)
+                .map(|channel| Track::create(&stem_path(path, channel), rate))
+                .collect::<Result<Vec<_>>>()?;
+            Some(tracks)
+        } else {
+            None
+        };
+        Ok(Self {
+            mix,
+            stems,
+            buffer: Vec::new(),
+            countdown: FLUSH_INTERVAL,
+            error: None,
+        })
+    }
+
+    /// Feeds in one native sample of the mix, and of each channel if recording stems.
+    pub fn push(&mut self, mix: StereoSample, channels: &[StereoSample; 4]) {
+        self.mix.sink.push(mix);
+        if let Some(stems) = &mut self.stems {
+            for (track, sample) in stems.iter_mut().zip(channels) {
+                track.sink.push(*sample);
+            }
+        }
+
+        self.countdown -= 1;
+        if self.countdown == 0 {
+            self.countdown = FLUSH_INTERVAL;
+            if let Err(e) = self.flush() {
+                self.error.get_or_insert(e);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.mix.flush(&mut self.buffer)?;
+        if let Some(stems) = &mut self.stems {
+            for track in stems {
+                track.flush(&mut self.buffer)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out whatever is left and closes the files.
+    /// The headers get their sizes filled in even after a failed write, so whatever made it to disk still plays.
+    pub fn finish(mut self) -> Result<()> {
+        let error = self.error.take();
+        let mut result = self.flush();
+        result = result.and(self.mix.writer.finish());
+        for track in self.stems.into_iter().flatten() {
+            result = result.and(track.writer.finish());
+        }
+        if let Some(e) = error {
+            return Err(anyhow!("Audio recording failed: {e:#}"))
+        }
+        result
+    }
+}
+
+fn stem_path(path: &Path, channel: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{stem}.ch{channel}.wav"))
+}
@@ -1,3 +1,84 @@
+pub const ZERO_FLAG: u8 = 1 << 7;
+pub const SUB_FLAG: u8 = 1 << 6;
+pub const HALF_CARRY_FLAG: u8 = 1 << 5;
+pub const CARRY_FLAG: u8 = 1 << 4;
+
+/// The 8-bit registers, for reading and writing them from outside the CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reg8 {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+}
+
+/// The 16-bit registers, including the pairs of 8-bit ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reg16 {
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+}
+
+/// Everything about the CPU itself at one point in time, for looking at or putting back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuState {
+    pub a: u8,
+    /// Only the top four bits exist, the rest always read as zero.
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub ime: bool,
+    pub halted: bool,
+}
+
+impl CpuState {
+    pub fn af(&self) -> u16 {
+        u16::from_be_bytes([self.a, self.f])
+    }
+
+    pub fn bc(&self) -> u16 {
+        u16::from_be_bytes([self.b, self.c])
+    }
+
+    pub fn de(&self) -> u16 {
+        u16::from_be_bytes([self.d, self.e])
+    }
+
+    pub fn hl(&self) -> u16 {
+        u16::from_be_bytes([self.h, self.l])
+    }
+
+    pub fn zero(&self) -> bool {
+        self.f & ZERO_FLAG != 0
+    }
+
+    pub fn subtract(&self) -> bool {
+        self.f & SUB_FLAG != 0
+    }
+
+    pub fn half_carry(&self) -> bool {
+        self.f & HALF_CARRY_FLAG != 0
+    }
+
+    pub fn carry(&self) -> bool {
+        self.f & CARRY_FLAG != 0
+    }
+}
+
 pub struct RegisterPair {
     pub high: u8,
     pub low: u8,
@@ -12,7 +93,7 @@ impl RegisterPair {
     }
 
     pub fn get_pair(&self) -> u16 {
-        ((self.high as u16) << 8) & (self.low as u16)
+        ((self.high as u16) << 8) | (self.low as u16)
     }
 
     pub fn set_pair(&mut self, value: u16) {
@@ -29,20 +110,22 @@ impl RegisterPair {
         let val = self.get_pair();
         self.set_pair(val.wrapping_sub(1));
     }
+}
 
-    pub fn inc_high(&mut self) {
-        self.high.wrapping_add(1);
-    }
-
-    pub fn dec_high(&mut self) {
-        self.high.wrapping_sub(1);
-    }
-
-    pub fn inc_low(&mut self) {
-        self.low.wrapping_add(1);
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub fn dec_low(&mut self) {
-        self.low.wrapping_sub(1);
+    #[test]
+    fn pair_round_trips() {
+        let mut pair = RegisterPair::new();
+        pair.set_pair(0x12F4);
+        assert_eq!((pair.high, pair.low), (0x12, 0xF4));
+        assert_eq!(pair.get_pair(), 0x12F4);
+        pair.inc_pair();
+        assert_eq!(pair.get_pair(), 0x12F5);
+        pair.set_pair(0x0000);
+        pair.dec_pair();
+        assert_eq!(pair.get_pair(), 0xFFFF);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,81 @@
+//! Scheduler
+//!
+//! Keeps the CPU clock and the fixed system clock in step with each other.
+//! In CGB double-speed mode the CPU (and the timer hanging off of it) runs twice as fast,
+//! while the PPU and APU keep ticking at the normal 4 MiHz rate.
+//! Time on the fixed clock is counted in dots, one per 4 MiHz tick.
+
+/// Dots in a second of emulated time, however fast the CPU is running.
+pub const DOTS_PER_SECOND: u64 = 4_194_304;
+
+/// Dots in one full frame, including VBlank.
+pub const DOTS_PER_FRAME: u64 = 70224;
+
+/// M-cycles the CPU sits paused after a STOP-triggered speed switch.
+pub const SPEED_SWITCH_CYCLES: i32 = 2050;
+
+/// Clock cycles elapsed in each of the two clock domains.
+#[derive(Clone, Copy)]
+pub struct Clocks {
+    /// Cycles on the CPU clock, which drives the timer.
+    pub cpu: u32,
+    /// Cycles on the fixed clock, which drives the PPU and APU.
+    pub dots: u32,
+}
+
+pub struct Scheduler {
+    double_speed: bool,
+    // M-cycles during which the CPU clock is stopped but the fixed clock keeps running
+    frozen: i32,
+    dots: u64,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            double_speed: false,
+            frozen: 0,
+            dots: 0,
+        }
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    /// Total dots elapsed since power on.
+    pub fn dots(&self) -> u64 {
+        self.dots
+    }
+
+    /// Toggles between normal and double speed, pausing the CPU clock for the length of the switch.
+    pub fn switch_speed(&mut self) {
+        self.double_speed = !self.double_speed;
+        self.freeze(SPEED_SWITCH_CYCLES);
+    }
+
+    /// Stops the CPU clock for the next few M-cycles.
+    pub fn freeze(&mut self, cycles: i32) {
+        self.frozen += cycles;
+    }
+
+    /// Converts M-cycles taken by the CPU into time passed in each clock domain.
+    pub fn advance(&mut self, cycles: i32) -> Clocks {
+        let frozen = self.frozen.min(cycles);
+        self.frozen -= frozen;
+
+        let dots_per_cycle = if self.double_speed { 2 } else { 4 };
+        let clocks = Clocks {
+            cpu: ((cycles - frozen) * 4) as u32,
+            dots: (cycles * dots_per_cycle) as u32,
+        };
+        self.dots += clocks.dots as u64;
+        clocks
+    }
+}
@@ -0,0 +1,58 @@
+//! Oscilloscope
+//!
+//! Keeps a rolling window of recent output from each APU channel, for drawing waveforms or dumping to a file.
+//! The raw channel output is captured before panning, volume, or muting get applied.
+
+use crate::apu::NATIVE_SAMPLE_RATE;
+
+/// Points kept per channel.
+pub const SCOPE_LENGTH: usize = 2048;
+/// Native samples per captured point.
+const SCOPE_DECIMATION: u32 = 64;
+/// Rate at which points are captured, in Hz.
+pub const SCOPE_RATE: u32 = NATIVE_SAMPLE_RATE / SCOPE_DECIMATION;
+
+pub struct Oscilloscope {
+    buffers: [Vec<f32>; 4],
+    // Index of the oldest point, which is the next one to be overwritten
+    position: usize,
+    countdown: u32,
+}
+
+impl Default for Oscilloscope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Oscilloscope {
+    pub fn new() -> Self {
+        Self {
+            buffers: std::array::from_fn(|_| vec![0.0; SCOPE_LENGTH]),
+            position: 0,
+            countdown: SCOPE_DECIMATION,
+        }
+    }
+
+    /// Feeds in one native sample from each channel's DAC.
+    pub fn push(&mut self, outputs: &[f32; 4]) {
+        self.countdown -= 1;
+        if self.countdown > 0 {
+            return
+        }
+        self.countdown = SCOPE_DECIMATION;
+        for (buffer, output) in self.buffers.iter_mut().zip(outputs) {
+            buffer[self.position] = *output;
+        }
+        self.position = (self.position + 1) % SCOPE_LENGTH;
+    }
+
+    /// The captured points for one channel, oldest first.
+    pub fn channel(&self, channel: usize) -> Vec<f32> {
+        let buffer = &self.buffers[channel];
+        let mut points = Vec::with_capacity(SCOPE_LENGTH);
+        points.extend_from_slice(&buffer[self.position..]);
+        points.extend_from_slice(&buffer[..self.position]);
+        points
+    }
+}
@@ -0,0 +1,236 @@
+//! Sample Sink
+//!
+//! Turns the APU's native ~2 MHz output into something a sound card can play.
+//! Rather than filtering every native sample, the sink only does work when the output level changes:
+//! each change is drawn into the output as a band-limited step (a windowed sinc impulse, integrated),
+//! so nothing above the target Nyquist frequency makes it through to alias.
+//! The result then goes through the same DC-blocking high-pass filter as the hardware's output capacitor.
+
+use std::collections::VecDeque;
+use anyhow::{anyhow, Result};
+use crate::apu::{StereoSample, NATIVE_SAMPLE_RATE};
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+// Length of each band-limited step, in output samples
+const KERNEL_WIDTH: usize = 16;
+// Number of sub-sample positions a step can land on
+const KERNEL_PHASES: usize = 64;
+// Cutoff frequency as a fraction of the output rate, a little under Nyquist
+const CUTOFF: f64 = 0.45;
+
+// Capacitor charge factor per 4 MiHz clock, measured on DMG hardware
+const CHARGE_FACTOR: f64 = 0.999958;
+
+pub struct SampleSink {
+    rate: u32,
+    // Output samples per native sample
+    step: f64,
+    // Position of the next native sample, in output samples from the front of `deltas`
+    time: f64,
+    level: StereoSample,
+    deltas: VecDeque<[f32; 2]>,
+    integrator: [f32; 2],
+    capacitor: [f32; 2],
+    charge_factor: f32,
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    output: VecDeque<[f32; 2]>,
+}
+
+impl Default for SampleSink {
+    fn default() -> Self {
+        Self::new(DEFAULT_SAMPLE_RATE)
+    }
+}
+
+impl SampleSink {
+    pub fn new(rate: u32) -> Self {
+        let mut sink = Self {
+            rate,
+            step: 0.0,
+            time: 0.0,
+            level: StereoSample::default(),
+            deltas: VecDeque::new(),
+            integrator: [0.0; 2],
+            capacitor: [0.0; 2],
+            charge_factor: 0.0,
+            kernel: build_kernel(),
+            output: VecDeque::new(),
+        };
+        sink.reset(rate);
+        sink
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Changes the output rate, throwing away anything not drained yet.
+    pub fn set_rate(&mut self, rate: u32) -> Result<()> {
+        if rate == 0 || rate > NATIVE_SAMPLE_RATE {
+            return Err(anyhow!("Sample rate must be between 1 and {} Hz: {}", NATIVE_SAMPLE_RATE, rate))
+        }
+        self.reset(rate);
+        Ok(())
+    }
+
+    fn reset(&mut self, rate: u32) {
+        self.rate = rate;
+        self.step = rate as f64 / NATIVE_SAMPLE_RATE as f64;
+        self.time = 0.0;
+        self.deltas.clear();
+        self.integrator = [self.level.left, self.level.right];
+        self.charge_factor = CHARGE_FACTOR.powf(4194304.0 / rate as f64) as f32;
+        self.output.clear();
+    }
+
+    /// Feeds in one sample at the native rate.
+    pub fn push(&mut self, sample: StereoSample) {
+        if self.rate == NATIVE_SAMPLE_RATE {
+            // Nothing to resample, but still needs the high-pass
+            self.output_sample([sample.left, sample.right]);
+            return
+        }
+
+        if sample != self.level {
+            let delta = [sample.left - self.level.left, sample.right - self.level.right];
+            self.level = sample;
+
+            let start = self.time as usize;
+            let phase = ((self.time - start as f64) * KERNEL_PHASES as f64).round() as usize;
+            if self.deltas.len() < start + KERNEL_WIDTH {
+                self.deltas.resize(start + KERNEL_WIDTH, [0.0; 2]);
+            }
+            for (i, k) in self.kernel[phase].iter().enumerate() {
+                let slot = &mut self.deltas[start + i];
+                slot[0] += delta[0] * k;
+                slot[1] += delta[1] * k;
+            }
+        }
+        self.time += self.step;
+
+        // Anything before the current position can't be touched by another step, so it's done
+        let ready = self.time as usize;
+        for _ in 0..ready {
+            let delta = self.deltas.pop_front().unwrap_or([0.0; 2]);
+            self.integrator[0] += delta[0];
+            self.integrator[1] += delta[1];
+            self.output_sample(self.integrator);
+        }
+        self.time -= ready as f64;
+    }
+
+    fn output_sample(&mut self, sample: [f32; 2]) {
+        let mut filtered = [0.0; 2];
+        for (side, input) in sample.into_iter().enumerate() {
+            filtered[side] = input - self.capacitor[side];
+            self.capacitor[side] = input - filtered[side] * self.charge_factor;
+        }
+        // Keep at most the last second of audio around if nobody is draining it
+        self.output.push_back(filtered);
+        if self.output.len() > self.rate as usize {
+            self.output.pop_front();
+        }
+    }
+
+    /// Moves every waiting sample into a buffer, interleaved left then right, from -1.0 to 1.0.
+    pub fn drain_f32(&mut self, buffer: &mut Vec<f32>) {
+        buffer.reserve(self.output.len() * 2);
+        for [left, right] in self.output.drain(..) {
+            buffer.push(left);
+            buffer.push(right);
+        }
+    }
+
+    /// Moves every waiting sample into a buffer, interleaved left then right, at full 16-bit scale.
+    pub fn drain_i16(&mut self, buffer: &mut Vec<i16>) {
+        let convert = |x: f32| (x.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        buffer.reserve(self.output.len() * 2);
+        for [left, right] in self.output.drain(..) {
+            buffer.push(convert(left));
+            buffer.push(convert(right));
+        }
+    }
+}
+
+// One windowed sinc impulse per phase, each normalized so a full step always adds up to exactly the delta.
+// The impulses are delayed by half their width so they never reach back before the step.
+fn build_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+    let half = KERNEL_WIDTH as f64 / 2.0;
+    (0..=KERNEL_PHASES).map(|phase| {
+        let offset = phase as f64 / KERNEL_PHASES as f64;
+        let mut row = [0.0; KERNEL_WIDTH];
+        let mut sum = 0.0;
+        for (i, k) in row.iter_mut().enumerate() {
+            let x = i as f64 - offset - half + 1.0;
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                let t = std::f64::consts::PI * 2.0 * CUTOFF * x;
+                t.sin() / t
+            };
+            // Blackman window across the width of the kernel
+            let w = (x + half) / KERNEL_WIDTH as f64;
+            let window = if (0.0..=1.0).contains(&w) {
+                0.42 - 0.5 * (2.0 * std::f64::consts::PI * w).cos() + 0.08 * (4.0 * std::f64::consts::PI * w).cos()
+            } else {
+                0.0
+            };
+            *k = sinc * window;
+            sum += *k;
+        }
+        row.map(|k| (k / sum) as f32)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(level: f32) -> StereoSample {
+        StereoSample { left: level, right: level }
+    }
+
+    fn drain(sink: &mut SampleSink) -> Vec<f32> {
+        let mut buffer = Vec::new();
+        sink.drain_f32(&mut buffer);
+        buffer.chunks(2).map(|pair| pair[0]).collect()
+    }
+
+    #[test]
+    fn output_matches_the_requested_rate() {
+        let mut sink = SampleSink::new(48000);
+        // A quarter of a second, the APU's one native sample every 2 dots
+        for _ in 0..NATIVE_SAMPLE_RATE / 4 {
+            sink.push(level(0.0));
+        }
+        let count = drain(&mut sink).len() as i64;
+        assert!((count - 12000).abs() <= 1, "{} samples", count);
+    }
+
+    #[test]
+    fn dc_step_decays_through_the_high_pass() {
+        let mut sink = SampleSink::new(48000);
+        for _ in 0..NATIVE_SAMPLE_RATE {
+            sink.push(level(0.5));
+        }
+        let output = drain(&mut sink);
+        let peak = output.iter().cloned().fold(0.0, f32::max);
+        assert!(peak > 0.45, "peak of {}", peak);
+        let tail = &output[output.len() - 100..];
+        assert!(tail.iter().all(|sample| sample.abs() < 0.001), "still at {} after a second", tail[0]);
+    }
+
+    #[test]
+    fn a_full_buffer_drops_the_oldest_samples() {
+        let mut sink = SampleSink::new(1000);
+        // Two seconds of silence, apart from a step in the last 25 milliseconds or so
+        let samples = NATIVE_SAMPLE_RATE * 2;
+        for i in 0..samples {
+            sink.push(level(if i > samples - 50000 { 0.5 } else { 0.0 }));
+        }
+        let output = drain(&mut sink);
+        assert_eq!(output.len(), 1000);
+        assert!(output[970..].iter().any(|sample| sample.abs() > 0.1));
+    }
+}
@@ -0,0 +1,155 @@
+//! Symbols
+//!
+//! Labels from the `.sym` and `.map` files RGBDS writes next to a ROM, so addresses can be shown as `Label+offset`
+//! and looked up by name. Labels belong to a bank as well as an address, since the same address in 0x4000-0x7FFF
+//! means something different depending on which ROM bank is switched in.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+
+// Where each area of memory starts, so a label in one never covers addresses in the next
+const REGION_STARTS: [u16; 11] = [0x0000, 0x4000, 0x8000, 0xA000, 0xC000, 0xD000, 0xE000, 0xFE00, 0xFEA0, 0xFF00, 0xFF80];
+
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    // By bank then address, for finding the closest label before an address
+    labels: BTreeMap<(u16, u16), String>,
+    addresses: HashMap<String, (u16, u16)>,
+}
+
+impl Symbols {
+    /// Loads a `.map` file if it has that extension, otherwise a `.sym` file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let symbols = match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("map") => Self::parse_map(&text),
+            _ => Self::parse_sym(&text),
+        };
+        symbols.with_context(|| format!("Failed to load symbols from {}", path.display()))
+    }
+
+    /// Parses a `.sym` file, made of lines like `01:4000 Label` with `;` comments.
+    pub fn parse_sym(text: &str) -> Result<Self> {
+        let mut symbols = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            // Other assemblers put their symbols under headers like [labels]
+            if line.is_empty() || line.starts_with('[') {
+                continue
+            }
+            let parse = || -> Option<(u16, u16, &str)> {
+                let (location, name) = line.split_once(char::is_whitespace)?;
+                let (bank, address) = location.split_once(':')?;
+                Some((u16::from_str_radix(bank, 16).ok()?, u16::from_str_radix(address, 16).ok()?, name.trim()))
+            };
+            let (bank, address, name) = parse().ok_or_else(|| anyhow!("Line {} isn't a symbol: {}", number + 1, line))?;
+            symbols.insert(bank, address, name);
+        }
+        Ok(symbols)
+    }
+
+    /// Parses a `.map` file, picking out the `$4000 = Label` lines under each `ROMX bank #1:` heading.
+    pub fn parse_map(text: &str) -> Result<Self> {
+        let mut symbols = Self::default();
+        let mut bank = 0;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if let Some(heading) = line.strip_suffix(':')
+                && let Some((_, heading_bank)) = heading.split_once("bank #")
+            {
+                bank = heading_bank.parse().map_err(|_| anyhow!("Line {} has a bad bank number: {}", number + 1, line))?;
+            } else if let Some(symbol) = line.strip_prefix('$')
+                && let Some((address, name)) = symbol.split_once(" = ")
+            {
+                let address = u16::from_str_radix(address.trim(), 16)
+                    .map_err(|_| anyhow!("Line {} has a bad address: {}", number + 1, line))?;
+                symbols.insert(bank, address, name.trim());
+            }
+        }
+        Ok(symbols)
+    }
+
+    fn insert(&mut self, bank: u16, address: u16, name: &str) {
+        self.addresses.entry(name.to_string()).or_insert((bank, address));
+        // Where a local label shares an address with its parent, the parent reads better
+        match self.labels.get(&(bank, address)) {
+            Some(existing) if !existing.contains('.') || name.contains('.') => {},
+            _ => {
+                self.labels.insert((bank, address), name.to_string());
+            },
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    /// The closest label at or before an address in a bank, as `Label` or `Label+offset`.
+    pub fn label(&self, bank: u16, address: u16) -> Option<String> {
+        let region = REGION_STARTS.iter().rev().find(|&&start| start <= address).copied().unwrap_or(0);
+        let (&(_, start), name) = self.labels.range((bank, region)..=(bank, address)).next_back()?;
+        match address - start {
+            0 => Some(name.clone()),
+            offset => Some(format!("{}+${:X}", name, offset)),
+        }
+    }
+
+    /// The bank and address of a label.
+    pub fn find(&self, name: &str) -> Option<(u16, u16)> {
+        self.addresses.get(name).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sym_files_skip_headers_and_comments() {
+        let symbols = Symbols::parse_sym("; File generated by rgblink\n[labels]\n00:0150 Main ; entry point\n01:4000 Bank1Routine\n").unwrap();
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.find("Main"), Some((0, 0x0150)));
+        assert_eq!(symbols.find("Bank1Routine"), Some((1, 0x4000)));
+        assert!(Symbols::parse_sym("00:0150\n").is_err());
+    }
+
+    #[test]
+    fn map_files_take_the_bank_from_headings() {
+        let map = "\
+ROM0 bank #0:
+\tSECTION: $0000-$00ff ($0100 bytes) [\"Header\"]
+\t         $0150 = Main
+ROMX bank #2:
+\tSECTION: $4000-$40ff ($0100 bytes) [\"Code\"]
+\t         $4010 = Bank2Routine
+";
+        let symbols = Symbols::parse_map(map).unwrap();
+        assert_eq!(symbols.find("Main"), Some((0, 0x0150)));
+        assert_eq!(symbols.find("Bank2Routine"), Some((2, 0x4010)));
+        assert_eq!(symbols.label(2, 0x4010).as_deref(), Some("Bank2Routine"));
+        assert_eq!(symbols.label(1, 0x4010), None);
+    }
+
+    #[test]
+    fn local_labels_dont_replace_their_parent() {
+        let symbols = Symbols::parse_sym("00:0150 Main\n00:0150 Main.loop\n00:0160 .skip\n00:0160 Other\n").unwrap();
+        assert_eq!(symbols.label(0, 0x0150).as_deref(), Some("Main"));
+        // But a parent still replaces a local label that came first
+        assert_eq!(symbols.label(0, 0x0160).as_deref(), Some("Other"));
+        assert_eq!(symbols.find("Main.loop"), Some((0, 0x0150)));
+    }
+
+    #[test]
+    fn labels_stop_at_the_next_region() {
+        let symbols = Symbols::parse_sym("00:9ff0 TileEnd\n00:c000 wBuffer\n").unwrap();
+        assert_eq!(symbols.label(0, 0x9FFF).as_deref(), Some("TileEnd+$F"));
+        assert_eq!(symbols.label(0, 0xA000), None);
+        assert_eq!(symbols.label(0, 0xC123).as_deref(), Some("wBuffer+$123"));
+        assert_eq!(symbols.label(0, 0xBFFF), None);
+    }
+}
@@ -0,0 +1,145 @@
+//! Timer
+//!
+//! The divider and the programmable timer (DIV, TIMA, TMA, TAC).
+//! Both hang off of the CPU clock, so they speed up along with the CPU in CGB double-speed mode.
+//! The APU's frame sequencer is clocked off of the divider too, by a bit one higher up in double speed
+//! so it stays at 512 Hz.
+
+pub const DIV_ADDR: u16 = 0xFF04;
+pub const TIMA_ADDR: u16 = 0xFF05;
+pub const TMA_ADDR: u16 = 0xFF06;
+pub const TAC_ADDR: u16 = 0xFF07;
+
+pub struct Timer {
+    // DIV is just the upper byte of this internal counter
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    double_speed: bool,
+    // Falling edges of the frame sequencer's DIV bit not yet passed on to the APU
+    sequencer_clocks: u32,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self {
+            counter: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            double_speed: false,
+            sequencer_clocks: 0,
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            DIV_ADDR => (self.counter >> 8) as u8,
+            TIMA_ADDR => self.tima,
+            TMA_ADDR => self.tma,
+            TAC_ADDR => self.tac | 0xF8,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) {
+        match address {
+            DIV_ADDR => self.reset_div(),
+            TIMA_ADDR => self.tima = data,
+            TMA_ADDR => self.tma = data,
+            TAC_ADDR => self.tac = data & 0x07,
+            _ => (),
+        }
+    }
+
+    /// Writing to DIV, or executing STOP, clears the whole internal counter.
+    /// That can count as a falling edge for the frame sequencer.
+    pub fn reset_div(&mut self) {
+        if self.counter & self.sequencer_bit() != 0 {
+            self.sequencer_clocks += 1;
+        }
+        self.counter = 0;
+    }
+
+    pub fn set_double_speed(&mut self, double_speed: bool) {
+        self.double_speed = double_speed;
+    }
+
+    /// Number of times the APU's frame sequencer should have been clocked since the last call.
+    pub fn take_sequencer_clocks(&mut self) -> u32 {
+        std::mem::take(&mut self.sequencer_clocks)
+    }
+
+    // DIV bit 4, or bit 5 in double speed
+    fn sequencer_bit(&self) -> u16 {
+        if self.double_speed { 1 << 13 } else { 1 << 12 }
+    }
+
+    /// Advances the timer by some number of CPU clock cycles.
+    /// Returns true if TIMA overflowed and the timer interrupt should be requested.
+    pub fn step(&mut self, clocks: u32) -> bool {
+        let mut overflow = false;
+        // TIMA increments on the falling edge of the counter bit selected by TAC.
+        // The counter only ever moves in whole M-cycles, so at most one edge is crossed per step.
+        for _ in 0..clocks / 4 {
+            let old = self.counter;
+            self.counter = self.counter.wrapping_add(4);
+            if old & self.sequencer_bit() != 0 && self.counter & self.sequencer_bit() == 0 {
+                self.sequencer_clocks += 1;
+            }
+            if self.tac & 0x04 == 0 {
+                continue;
+            }
+            let bit = match self.tac & 0x03 {
+                0 => 1 << 9,
+                1 => 1 << 3,
+                2 => 1 << 5,
+                _ => 1 << 7,
+            };
+            if old & bit != 0 && self.counter & bit == 0 {
+                let (result, carry) = self.tima.overflowing_add(1);
+                if carry {
+                    self.tima = self.tma;
+                    overflow = true;
+                } else {
+                    self.tima = result;
+                }
+            }
+        }
+        overflow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequencer_clocks_on_div_bit_falling_edges() {
+        let mut timer = Timer::new();
+        // Bit 12 falls every 8192 clocks
+        timer.step(8192 * 3);
+        assert_eq!(timer.take_sequencer_clocks(), 3);
+        assert_eq!(timer.take_sequencer_clocks(), 0);
+
+        // Resetting DIV with the bit set is a falling edge too, and restarts the count
+        timer.step(4096);
+        timer.write(DIV_ADDR, 0);
+        assert_eq!(timer.take_sequencer_clocks(), 1);
+        timer.step(8188);
+        assert_eq!(timer.take_sequencer_clocks(), 0);
+
+        // Bit 13 in double speed, which is the same rate with the CPU clock going twice as fast
+        timer.set_double_speed(true);
+        timer.write(DIV_ADDR, 0);
+        timer.step(16384);
+        assert_eq!(timer.take_sequencer_clocks(), 1);
+    }
+}
@@ -0,0 +1,47 @@
+//! Trace
+//!
+//! Logs the CPU state before every instruction, one line each, in the format Gameboy Doctor checks:
+//! `A:00 F:11 B:22 C:33 D:44 E:55 H:66 L:77 SP:8888 PC:9999 PCMEM:AA,BB,CC,DD`.
+//! Comparing a log with one from an emulator known to be right finds the first instruction that went wrong.
+//! With labels on and symbols loaded, lines end in a comment with the label for PC, like ` ; Main+$3`,
+//! which Gameboy Doctor itself won't expect.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use anyhow::{Context, Result};
+use crate::CPU;
+
+pub struct TraceLogger {
+    out: BufWriter<File>,
+    labels: bool,
+}
+
+impl TraceLogger {
+    pub fn create(path: &Path, labels: bool) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(Self {
+            out: BufWriter::new(file),
+            labels,
+        })
+    }
+
+    /// Logs the state the CPU is in, just before it runs the instruction at PC.
+    pub fn log(&mut self, cpu: &CPU) -> Result<()> {
+        let pc = cpu.memory.program_counter;
+        let pcmem = [0, 1, 2, 3].map(|offset| cpu.peek(pc.wrapping_add(offset)));
+        write!(self.out, "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            cpu.af.high, cpu.af.low, cpu.bc.high, cpu.bc.low, cpu.de.high, cpu.de.low, cpu.hl.high, cpu.hl.low,
+            cpu.memory.stack_pointer, pc, pcmem[0], pcmem[1], pcmem[2], pcmem[3])?;
+        if self.labels && let Some(label) = cpu.label(pc) {
+            write!(self.out, " ; {}", label)?;
+        }
+        writeln!(self.out)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+//! VGM
+//!
+//! Logs every write to the APU registers and wave RAM into a VGM file (version 1.61, the first with Game Boy support).
+//! Writes are timestamped on the fixed 4 MiHz clock and converted to VGM's 44.1 kHz wait commands.
+//! The log is kept in memory and only written out to the file once logging stops.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use anyhow::{Context, Result};
+use crate::apu::APU_START_ADDR;
+
+const VGM_VERSION: u32 = 0x0000_0161;
+const VGM_SAMPLE_RATE: u64 = 44100;
+const DMG_CLOCK: u64 = 4_194_304;
+const HEADER_SIZE: usize = 0xC0;
+
+const LOOP_OFFSET_FIELD: usize = 0x1C;
+const DATA_OFFSET_FIELD: usize = 0x34;
+
+const CMD_DMG_WRITE: u8 = 0xB3;
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_NTSC: u8 = 0x62;
+const CMD_WAIT_PAL: u8 = 0x63;
+const CMD_END: u8 = 0x66;
+const CMD_WAIT_SHORT: u8 = 0x70;
+
+pub struct VgmLogger {
+    file: File,
+    data: Vec<u8>,
+    // Dots since logging started
+    dots: u64,
+    // 44.1 kHz samples already accounted for by wait commands
+    samples: u64,
+    // Position in the data and sample count where playback loops back to
+    loop_point: Option<(usize, u64)>,
+}
+
+impl VgmLogger {
+    /// Creates the file up front, so a bad path fails right away rather than when logging stops.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(Self {
+            file,
+            data: Vec::new(),
+            dots: 0,
+            samples: 0,
+            loop_point: None,
+        })
+    }
+
+    pub fn step(&mut self, dots: u32) {
+        self.dots += dots as u64;
+    }
+
+    /// Logs a write to some address from 0xFF10 to 0xFF3F.
+    pub fn write(&mut self, address: u16, data: u8) {
+        self.catch_up();
+        self.data.extend([CMD_DMG_WRITE, (address - APU_START_ADDR) as u8, data]);
+    }
+
+    /// Marks the current point in the log as where playback loops back to after reaching the end.
+    pub fn mark_loop(&mut self) {
+        self.catch_up();
+        self.loop_point = Some((self.data.len(), self.samples));
+    }
+
+    // Emits waits for all the time passed since the last command
+    fn catch_up(&mut self) {
+        let target = self.dots * VGM_SAMPLE_RATE / DMG_CLOCK;
+        let mut wait = target - self.samples;
+        self.samples = target;
+        while wait > 0 {
+            let chunk = wait.min(0xFFFF);
+            match chunk {
+                1..=16 => self.data.push(CMD_WAIT_SHORT + chunk as u8 - 1),
+                735 => self.data.push(CMD_WAIT_NTSC),
+                882 => self.data.push(CMD_WAIT_PAL),
+                _ => {
+                    self.data.push(CMD_WAIT);
+                    self.data.extend((chunk as u16).to_le_bytes());
+                },
+            }
+            wait -= chunk;
+        }
+    }
+
+    /// Ends the log and writes out the whole file.
+    pub fn finish(mut self) -> Result<()> {
+        self.catch_up();
+        self.data.push(CMD_END);
+
+        let mut header = [0u8; HEADER_SIZE];
+        let mut field = |offset: usize, value: u32| header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        field(0x00, u32::from_le_bytes(*b"Vgm "));
+        field(0x04, (HEADER_SIZE + self.data.len() - 4) as u32);
+        field(0x08, VGM_VERSION);
+        field(0x18, self.samples as u32);
+        if let Some((offset, samples)) = self.loop_point {
+            // Offsets in the header are relative to the field they're stored in
+            field(LOOP_OFFSET_FIELD, (HEADER_SIZE + offset - LOOP_OFFSET_FIELD) as u32);
+            field(0x20, (self.samples - samples) as u32);
+        }
+        field(DATA_OFFSET_FIELD, (HEADER_SIZE - DATA_OFFSET_FIELD) as u32);
+        field(0x80, DMG_CLOCK as u32);
+
+        let mut file = BufWriter::new(self.file);
+        file.write_all(&header)?;
+        file.write_all(&self.data)?;
+        file.flush()?;
+        Ok(())
+    }
+}
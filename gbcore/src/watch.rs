@@ -0,0 +1,186 @@
+//! Watchpoints
+//!
+//! Catches the CPU reading or writing memory in a given range, for debuggers to stop on.
+//! Only the CPU's own data accesses count: instruction fetches, DMA, and debuggers peeking and poking don't.
+
+use std::cell::Cell;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Reads and writes both.
+    Access,
+}
+
+/// What the value has to be for a watchpoint to go off, on top of the address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchCondition {
+    /// The byte read or written is this.
+    Equals(u8),
+    /// A write changes what was there. Reads never do.
+    Changes,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    /// Inclusive, so a single address has the same start and end.
+    pub end: u16,
+    pub kind: WatchKind,
+    pub condition: Option<WatchCondition>,
+}
+
+impl Watchpoint {
+    pub fn new(start: u16, end: u16, kind: WatchKind) -> Self {
+        Self { start, end, kind, condition: None }
+    }
+
+    /// Only goes off when the value meets a condition too.
+    pub fn when(self, condition: WatchCondition) -> Self {
+        Self { condition: Some(condition), ..self }
+    }
+
+    fn triggered_by(&self, address: u16, write: bool, old: u8, new: u8) -> bool {
+        let kind = match self.kind {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        };
+        let condition = match self.condition {
+            None => true,
+            Some(WatchCondition::Equals(value)) => new == value,
+            Some(WatchCondition::Changes) => write && new != old,
+        };
+        kind && condition && (self.start..=self.end).contains(&address)
+    }
+}
+
+/// A watchpoint going off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    /// Where the instruction that set it off starts.
+    pub pc: u16,
+    pub address: u16,
+    /// True for a write, false for a read.
+    pub write: bool,
+    /// What was there before. The same as `new` for a read.
+    pub old: u8,
+    pub new: u8,
+}
+
+#[derive(Default)]
+pub struct Watches {
+    pub points: Vec<Watchpoint>,
+    // Reads go through &self, so the hit has to be set from behind a shared reference
+    hit: Cell<Option<WatchHit>>,
+    pc: u16,
+}
+
+impl Watches {
+    pub fn is_watching(&self) -> bool {
+        !self.points.is_empty()
+    }
+
+    /// Notes where the instruction about to run starts, for any hits it sets off.
+    pub fn start_instruction(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn read(&self, address: u16, value: u8) {
+        self.check(address, false, value, value);
+    }
+
+    pub fn write(&self, address: u16, old: u8, new: u8) {
+        self.check(address, true, old, new);
+    }
+
+    /// The first watchpoint to go off since the last time this was called.
+    pub fn take_hit(&self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+
+    fn check(&self, address: u16, write: bool, old: u8, new: u8) {
+        if self.hit.get().is_some() {
+            return
+        }
+        let mut points = self.points.iter();
+        if let Some(watchpoint) = points.find(|watchpoint| watchpoint.triggered_by(address, write, old, new)) {
+            self.hit.set(Some(WatchHit { watchpoint: *watchpoint, pc: self.pc, address, write, old, new }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watching(watchpoint: Watchpoint) -> Watches {
+        Watches { points: vec![watchpoint], ..Default::default() }
+    }
+
+    #[test]
+    fn kinds_pick_reads_writes_or_both() {
+        let reads = watching(Watchpoint::new(0xC000, 0xC000, WatchKind::Read));
+        reads.write(0xC000, 0, 1);
+        assert_eq!(reads.take_hit(), None);
+        reads.read(0xC000, 1);
+        assert!(reads.take_hit().is_some_and(|hit| !hit.write));
+
+        let writes = watching(Watchpoint::new(0xC000, 0xC000, WatchKind::Write));
+        writes.read(0xC000, 1);
+        assert_eq!(writes.take_hit(), None);
+        writes.write(0xC000, 0, 1);
+        assert!(writes.take_hit().is_some_and(|hit| hit.write));
+
+        let accesses = watching(Watchpoint::new(0xC000, 0xC000, WatchKind::Access));
+        accesses.read(0xC000, 1);
+        assert!(accesses.take_hit().is_some());
+        accesses.write(0xC000, 0, 1);
+        assert!(accesses.take_hit().is_some());
+    }
+
+    #[test]
+    fn range_end_is_inclusive() {
+        let watches = watching(Watchpoint::new(0xC000, 0xC00F, WatchKind::Access));
+        watches.read(0xBFFF, 0);
+        watches.read(0xC010, 0);
+        assert_eq!(watches.take_hit(), None);
+        watches.read(0xC00F, 0);
+        assert_eq!(watches.take_hit().map(|hit| hit.address), Some(0xC00F));
+    }
+
+    #[test]
+    fn equals_checks_the_value_read_or_written() {
+        let watches = watching(Watchpoint::new(0xC000, 0xC000, WatchKind::Access).when(WatchCondition::Equals(0x42)));
+        watches.read(0xC000, 0x41);
+        watches.write(0xC000, 0x42, 0x41);
+        assert_eq!(watches.take_hit(), None);
+        watches.read(0xC000, 0x42);
+        assert!(watches.take_hit().is_some());
+        watches.write(0xC000, 0x00, 0x42);
+        assert_eq!(watches.take_hit().map(|hit| (hit.old, hit.new)), Some((0x00, 0x42)));
+    }
+
+    #[test]
+    fn changes_ignores_reads_and_writes_of_the_same_value() {
+        let watches = watching(Watchpoint::new(0xC000, 0xC000, WatchKind::Access).when(WatchCondition::Changes));
+        watches.read(0xC000, 0x42);
+        watches.write(0xC000, 0x42, 0x42);
+        assert_eq!(watches.take_hit(), None);
+        watches.write(0xC000, 0x42, 0x43);
+        assert!(watches.take_hit().is_some());
+    }
+
+    #[test]
+    fn only_the_first_hit_is_kept() {
+        let mut watches = watching(Watchpoint::new(0xC000, 0xC001, WatchKind::Read));
+        watches.start_instruction(0x1234);
+        watches.read(0xC000, 0);
+        watches.read(0xC001, 0);
+        let hit = watches.take_hit().unwrap();
+        assert_eq!((hit.pc, hit.address), (0x1234, 0xC000));
+        assert_eq!(watches.take_hit(), None);
+    }
+}
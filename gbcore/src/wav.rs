@@ -0,0 +1,68 @@
+//! WAV
+//!
+//! A bare-bones writer for 16-bit stereo PCM WAV files.
+//! The header is written up front with empty sizes, which get patched in once the file is finished.
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use anyhow::{Context, Result};
+
+const HEADER_SIZE: u32 = 44;
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+
+pub struct WavWriter {
+    file: BufWriter<File>,
+    data_size: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32) -> Result<Self> {
+        let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut writer = Self {
+            file: BufWriter::new(file),
+            data_size: 0,
+        };
+        writer.write_header(sample_rate)?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self, sample_rate: u32) -> Result<()> {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+        let f = &mut self.file;
+        f.write_all(b"RIFF")?;
+        f.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        f.write_all(b"WAVE")?;
+        f.write_all(b"fmt ")?;
+        f.write_all(&16u32.to_le_bytes())?;
+        f.write_all(&1u16.to_le_bytes())?; // PCM
+        f.write_all(&CHANNELS.to_le_bytes())?;
+        f.write_all(&sample_rate.to_le_bytes())?;
+        f.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        f.write_all(&block_align.to_le_bytes())?;
+        f.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        f.write_all(b"data")?;
+        f.write_all(&self.data_size.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Appends interleaved stereo samples.
+    pub fn write_samples(&mut self, samples: &[i16]) -> Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_size += (samples.len() * 2) as u32;
+        Ok(())
+    }
+
+    /// Fills in the chunk sizes and flushes everything out to disk.
+    pub fn finish(mut self) -> Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_size.to_le_bytes())?;
+        self.file.flush()?;
+        Ok(())
+    }
+}